@@ -6,15 +6,28 @@ extern crate rand;
 use criterion::{Bencher, Criterion};
 use rand::{Rng, SeedableRng, StdRng};
 use std::collections::btree_set::BTreeSet;
+use std::cell::UnsafeCell;
 use std::collections::HashSet;
 use std::hash::Hash;
 
-use radix_tree::{ARTSet, ArtElement, CachingARTSet, Digital, PrefixCache, RawART};
+use radix_tree::{ARTSet, ArtElement, CachingARTSet, Digital, Global, LeafLayout,
+                 MultiValueARTSet, NodeAllocator, NullBuckets, PartialCachingARTSet, PrefixCache,
+                 RawART, ShrinkThresholds, SingleValueLeaves, SlabAllocator,
+                 SwissCachingARTSet};
 
 /// We use a deterministic seed when generating random data to cut down on variance between
 /// different benchmark runs.
 const RAND_SEED: [usize; 32] = [1; 32];
 
+/// An `ARTSet` that allocates its nodes from the global allocator rather than a slab.
+type GlobalAllocARTSet<T> = ARTSet<T, Global>;
+
+/// `ARTSet`s whose nodes store 16 and 24 bytes of their prefix rather than 8.
+type Prefix16ARTSet<T> =
+    RawART<ArtElement<T>, NullBuckets<ArtElement<T>>, SlabAllocator, SingleValueLeaves, 16>;
+type Prefix24ARTSet<T> =
+    RawART<ArtElement<T>, NullBuckets<ArtElement<T>>, SlabAllocator, SingleValueLeaves, 24>;
+
 /// Barebones set trait to abstract over various collections.
 trait Set<T> {
     fn new() -> Self;
@@ -31,12 +44,20 @@ impl ARTArg for u64 {
     const PREFIX_LEN: usize = 3;
 }
 
+impl ARTArg for u32 {
+    const PREFIX_LEN: usize = 2;
+}
+
 impl ARTArg for String {
     const PREFIX_LEN: usize = 8;
 }
 
-impl<T: ARTArg + for<'a> Digital<'a> + Ord, C: PrefixCache<ArtElement<T>>> Set<T>
-    for RawART<ArtElement<T>, C>
+impl<T, C, A, L, const PREFIX: usize> Set<T> for RawART<ArtElement<T>, C, A, L, PREFIX>
+where
+    T: ARTArg + for<'a> Digital<'a> + Ord,
+    C: PrefixCache<ArtElement<T>>,
+    A: NodeAllocator + Default,
+    L: LeafLayout,
 {
     fn new() -> Self {
         Self::with_prefix_buckets(T::PREFIX_LEN)
@@ -89,6 +110,14 @@ fn random_vec(len: usize, max_val: u64) -> Vec<u64> {
         .collect()
 }
 
+/// Like `random_vec`, with the first byte of each key one of `firsts` values.
+fn random_mid_vec(len: usize, firsts: u64) -> Vec<u64> {
+    let mut rng = StdRng::from_seed(&RAND_SEED[..]);
+    (0..len.next_power_of_two())
+        .map(|_| rng.gen_range::<u64>(0, firsts) << 56 | rng.gen_range::<u64>(0, 1 << 56))
+        .collect()
+}
+
 fn random_dense_vec(len: u64, bias: u64) -> Vec<u64> {
     let mut rng = StdRng::from_seed(&RAND_SEED[..]);
     let mut res = (0..len.next_power_of_two())
@@ -111,37 +140,118 @@ fn random_string_vec(max_len: usize, len: usize) -> Vec<String> {
         .collect()
 }
 
+/// Random URL-like strings. Past the host, keys share a prefix of 13 bytes and then one of a
+/// few directories, so many inner nodes have prefixes longer than 8 bytes.
+fn random_url_vec(len: usize) -> Vec<String> {
+    const DIRS: [&str; 4] = ["images/", "api/v2/users/", "docs/reference/", "blog/"];
+    let mut rng = StdRng::from_seed(&RAND_SEED[..]);
+    (0..len.next_power_of_two())
+        .map(|_| {
+            let host: String = (0..3).map(|_| (b'a' + rng.gen_range(0, 26)) as char).collect();
+            let name: String = rng.gen_ascii_chars().take(6).collect();
+            format!("https://{}.example.com/{}{}", host, rng.choose(&DIRS).unwrap(), name)
+        })
+        .collect()
+}
+
+/// Draw `len` lookups from `keys` with (approximately) Zipfian frequencies, where the `i`th key
+/// has weight proportional to `1 / (i + 1)`. We sample from the continuous analogue of the
+/// distribution, which avoids materializing a CDF over every key.
+fn zipf_vec<T: Clone>(keys: &[T], len: usize) -> Vec<T> {
+    let mut rng = StdRng::from_seed(&RAND_SEED[..]);
+    let log_n = ((keys.len() + 1) as f64).ln();
+    (0..len.next_power_of_two())
+        .map(|_| {
+            let u = rng.gen_range::<f64>(0.0, 1.0);
+            let ix = ((u * log_n).exp() as usize).saturating_sub(1);
+            keys[ix.min(keys.len() - 1)].clone()
+        })
+        .collect()
+}
+
 fn bench_set_rand_int_lookup<T: for<'a> Digital<'a>, S: Set<T>>(
     b: &mut Bencher,
     contents: &S,
-    lookups: &Vec<T>,
+    lookups: &[T],
 ) {
     assert!(lookups.len().is_power_of_two());
     let mut ix = 0;
     b.iter(|| {
         contents.contains(&lookups[ix]);
         ix += 1;
-        ix = ix & (lookups.len() - 1);
+        ix &= lookups.len() - 1;
     })
 }
 
 fn bench_set_insert_remove<T: Clone + for<'a> Digital<'a>, S: Set<T>>(
     b: &mut Bencher,
     contents: &mut S,
-    lookups: &Vec<T>,
+    lookups: &[T],
 ) {
     assert!(lookups.len().is_power_of_two());
     let mut ix = 0;
     b.iter(|| {
         contents.insert(lookups[ix].clone());
         ix += 1;
-        ix = ix & (lookups.len() - 1);
+        ix &= lookups.len() - 1;
         contents.delete(&lookups[ix]);
         // Why += 2? lookups has an even length, but we don't want all inserts to converge to
         // "replace" ops (similarly, deletes should sometimes succeed).
         // TODO: There's probably a more principled way of doing this.
         ix += 2;
-        ix = ix & (lookups.len() - 1);
+        ix &= lookups.len() - 1;
+    })
+}
+
+/// A set whose nodes at the lowest level all have `children` children, and the keys that take
+/// one of those nodes back and forth across that size.
+struct Oscillation {
+    thresholds: (&'static str, ShrinkThresholds),
+    children: u64,
+    // Benchmarks only get a shared reference to their input.
+    set: UnsafeCell<ARTSet<u64>>,
+    keys: Vec<u64>,
+}
+
+impl ::std::fmt::Debug for Oscillation {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+        write!(f, "{}/{}", self.thresholds.0, self.children)
+    }
+}
+
+impl Oscillation {
+    fn new(thresholds: (&'static str, ShrinkThresholds), children: u64) -> Self {
+        const NODES: u64 = 1 << 14;
+        let mut set = ARTSet::new();
+        set.set_shrink_thresholds(thresholds.1);
+        for node in 0..NODES {
+            for d in 0..children {
+                set.add(node << 8 | d);
+            }
+        }
+        let mut rng = StdRng::from_seed(&RAND_SEED[..]);
+        let keys = (0..NODES)
+            .map(|_| rng.gen_range(0, NODES) << 8 | (children - 1))
+            .collect();
+        Oscillation {
+            thresholds,
+            children,
+            set: UnsafeCell::new(set),
+            keys,
+        }
+    }
+}
+
+/// Remove the last child of a node at a size boundary, then put it back.
+unsafe fn bench_oscillation(b: &mut Bencher, o: &Oscillation) {
+    let set = &mut *o.set.get();
+    let mut ix = 0;
+    b.iter(|| {
+        let k = o.keys[ix];
+        set.remove(&k);
+        set.add(k);
+        ix += 1;
+        ix &= o.keys.len() - 1;
     })
 }
 
@@ -157,7 +267,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     fn make_bench<T: 'static + Clone + for<'a> Digital<'a>, S: Set<T> + 'static>(
         c: &mut Criterion,
         desc: String,
-        inp: &Vec<SizeVec<T>>,
+        inp: &[SizeVec<T>],
     ) {
         eprintln!("Generating for {} (1/3)", desc);
         struct Wrap<S, T>(SizeVec<S>, Box<T>);
@@ -177,7 +287,7 @@ fn criterion_benchmark(c: &mut Criterion) {
             .collect::<Vec<Wrap<_, _>>>();
         c.bench_function_over_inputs(
             &format!("{}/lookup_hit", desc),
-            |b, &Wrap(ref sv, ref s)| bench_set_rand_int_lookup::<T, S>(b, &*s, &sv.0),
+            |b, Wrap(sv, s)| bench_set_rand_int_lookup::<T, S>(b, s, &sv.0),
             sets1,
         );
         eprintln!("Generating for {} (2/3)", desc);
@@ -192,7 +302,7 @@ fn criterion_benchmark(c: &mut Criterion) {
             .collect::<Vec<Wrap<_, _>>>();
         c.bench_function_over_inputs(
             &format!("{}/lookup_miss", desc),
-            |b, &Wrap(ref sv, ref s)| bench_set_rand_int_lookup::<T, S>(b, &*s, &sv.1),
+            |b, Wrap(sv, s)| bench_set_rand_int_lookup::<T, S>(b, s, &sv.1),
             sets2,
         );
         eprintln!("Generating for {} (3/3)", desc);
@@ -209,7 +319,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         unsafe {
             c.bench_function_over_inputs(
                 &format!("{}/insert_remove", desc),
-                |b, &Wrap(ref sv, ref s)| bench_set_insert_remove::<T, S>(b, &mut *s.get(), &sv.0),
+                |b, Wrap(sv, s)| bench_set_insert_remove::<T, S>(b, &mut *s.get(), &sv.0),
                 sets3,
             );
         }
@@ -225,6 +335,19 @@ fn criterion_benchmark(c: &mut Criterion) {
             );
         }};
     }
+    // `u32`s are stored inline in their parent nodes, without a leaf allocation. Comparing these
+    // with the `dense_u64` benchmarks shows what that saves.
+    macro_rules! bench_u32 {
+        ($c:expr, $ivec:expr, $( $container:tt ),+) => {
+            $(
+                make_bench::<u32, $container<u32>>(
+                    $c,
+                    format!("{}/dense_u32", stringify!($container)),
+                    $ivec,
+                );
+            )+
+        }
+    }
     macro_rules! bench_all {
         ($c:expr, $ivec:expr, $ivec2:expr, $svec:expr, $( $container:tt ),+) => {
             $(
@@ -232,6 +355,44 @@ fn criterion_benchmark(c: &mut Criterion) {
             )+
         }
     }
+    fn make_zipf_bench<T: 'static + Clone + for<'a> Digital<'a>, S: Set<T> + 'static>(
+        c: &mut Criterion,
+        desc: String,
+        inp: &[SizeVec<T>],
+    ) {
+        eprintln!("Generating for {}", desc);
+        struct Wrap<S, T>(SizeVec<S>, Box<T>);
+        impl<S, T> Debug for Wrap<S, T> {
+            fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+                write!(f, "{:?}", self.0)
+            }
+        }
+        let sets = inp.iter()
+            .map(|sv| {
+                let mut s = S::new();
+                for i in sv.0.iter() {
+                    s.insert(i.clone());
+                }
+                Wrap(sv.clone(), Box::new(s))
+            })
+            .collect::<Vec<Wrap<_, _>>>();
+        c.bench_function_over_inputs(
+            &format!("{}/lookup_zipf", desc),
+            |b, Wrap(sv, s)| bench_set_rand_int_lookup::<T, S>(b, s, &sv.1),
+            sets,
+        );
+    }
+    macro_rules! bench_zipf {
+        ($c:expr, $ivec:expr, $( $container:tt ),+) => {
+            $(
+                make_zipf_bench::<u64, $container<u64>>(
+                    $c,
+                    format!("{}/zipf_u64", stringify!($container)),
+                    $ivec,
+                );
+            )+
+        }
+    }
     eprintln!("Generating Ints");
     let v1s: Vec<SizeVec<u64>> = [16 << 10, 16 << 20, 256 << 20]
         .iter()
@@ -260,9 +421,120 @@ fn criterion_benchmark(c: &mut Criterion) {
         &v1_dense,
         &v2s,
         ARTSet,
+        GlobalAllocARTSet,
+        HashSet,
+        BTreeSet,
+        CachingARTSet,
+        SwissCachingARTSet
+    );
+
+    let v32_dense: Vec<SizeVec<u32>> = v1_dense[0..2]
+        .iter()
+        .map(|sv| {
+            let narrow = |v: &Vec<u64>| v.iter().map(|x| *x as u32).collect::<Vec<u32>>();
+            SizeVec(narrow(&sv.0), narrow(&sv.1))
+        })
+        .collect();
+    bench_u32!(c, &v32_dense, ARTSet, HashSet, BTreeSet, CachingARTSet);
+
+    // `MultiValueARTSet` stores elements next to the nodes at the lowest level of the tree, which
+    // only dense keys fill in. Compare these with the `ARTSet` benchmarks.
+    make_bench::<u64, MultiValueARTSet<u64>>(
+        c,
+        "MultiValueARTSet/sparse_u64".to_string(),
+        &v1s,
+    );
+    make_bench::<u64, MultiValueARTSet<u64>>(
+        c,
+        "MultiValueARTSet/dense_u64".to_string(),
+        &v1_dense,
+    );
+
+    // Keys that make nodes with 17 to 32 children, which are `Node32`s: 1M sparse keys, most of
+    // whose nodes at the third level have that many, and `mid_u64` keys, sparse keys whose first
+    // byte takes one of 24 values. The bytes of inner nodes per key are printed along with them.
+    eprintln!("Generating Node32 Ints");
+    let v_sparse_1m = vec![SizeVec(random_vec(1 << 20, !0), random_vec(1 << 20, !0))];
+    let v_mid: Vec<SizeVec<u64>> = [16 << 10, 1 << 20, 16 << 20]
+        .iter()
+        .map(|size: &usize| SizeVec(random_mid_vec(*size, 24), random_vec(*size, !0)))
+        .collect();
+    for &(name, svec) in [("sparse_u64", &v_sparse_1m), ("mid_u64", &v_mid)].iter() {
+        for sv in svec.iter() {
+            let mut s = ARTSet::<u64>::new();
+            for k in sv.0.iter() {
+                s.add(*k);
+            }
+            let report = s.memory_usage();
+            eprintln!(
+                "ARTSet/{} {}: {:.1} node bytes/key",
+                name,
+                sv.0.len(),
+                report.nodes().bytes as f64 / report.len as f64
+            );
+        }
+        make_bench::<u64, ARTSet<u64>>(c, format!("ARTSet/{}", name), svec);
+    }
+
+    // Strings with long shared prefixes. With the default `PREFIX_LEN` of 8, most of the inner
+    // nodes' prefixes have to be read off of a leaf; storing more of them avoids that.
+    eprintln!("Generating URLs");
+    let v_urls: Vec<SizeVec<String>> = [16 << 10, 1 << 20]
+        .iter()
+        .map(|size: &usize| {
+            let keys = random_url_vec(*size * 2);
+            let (hits, misses) = keys.split_at(*size);
+            SizeVec(hits.to_vec(), misses.to_vec())
+        })
+        .collect();
+    // Compare these with the `ARTSet/String` benchmarks above.
+    make_bench::<String, ARTSet<String>>(c, "ARTSet/url".to_string(), &v_urls);
+    for &(name, svec) in [("String", &v2s), ("url", &v_urls)].iter() {
+        make_bench::<String, Prefix16ARTSet<String>>(c, format!("Prefix16ARTSet/{}", name), svec);
+        make_bench::<String, Prefix24ARTSet<String>>(c, format!("Prefix24ARTSet/{}", name), svec);
+    }
+
+    // Adversarial deletions and insertions at the boundaries between node sizes: each key moves a
+    // node with 5, 17, 33 or 49 children across the size of the next smaller kind and back.
+    // Shrinking eagerly reallocates the node twice per iteration; hysteresis leaves it alone.
+    eprintln!("Generating oscillations");
+    let mut oscillations = Vec::new();
+    for &thresholds in [
+        ("never", ShrinkThresholds::NEVER),
+        ("eager", ShrinkThresholds::EAGER),
+        ("hysteresis", ShrinkThresholds::HYSTERESIS),
+    ].iter()
+    {
+        for &children in [5, 17, 33, 49].iter() {
+            oscillations.push(Oscillation::new(thresholds, children));
+        }
+    }
+    c.bench_function_over_inputs(
+        "ARTSet/boundary_oscillation",
+        |b, o| unsafe { bench_oscillation(b, o) },
+        oscillations,
+    );
+
+    // Skewed lookups, where a small cache of hot prefixes should do most of the work of a
+    // complete one.
+    eprintln!("Generating Zipfian Ints");
+    let v_zipf: Vec<SizeVec<u64>> = [16 << 10, 16 << 20]
+        .iter()
+        .map(|size: &usize| {
+            let keys = random_vec(*size, !0);
+            let lookups = zipf_vec(&keys[..], *size);
+            SizeVec(keys, lookups)
+        })
+        .collect();
+    bench_zipf!(
+        c,
+        &v_zipf,
+        ARTSet,
         HashSet,
         BTreeSet,
-        CachingARTSet
+        CachingARTSet,
+        SwissCachingARTSet,
+        PartialCachingARTSet
     );
 }
 
@@ -0,0 +1,117 @@
+//! Measure the memory used and the number of allocations per key by `ARTMap<u64, u64>` and
+//! `MultiValueARTMap<u64, u64>`.
+//!
+//! Usage: `cargo run --release --example memory_per_key [N...]`, where each `N` is a number of
+//! keys (1M, 16M and 256M by default; the 256M maps need over 6GB of memory). Dense keys are the
+//! integers `0..N`; sparse keys are drawn uniformly from all `u64`s. Only the memory requested
+//! for the trees' nodes and leaves is counted, not allocator overhead; the allocations column
+//! gives an idea of the latter.
+//!
+//! Both kinds of keys are inserted in increasing order. A tree's nodes and leaves only depend on
+//! the set of keys it holds, not on the order they arrived in, and inserting in order keeps the
+//! memory that each insertion touches together, so maps larger than RAM can be built from swap.
+extern crate radix_tree;
+extern crate rand;
+
+use std::alloc::Layout;
+use std::env;
+
+use radix_tree::{ARTMap, Global, MultiValueARTMap, NodeAllocator};
+use rand::{Rng, SeedableRng, StdRng};
+
+/// Keeps track of the bytes and allocations currently live in `Global`.
+#[derive(Default)]
+struct Counting {
+    live: usize,
+    allocations: usize,
+}
+
+unsafe impl NodeAllocator for Counting {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.live += layout.size();
+        self.allocations += 1;
+        Global.alloc(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.live -= layout.size();
+        self.allocations -= 1;
+        Global.dealloc(ptr, layout)
+    }
+}
+
+trait Map {
+    fn new() -> Self;
+    fn add(&mut self, k: u64);
+    fn counts(&self) -> &Counting;
+    fn len(&self) -> usize;
+}
+
+impl Map for ARTMap<u64, u64, Counting> {
+    fn new() -> Self {
+        ARTMap::new_in(Counting::default())
+    }
+    fn add(&mut self, k: u64) {
+        ARTMap::add(self, k, k);
+    }
+    fn counts(&self) -> &Counting {
+        self.allocator()
+    }
+    fn len(&self) -> usize {
+        ARTMap::len(self)
+    }
+}
+
+impl Map for MultiValueARTMap<u64, u64, Counting> {
+    fn new() -> Self {
+        MultiValueARTMap::new_in(Counting::default())
+    }
+    fn add(&mut self, k: u64) {
+        MultiValueARTMap::add(self, k, k);
+    }
+    fn counts(&self) -> &Counting {
+        self.allocator()
+    }
+    fn len(&self) -> usize {
+        MultiValueARTMap::len(self)
+    }
+}
+
+/// Bytes and allocations per key, formatted for a table.
+fn per_key<M: Map, I: Iterator<Item = u64>>(keys: I) -> String {
+    let mut m = M::new();
+    for k in keys {
+        m.add(k);
+    }
+    let (counts, n) = (m.counts(), m.len() as f64);
+    format!("{:>6.1} B {:>6.3}", counts.live as f64 / n, counts.allocations as f64 / n)
+}
+
+fn main() {
+    let mut sizes: Vec<usize> = env::args().skip(1).map(|s| s.parse().unwrap()).collect();
+    if sizes.is_empty() {
+        sizes = vec![1 << 20, 1 << 24, 1 << 28];
+    }
+    println!("{:>10} {:>7} {:>16} {:>16}", "keys", "", "single-value", "multi-value");
+    for &n in sizes.iter() {
+        let dense = || 0..n as u64;
+        println!(
+            "{:>10} {:>7} {:>16} {:>16}",
+            n,
+            "dense",
+            per_key::<ARTMap<u64, u64, Counting>, _>(dense()),
+            per_key::<MultiValueARTMap<u64, u64, Counting>, _>(dense())
+        );
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let mut keys: Vec<u64> = (0..n).map(|_| rng.gen()).collect();
+        keys.sort_unstable();
+        let sparse = || keys.iter().cloned();
+        println!(
+            "{:>10} {:>7} {:>16} {:>16}",
+            n,
+            "sparse",
+            per_key::<ARTMap<u64, u64, Counting>, _>(sparse()),
+            per_key::<MultiValueARTMap<u64, u64, Counting>, _>(sparse())
+        );
+    }
+}
@@ -0,0 +1,451 @@
+//! An ordered map that maintains an aggregate (sum, min, max, ...) of the values below each
+//! interior node, so that aggregates over key ranges do not have to visit every element in the
+//! range.
+use std::borrow::Borrow;
+use std::cmp::{self, Ordering};
+use std::mem;
+use std::ops::Add;
+
+use super::Digital;
+use super::art_impl::{ArtPair, RawART};
+use super::art_internal::*;
+use super::node_alloc::SlabAllocator;
+use super::prefix_cache::NullBuckets;
+use super::smallvec::SmallVec;
+
+/// An associative operation with an identity.
+///
+/// `combine` must be associative, and `empty` must be an identity for it. The operation need not
+/// be commutative: aggregates are always combined in increasing order of keys.
+pub trait Monoid: Clone {
+    fn empty() -> Self;
+    fn combine(&self, other: &Self) -> Self;
+}
+
+/// The sum of a set of values.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sum<T>(pub T);
+
+impl<T: Add<Output = T> + Default + Copy> Monoid for Sum<T> {
+    fn empty() -> Self {
+        Sum(T::default())
+    }
+    fn combine(&self, other: &Self) -> Self {
+        Sum(self.0 + other.0)
+    }
+}
+
+impl<'a, T: Copy> From<&'a T> for Sum<T> {
+    fn from(t: &'a T) -> Self {
+        Sum(*t)
+    }
+}
+
+/// The minimum of a set of values, or `None` if the set is empty.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Min<T>(pub Option<T>);
+
+impl<T: Ord + Copy> Monoid for Min<T> {
+    fn empty() -> Self {
+        Min(None)
+    }
+    fn combine(&self, other: &Self) -> Self {
+        match (self.0, other.0) {
+            (Some(x), Some(y)) => Min(Some(cmp::min(x, y))),
+            (x, None) => Min(x),
+            (None, y) => Min(y),
+        }
+    }
+}
+
+impl<'a, T: Copy> From<&'a T> for Min<T> {
+    fn from(t: &'a T) -> Self {
+        Min(Some(*t))
+    }
+}
+
+/// The maximum of a set of values, or `None` if the set is empty.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Max<T>(pub Option<T>);
+
+impl<T: Ord + Copy> Monoid for Max<T> {
+    fn empty() -> Self {
+        Max(None)
+    }
+    fn combine(&self, other: &Self) -> Self {
+        match (self.0, other.0) {
+            (Some(x), Some(y)) => Max(Some(cmp::max(x, y))),
+            (x, None) => Max(x),
+            (None, y) => Max(y),
+        }
+    }
+}
+
+impl<'a, T: Copy> From<&'a T> for Max<T> {
+    fn from(t: &'a T) -> Self {
+        Max(Some(*t))
+    }
+}
+
+/// A map from `K` to `V` that can compute the aggregate `A` of all values in a range of keys.
+///
+/// Each value is lifted into `A` with `A::from`. Every interior node has a slot in a table of
+/// aggregates holding the aggregate of its subtree; the slot's index is kept in the node itself
+/// (see `RawNode::agg_slot`), and carries over to the new node when it grows or shrinks.
+/// Insertions and removals only change nodes along the path to the key they modify, so after
+/// each one we recompute the aggregates for that path from the bottom up, giving slots to any
+/// nodes that the operation created.
+///
+/// Nodes are freed without the map finding out, which leaves their slots unused. Once most of
+/// the table is unused, it is rebuilt from the nodes still in the tree.
+///
+/// `aggregate_range` then only descends along the paths to its two bounds, using the cached
+/// aggregates for any subtree that falls entirely within the range.
+pub struct AggregatingARTMap<K: for<'a> Digital<'a> + PartialOrd, V, A: Monoid> {
+    map: AggTree<K, V>,
+    // `aggs[i - 1]` is the aggregate of the node whose `agg_slot` is `i`; nodes without a slot
+    // have an `agg_slot` of 0.
+    aggs: Vec<A>,
+}
+
+/// The annotations of the nodes of an `AggregatingARTMap`: just the index of their aggregate.
+struct AggSlots;
+
+impl NodeAnnotations for AggSlots {
+    const LEAF_COUNTS: bool = false;
+    const AGG_SLOTS: bool = true;
+}
+
+type AggTree<K, V> = RawART<
+    ArtPair<K, V>,
+    NullBuckets<ArtPair<K, V>>,
+    SlabAllocator,
+    SingleValueLeaves,
+    PREFIX_LEN,
+    AggSlots,
+>;
+
+/// The number of unused slots a map can build up beyond one for each of its elements before it
+/// rebuilds its table of aggregates.
+const SLACK: usize = 16;
+
+impl<K, V, A> Default for AggregatingARTMap<K, V, A>
+where
+    K: for<'a> Digital<'a> + PartialOrd,
+    A: Monoid + for<'a> From<&'a V>,
+{
+    fn default() -> Self {
+        AggregatingARTMap::new()
+    }
+}
+
+// The subset of a range bound that still applies to a subtree: the bound's key, and its digits.
+type Bound<'a, K> = Option<(&'a K, &'a [u8])>;
+
+unsafe fn for_each_child<T: Element, F: FnMut(u8, MarkedPtr<T>)>(inner: &RawNode<()>, f: F) {
+    with_node!(inner, node, node.local_foreach(f), T)
+}
+
+/// The prefix of `inner`, which has to be read off of a leaf if the header does not store all of
+/// it.
+unsafe fn node_prefix<T: Element>(inner: &RawNode<()>) -> SmallVec<[u8; 16]> {
+    let count = inner.count as usize;
+    let mut res = SmallVec::new();
+    if count <= inner.typ.prefix_capacity() {
+        res.extend(inner.prefix()[0..count].iter().cloned());
+    } else {
+        let min = with_node!(inner, node, node.get_min(), T).expect("inner nodes must be nonempty");
+        res.extend(min.key().digits().skip(inner.consumed as usize).take(count));
+    }
+    res
+}
+
+impl<K, V, A> AggregatingARTMap<K, V, A>
+where
+    K: for<'a> Digital<'a> + PartialOrd,
+    A: Monoid + for<'a> From<&'a V>,
+{
+    pub fn new() -> Self {
+        AggregatingARTMap {
+            map: RawART::new(),
+            aggs: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        self.map.contains(key)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        self.map.get(key).map(|pair| pair.value())
+    }
+
+    /// Insert `v` at `k`, returning the previous value stored at `k`, if there was one.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let mut digits = SmallVec::<[u8; 16]>::new();
+        digits.extend(k.digits());
+        let res = self.map.replace(k, v).map(|(_, v)| v);
+        unsafe { self.repair(&digits[..]) };
+        res
+    }
+
+    /// Remove the value stored at `key`, if there is one.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        let mut digits = SmallVec::<[u8; 16]>::new();
+        digits.extend(key.borrow().digits());
+        let res = self.map.take(key).map(|(_, v)| v);
+        if res.is_some() {
+            unsafe { self.repair(&digits[..]) };
+        }
+        res
+    }
+
+    /// The aggregate of all values in the map.
+    pub fn aggregate(&self) -> A {
+        self.aggregate_range(None, None)
+    }
+
+    /// The aggregate of all values with keys in `[lower_bound, upper_bound)`, combined in
+    /// increasing order of keys.
+    pub fn aggregate_range(&self, lower_bound: Option<&K>, upper_bound: Option<&K>) -> A {
+        let mut lower_digits = SmallVec::<[u8; 16]>::new();
+        let mut upper_digits = SmallVec::<[u8; 16]>::new();
+        let lower = lower_bound.map(|k| {
+            lower_digits.extend(k.digits());
+            (k, &lower_digits[..])
+        });
+        let upper = upper_bound.map(|k| {
+            upper_digits.extend(k.digits());
+            (k, &upper_digits[..])
+        });
+        let mut acc = A::empty();
+        unsafe { self.aggregate_rec(self.map.root_marked(), lower, upper, &mut acc) };
+        acc
+    }
+
+    unsafe fn aggregate_rec(
+        &self,
+        ptr: MarkedPtr<ArtPair<K, V>>,
+        mut lower: Bound<K>,
+        mut upper: Bound<K>,
+        acc: &mut A,
+    ) {
+        let inner = match ptr.get() {
+            None => return,
+            Some(Ok(leaf)) => {
+                let k = leaf.key();
+                let above = lower.is_none_or(|(l, _)| *k >= *l);
+                let below = upper.is_none_or(|(u, _)| *k < *u);
+                if above && below {
+                    *acc = acc.combine(&A::from(leaf.value()));
+                }
+                return;
+            }
+            Some(Err(inner)) => inner,
+        };
+        if lower.is_none() && upper.is_none() {
+            *acc = acc.combine(self.agg(inner));
+            return;
+        }
+        // Compare the node's prefix against the bounds.
+        let consumed = inner.consumed as usize;
+        let pos = consumed + inner.count as usize;
+        let prefix = node_prefix::<ArtPair<K, V>>(inner);
+        let prefix = &prefix[..];
+        let mut lower_digit = None;
+        if let Some((_, ds)) = lower {
+            let bound = &ds[cmp::min(consumed, ds.len())..cmp::min(pos, ds.len())];
+            match prefix.cmp(bound) {
+                // everything below this node is less than the lower bound
+                Ordering::Less => return,
+                Ordering::Equal if pos < ds.len() => lower_digit = Some(ds[pos]),
+                _ => lower = None,
+            }
+        }
+        let mut upper_digit = None;
+        if let Some((_, ds)) = upper {
+            let bound = &ds[cmp::min(consumed, ds.len())..cmp::min(pos, ds.len())];
+            match prefix.cmp(bound) {
+                Ordering::Less => upper = None,
+                Ordering::Equal if pos < ds.len() => upper_digit = Some(ds[pos]),
+                // everything below this node is at least the upper bound
+                _ => return,
+            }
+        }
+        for_each_child(inner, |d, child: MarkedPtr<ArtPair<K, V>>| {
+            if lower_digit.is_some_and(|l| d < l) || upper_digit.is_some_and(|u| d > u) {
+                return;
+            }
+            self.aggregate_rec(
+                child,
+                if lower_digit == Some(d) { lower } else { None },
+                if upper_digit == Some(d) { upper } else { None },
+                acc,
+            );
+        });
+    }
+
+    fn agg(&self, inner: &RawNode<()>) -> &A {
+        debug_assert!(inner.agg_slot() > 0, "node without an aggregate");
+        &self.aggs[inner.agg_slot() as usize - 1]
+    }
+
+    /// The interior nodes along the path to `digits`, from the root down. The path continues
+    /// past prefix mismatches, which only adds nodes whose subtrees cannot have changed.
+    unsafe fn path(&self, digits: &[u8]) -> SmallVec<[*mut RawNode<()>; 16]> {
+        let mut res = SmallVec::new();
+        let mut curr = self.map.root_marked();
+        while let Some(Err(inner)) = curr.get_raw() {
+            res.push(inner);
+            let pos = ((*inner).consumed + (*inner).count) as usize;
+            if pos >= digits.len() {
+                break;
+            }
+            let mut next = MarkedPtr::null();
+            for_each_child(&*inner, |d, child: MarkedPtr<ArtPair<K, V>>| {
+                if d == digits[pos] {
+                    next = child;
+                }
+            });
+            curr = next;
+        }
+        res
+    }
+
+    /// Restore the aggregates after a mutation at `digits`.
+    ///
+    /// Only the nodes on the path to `digits` can have gained or lost elements below them. Any
+    /// other node that the mutation touched, such as one moved below a new node when its prefix
+    /// was split, keeps its subtree and its slot.
+    unsafe fn repair(&mut self, digits: &[u8]) {
+        for &n in self.path(digits).iter().rev() {
+            let mut agg = A::empty();
+            for_each_child(&*n, |_, child: MarkedPtr<ArtPair<K, V>>| match child.get() {
+                None => {}
+                Some(Ok(leaf)) => agg = agg.combine(&A::from(leaf.value())),
+                Some(Err(c)) => agg = agg.combine(self.agg(c)),
+            });
+            if (*n).agg_slot() == 0 {
+                self.aggs.push(agg);
+                *(*n).agg_slot_mut() = self.aggs.len() as u64;
+            } else {
+                self.aggs[(*n).agg_slot() as usize - 1] = agg;
+            }
+        }
+        // Every interior node has at least two children, so there are fewer of them than there
+        // are elements.
+        if self.aggs.len() > 2 * self.map.len() + SLACK {
+            let mut old = mem::take(&mut self.aggs);
+            self.renumber(self.map.root_marked(), &mut old);
+        }
+    }
+
+    /// Move the aggregates of the nodes at or below `ptr` from `old` into fresh slots.
+    unsafe fn renumber(&mut self, ptr: MarkedPtr<ArtPair<K, V>>, old: &mut [A]) {
+        if let Some(Err(inner)) = ptr.get_raw() {
+            for_each_child(&*inner, |_, child| self.renumber(child, old));
+            let slot = &mut old[(*inner).agg_slot() as usize - 1];
+            self.aggs.push(mem::replace(slot, A::empty()));
+            *(*inner).agg_slot_mut() = self.aggs.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::rand::{Rng, SeedableRng, StdRng};
+
+    #[test]
+    fn aggregate_ranges() {
+        let mut m = AggregatingARTMap::<u64, u64, Sum<u64>>::new();
+        let mut mins = AggregatingARTMap::<String, i64, Min<i64>>::new();
+        let mut naive = Vec::new();
+        let mut rng = StdRng::from_seed(&[1][..]);
+        for _ in 0..(1 << 12) {
+            let k = rng.gen_range(0, 1 << 16);
+            let v = rng.gen_range(0, 1000);
+            m.insert(k, v);
+            mins.insert(format!("{}", k), v as i64 - 500);
+            naive.retain(|&(k2, _)| k2 != k);
+            naive.push((k, v));
+        }
+        // Nodes keep their slots as they grow, so insertions leave none unused.
+        assert_eq!(m.aggs.len(), m.map.memory_usage().nodes().count);
+        let check = |m: &AggregatingARTMap<u64, u64, Sum<u64>>,
+                     mins: &AggregatingARTMap<String, i64, Min<i64>>,
+                     naive: &Vec<(u64, u64)>,
+                     rng: &mut StdRng| {
+            assert_eq!(m.len(), naive.len());
+            assert_eq!(m.aggregate(), Sum(naive.iter().map(|&(_, v)| v).sum()));
+            for _ in 0..256 {
+                let lo = rng.gen_range(0, 1 << 16);
+                let hi = rng.gen_range(0, 1 << 16);
+                let expected: u64 = naive
+                    .iter()
+                    .filter(|&&(k, _)| k >= lo && k < hi)
+                    .map(|&(_, v)| v)
+                    .sum();
+                assert_eq!(m.aggregate_range(Some(&lo), Some(&hi)), Sum(expected));
+                let (los, his) = (format!("{}", lo), format!("{}", hi));
+                let expected = naive
+                    .iter()
+                    .filter(|&&(k, _)| {
+                        let ks = format!("{}", k);
+                        ks >= los && ks < his
+                    })
+                    .map(|&(_, v)| v as i64 - 500)
+                    .min();
+                assert_eq!(mins.aggregate_range(Some(&los), Some(&his)), Min(expected));
+            }
+        };
+        check(&m, &mins, &naive, &mut rng);
+        // removals free and collapse nodes along the way, leaving their slots unused until the
+        // table is rebuilt.
+        let removed: Vec<u64> = naive.iter().map(|&(k, _)| k).step_by(2).collect();
+        for k in removed.iter() {
+            assert!(m.remove(k).is_some());
+            assert!(mins.remove(&format!("{}", k)).is_some());
+            assert!(m.aggs.len() <= 2 * m.len() + SLACK);
+        }
+        naive.retain(|&(k, _)| !removed.contains(&k));
+        check(&m, &mins, &naive, &mut rng);
+        for &(k, _) in naive.iter() {
+            m.remove(&k);
+        }
+        assert_eq!(m.aggregate(), Sum(0));
+        assert!(m.aggs.len() <= SLACK);
+
+        // Churn a small map, so that the table is rebuilt while the tree still has nodes.
+        let mut naive = ::std::collections::BTreeMap::new();
+        for _ in 0..(1 << 14) {
+            let k = rng.gen_range(0, 1 << 10);
+            if rng.gen() {
+                assert_eq!(m.insert(k, k), naive.insert(k, k));
+            } else {
+                assert_eq!(m.remove(&k), naive.remove(&k));
+            }
+            assert!(m.aggs.len() <= 2 * m.len() + SLACK);
+            let (lo, hi) = (k / 2, k + 64);
+            let expected = naive.range(lo..hi).map(|(_, v)| v).sum();
+            assert_eq!(m.aggregate_range(Some(&lo), Some(&hi)), Sum(expected));
+        }
+    }
+}
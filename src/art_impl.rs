@@ -1,15 +1,25 @@
 //! Single-threaded radix tree implementation based on HyPer's ART
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::cmp;
 use std::marker::PhantomData;
 use std::mem;
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use super::Digital;
 use super::art_internal::*;
-use super::prefix_cache::{HashSetPrefixCache, NullBuckets};
+use super::filter::{hash_digits, CountingBloomFilter, KeyFilter, XorFilter};
+use super::prefix_cache::HashSetPrefixCache;
 use super::smallvec::SmallVec;
-pub use super::prefix_cache::PrefixCache;
+pub use super::art_internal::{
+    Element, LeafCounts, LeafLayout, MultiValueLeaves, NoAnnotations, NodeAnnotations,
+    SingleValueLeaves, MAX_PREFIX_CAPACITY, PREFIX_LEN,
+};
+pub use super::filter::FilterStats;
+pub use super::node_alloc::{FromGlobalAlloc, Global, NodeAllocator, SlabAllocator};
+pub use super::prefix_cache::{CacheStats, LongPrefixCache, NullBuckets, PartialPrefixCache,
+                              PrefixCache, SwissPrefixCache, TaggedPrefixCache,
+                              PROBE_HISTOGRAM_LEN};
 
 pub struct ArtPair<K: for<'a> Digital<'a> + PartialOrd, V>(K, V);
 
@@ -17,6 +27,14 @@ impl<K: for<'a> Digital<'a> + PartialOrd, V> ArtPair<K, V> {
     pub fn new(k: K, v: V) -> ArtPair<K, V> {
         ArtPair(k, v)
     }
+
+    pub fn value(&self) -> &V {
+        &self.1
+    }
+
+    pub fn value_mut(&mut self) -> &mut V {
+        &mut self.1
+    }
 }
 
 pub struct ArtElement<T: for<'a> Digital<'a> + PartialOrd>(T);
@@ -25,14 +43,25 @@ impl<T: for<'a> Digital<'a> + PartialOrd> ArtElement<T> {
     pub fn new(t: T) -> ArtElement<T> {
         ArtElement(t)
     }
+
+    pub fn value(&self) -> &T {
+        &self.0
+    }
 }
 
 impl<T: for<'a> Digital<'a> + PartialOrd> Element for ArtElement<T> {
     type Key = T;
+    type Value = ();
+    const INLINE: bool = <T as Digital<'static>>::INLINE;
     fn key(&self) -> &T {
         &self.0
     }
 
+    fn value_mut(&mut self) -> &mut () {
+        // Set elements are just their keys. This does not allocate.
+        Box::leak(Box::new(()))
+    }
+
     fn matches(&self, k: &Self::Key) -> bool {
         *k == self.0
     }
@@ -45,10 +74,15 @@ impl<T: for<'a> Digital<'a> + PartialOrd> Element for ArtElement<T> {
 
 impl<K: for<'a> Digital<'a> + PartialOrd, V> Element for ArtPair<K, V> {
     type Key = K;
+    type Value = V;
     fn key(&self) -> &K {
         &self.0
     }
 
+    fn value_mut(&mut self) -> &mut V {
+        &mut self.1
+    }
+
     fn matches(&self, k: &Self::Key) -> bool {
         *k == self.0
     }
@@ -59,21 +93,57 @@ impl<K: for<'a> Digital<'a> + PartialOrd, V> Element for ArtPair<K, V> {
     }
 }
 
-pub type ARTSet<T> = RawART<ArtElement<T>, NullBuckets<ArtElement<T>>>;
-pub type CachingARTSet<T> = RawART<ArtElement<T>, HashSetPrefixCache<ArtElement<T>>>;
-pub type ARTMap<K, V> = RawART<ArtPair<K, V>, NullBuckets<ArtPair<K, V>>>;
-pub type CachingARTMap<K, V> = RawART<ArtPair<K, V>, HashSetPrefixCache<ArtPair<K, V>>>;
+pub type ARTSet<T, A = SlabAllocator> = RawART<ArtElement<T>, NullBuckets<ArtElement<T>>, A>;
+pub type CachingARTSet<T, A = SlabAllocator> =
+    RawART<ArtElement<T>, HashSetPrefixCache<ArtElement<T>>, A>;
+pub type ARTMap<K, V, A = SlabAllocator> = RawART<ArtPair<K, V>, NullBuckets<ArtPair<K, V>>, A>;
+pub type CachingARTMap<K, V, A = SlabAllocator> =
+    RawART<ArtPair<K, V>, HashSetPrefixCache<ArtPair<K, V>>, A>;
+pub type LongCachingARTSet<T, A = SlabAllocator> =
+    RawART<ArtElement<T>, LongPrefixCache<ArtElement<T>>, A>;
+pub type LongCachingARTMap<K, V, A = SlabAllocator> =
+    RawART<ArtPair<K, V>, LongPrefixCache<ArtPair<K, V>>, A>;
+pub type PartialCachingARTSet<T, A = SlabAllocator> =
+    RawART<ArtElement<T>, PartialPrefixCache<ArtElement<T>>, A>;
+pub type PartialCachingARTMap<K, V, A = SlabAllocator> =
+    RawART<ArtPair<K, V>, PartialPrefixCache<ArtPair<K, V>>, A>;
+pub type SwissCachingARTSet<T, A = SlabAllocator> =
+    RawART<ArtElement<T>, SwissPrefixCache<ArtElement<T>>, A>;
+pub type SwissCachingARTMap<K, V, A = SlabAllocator> =
+    RawART<ArtPair<K, V>, SwissPrefixCache<ArtPair<K, V>>, A>;
+pub type TaggedCachingARTSet<T, A = SlabAllocator> =
+    RawART<ArtElement<T>, TaggedPrefixCache<ArtElement<T>>, A>;
+pub type TaggedCachingARTMap<K, V, A = SlabAllocator> =
+    RawART<ArtPair<K, V>, TaggedPrefixCache<ArtPair<K, V>>, A>;
+pub type MultiValueARTSet<T, A = SlabAllocator> =
+    RawART<ArtElement<T>, NullBuckets<ArtElement<T>>, A, MultiValueLeaves>;
+pub type MultiValueARTMap<K, V, A = SlabAllocator> =
+    RawART<ArtPair<K, V>, NullBuckets<ArtPair<K, V>>, A, MultiValueLeaves>;
+pub type RankedARTSet<T, A = SlabAllocator> = RawART<
+    ArtElement<T>,
+    NullBuckets<ArtElement<T>>,
+    A,
+    SingleValueLeaves,
+    PREFIX_LEN,
+    LeafCounts,
+>;
+pub type RankedARTMap<K, V, A = SlabAllocator> = RawART<
+    ArtPair<K, V>,
+    NullBuckets<ArtPair<K, V>>,
+    A,
+    SingleValueLeaves,
+    PREFIX_LEN,
+    LeafCounts,
+>;
 
-impl<K: for<'a> Digital<'a> + PartialOrd, V, C: PrefixCache<ArtPair<K, V>>>
-    RawART<ArtPair<K, V>, C>
+impl<K, V, C, A, L, const PREFIX: usize, S> RawART<ArtPair<K, V>, C, A, L, PREFIX, S>
+where
+    K: for<'a> Digital<'a> + PartialOrd,
+    C: PrefixCache<ArtPair<K, V>>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
 {
-    pub fn contains<Q>(&self, key: &Q) -> bool
-    where
-        Q: Borrow<K> + ?Sized,
-    {
-        unsafe { self.lookup_raw(key.borrow()).is_some() }
-    }
-
     pub fn contains_val(&self, key: K) -> bool {
         self.contains(&key)
     }
@@ -100,13 +170,6 @@ impl<K: for<'a> Digital<'a> + PartialOrd, V, C: PrefixCache<ArtPair<K, V>>>
         self.remove(&key)
     }
 
-    pub fn remove<Q>(&mut self, key: &Q) -> bool
-    where
-        Q: Borrow<K> + ?Sized,
-    {
-        self.take(key).is_some()
-    }
-
     pub fn for_each_range<F: FnMut(&K, &V)>(
         &self,
         f: F,
@@ -132,34 +195,17 @@ impl<K: for<'a> Digital<'a> + PartialOrd, V, C: PrefixCache<ArtPair<K, V>>>
         upper_bound: Option<&K>,
         _dir: D,
     ) {
-        let mut lower_digits = SmallVec::<[u8; 16]>::new();
-        let mut upper_digits = SmallVec::<[u8; 16]>::new();
-        let mut ff = |x: &ArtPair<K, V>| f(&x.0, &x.1);
-        visit_leaf(
-            &self.root,
-            &mut ff,
-            lower_bound.map(|x| {
-                lower_digits.extend(x.digits());
-                &lower_digits[..]
-            }),
-            upper_bound.map(|x| {
-                upper_digits.extend(x.digits());
-                &upper_digits[..]
-            }),
-            lower_bound,
-            upper_bound,
-            _dir,
-        );
+        self.visit_range(|x: &ArtPair<K, V>| f(&x.0, &x.1), lower_bound, upper_bound, _dir);
     }
 }
-impl<T: for<'a> Digital<'a> + PartialOrd, C: PrefixCache<ArtElement<T>>> RawART<ArtElement<T>, C> {
-    pub fn contains<Q>(&self, key: &Q) -> bool
-    where
-        Q: Borrow<T> + ?Sized,
-    {
-        unsafe { self.lookup_raw(key.borrow()).is_some() }
-    }
-
+impl<T, C, A, L, const PREFIX: usize, S> RawART<ArtElement<T>, C, A, L, PREFIX, S>
+where
+    T: for<'a> Digital<'a> + PartialOrd,
+    C: PrefixCache<ArtElement<T>>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
     pub fn contains_val(&self, key: T) -> bool {
         self.contains(&key)
     }
@@ -186,13 +232,6 @@ impl<T: for<'a> Digital<'a> + PartialOrd, C: PrefixCache<ArtElement<T>>> RawART<
         self.remove(&key)
     }
 
-    pub fn remove<Q>(&mut self, key: &Q) -> bool
-    where
-        Q: Borrow<T> + ?Sized,
-    {
-        self.take(key).is_some()
-    }
-
     pub fn for_each_range<F: FnMut(&T)>(
         &self,
         f: F,
@@ -218,24 +257,131 @@ impl<T: for<'a> Digital<'a> + PartialOrd, C: PrefixCache<ArtElement<T>>> RawART<
         upper_bound: Option<&T>,
         _dir: D,
     ) {
-        let mut lower_digits = SmallVec::<[u8; 16]>::new();
-        let mut upper_digits = SmallVec::<[u8; 16]>::new();
-        let mut ff = |x: &ArtElement<T>| f(&x.0);
-        visit_leaf(
-            &self.root,
-            &mut ff,
-            lower_bound.map(|x| {
-                lower_digits.extend(x.digits());
-                &lower_digits[..]
-            }),
-            upper_bound.map(|x| {
-                upper_digits.extend(x.digits());
-                &upper_digits[..]
-            }),
-            lower_bound,
-            upper_bound,
-            _dir,
-        );
+        self.visit_range(|x: &ArtElement<T>| f(&x.0), lower_bound, upper_bound, _dir);
+    }
+}
+
+/// When deletions shrink inner nodes, as set by `RawART::set_shrink_thresholds`.
+///
+/// A node of each kind is moved into the smallest kind that holds its children once a deletion
+/// leaves it with at most this many of them; 0 means never. Shrinking as soon as the children fit
+/// (`EAGER`) makes alternating insertions and deletions at a size boundary reallocate the node
+/// every time, so `HYSTERESIS` waits until the node is well below the boundary. A deletion that
+/// starts from a node in the prefix cache and would shrink that node restarts from the root,
+/// which knows the node's parent. `shrink_to_fit` shrinks every node regardless of these
+/// thresholds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkThresholds {
+    pub node16: usize,
+    pub node32: usize,
+    pub node48: usize,
+    pub node256: usize,
+}
+
+impl ShrinkThresholds {
+    /// Deletions never shrink nodes. This is the default.
+    pub const NEVER: ShrinkThresholds = ShrinkThresholds {
+        node16: 0,
+        node32: 0,
+        node48: 0,
+        node256: 0,
+    };
+
+    /// Shrink nodes as soon as their children fit in a smaller kind.
+    pub const EAGER: ShrinkThresholds = ShrinkThresholds {
+        node16: 4,
+        node32: 16,
+        node48: 32,
+        node256: 48,
+    };
+
+    /// Shrink nodes once they are down to half the capacity of the next smaller kind (or 12
+    /// children, for a `Node48`).
+    pub const HYSTERESIS: ShrinkThresholds = ShrinkThresholds {
+        node16: 2,
+        node32: 8,
+        node48: 12,
+        node256: 24,
+    };
+
+    fn get(&self, typ: NodeType) -> usize {
+        node_kinds!(shrink_threshold, self, typ)
+    }
+}
+
+impl Default for ShrinkThresholds {
+    fn default() -> Self {
+        ShrinkThresholds::NEVER
+    }
+}
+
+/// A number of allocations, and the bytes they take up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocationStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl AllocationStats {
+    fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// How much memory a tree uses, as returned by `RawART::memory_usage`.
+///
+/// Byte counts are the sizes of the allocations the tree has live. The allocator may round them
+/// up, and a `SlabAllocator` also holds on to memory that the tree has freed (see
+/// `SlabAllocator::reserved`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub node4: AllocationStats,
+    pub node16: AllocationStats,
+    pub node32: AllocationStats,
+    pub node48: AllocationStats,
+    pub node256: AllocationStats,
+    /// Elements in leaves of their own. Elements stored in their parent's child pointers or value
+    /// slots take no memory beyond that of the parent, and are counted in `inline_leaves` and
+    /// `stored_leaves` instead.
+    pub leaves: AllocationStats,
+    pub inline_leaves: usize,
+    pub stored_leaves: usize,
+    /// The prefix cache: its entries and the memory of its hash table.
+    pub prefix_cache: AllocationStats,
+    /// The memory used by the membership filter, if there is one.
+    pub filter: usize,
+    /// The number of children of all inner nodes.
+    pub children: usize,
+    /// The number of inner nodes whose prefix is longer than they can store (see `RawART`), so
+    /// that the rest of it has to be read off of a leaf.
+    pub implicit_prefixes: usize,
+    /// The number of elements in the tree.
+    pub len: usize,
+}
+
+impl MemoryReport {
+    /// All inner nodes, of any type.
+    pub fn nodes(&self) -> AllocationStats {
+        let all = [self.node4, self.node16, self.node32, self.node48, self.node256];
+        AllocationStats {
+            count: all.iter().map(|s| s.count).sum(),
+            bytes: all.iter().map(|s| s.bytes).sum(),
+        }
+    }
+
+    /// The memory used by the tree's nodes, leaves, prefix cache and filter.
+    pub fn total_bytes(&self) -> usize {
+        self.nodes().bytes + self.leaves.bytes + self.prefix_cache.bytes + self.filter
+    }
+
+    /// The average number of children of an inner node.
+    pub fn average_fan_out(&self) -> f64 {
+        self.children as f64 / cmp::max(self.nodes().count, 1) as f64
+    }
+
+    pub fn bytes_per_key(&self) -> f64 {
+        self.total_bytes() as f64 / cmp::max(self.len, 1) as f64
     }
 }
 
@@ -251,177 +397,878 @@ enum PartialDeleteResult<T> {
     Success(T),
 }
 
-pub struct RawART<T: Element, C: PrefixCache<T>> {
+impl<T> PartialResult<T> {
+    fn is_success(&self) -> bool {
+        matches!(*self, PartialResult::Success)
+    }
+}
+
+impl<T> PartialDeleteResult<T> {
+    fn is_success(&self) -> bool {
+        matches!(*self, PartialDeleteResult::Success(_))
+    }
+}
+
+/// A prefix cache hit: `Ok` for a leaf and `Err` for an inner node. A leaf that is stored inline
+/// is only a copy of the one in the tree.
+type CacheHit<T> = Result<MarkedPtr<T>, MarkedPtr<T>>;
+
+/// An adaptive radix tree storing values of any `Element` type.
+///
+/// `ARTSet` and `ARTMap` (and their caching variants) are aliases of this type. It can also be
+/// used directly with a user-defined `Element`, in which case `contains`, `get`, `insert` and
+/// `remove` are the main entry points.
+///
+/// Inner nodes and leaves are allocated from `A`. The default `SlabAllocator` keeps freed nodes
+/// around for reuse and releases them all at once when the tree is dropped; `Global` allocates
+/// each of them from the global allocator instead.
+///
+/// `L` picks how elements are stored: in leaves of their own (`SingleValueLeaves`), or in value
+/// slots next to the nodes at the lowest level of the tree (`MultiValueLeaves`).
+///
+/// `PREFIX` is the number of bytes of its compressed prefix that each inner node stores. Prefixes
+/// longer than that have the rest of their bytes read off of a leaf, which costs an extra cache
+/// miss on lookups; keys that share long prefixes (such as URLs or paths) benefit from a larger
+/// value, at the cost of `PREFIX - PREFIX_LEN` bytes per inner node. It must be a multiple of 8
+/// between `PREFIX_LEN` and `MAX_PREFIX_CAPACITY`.
+///
+/// `S` picks what inner nodes keep about their subtrees beyond what lookups need. With
+/// `LeafCounts` (as in `RankedARTSet` and `RankedARTMap`) they count their leaves, which gives
+/// the tree `rank`, `select` and `count_range` at the cost of 8 bytes per inner node and an
+/// update of every node on the path of each insertion and deletion. Leaf counts require a tree
+/// without a prefix cache.
+pub struct RawART<
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator = SlabAllocator,
+    L: LeafLayout = SingleValueLeaves,
+    const PREFIX: usize = PREFIX_LEN,
+    S: NodeAnnotations = NoAnnotations,
+> {
     len: usize,
     root: ChildPtr<T>,
-    prefix_target: usize,
+    alloc: A,
+    // The prefix lengths cached in `buckets`, in increasing order.
+    prefix_levels: SmallVec<[usize; 4]>,
     buckets: C,
+    // Only present for trees that pick their prefix length at runtime.
+    tuner: Option<Box<PrefixTuner>>,
+    // Only present after a call to `enable_cache_stats`.
+    counters: Option<CacheCounters>,
+    // Only present after a call to `enable_bloom_filter` or `enable_xor_filter`.
+    filter: Option<Box<MembershipFilter>>,
+    shrink: ShrinkThresholds,
+    leaves: PhantomData<(L, S)>,
+}
+
+/// Sample one operation in this many to estimate the benefit of each prefix length.
+const TUNE_SAMPLE_INTERVAL: usize = 64;
+/// The number of samples to collect before reconsidering the prefix length.
+const TUNE_SAMPLES: usize = 256;
+/// The number of cache entries filled in per mutation while switching prefix lengths.
+const TUNE_FILL_STEP: usize = 64;
+/// The longest prefix length an adaptive tree will pick.
+const TUNE_MAX_PREFIX_LEN: usize = 16;
+
+// Lookups take `&self` but still record statistics, so the counters are atomics. Relaxed
+// increments are enough: the counters are only ever read as totals.
+struct TunerStats {
+    ops: AtomicUsize,
+    samples: AtomicUsize,
+    probes: AtomicUsize,
+    hits: AtomicUsize,
+    // `skipped[l]` is the number of interior nodes that sampled operations would have skipped
+    // had they started from the cache entry for their prefix of length `l`.
+    skipped: Vec<AtomicUsize>,
+}
+
+fn bump(counter: &AtomicUsize) -> usize {
+    counter.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+fn read(counter: &AtomicUsize) -> usize {
+    counter.load(Ordering::Relaxed)
+}
+
+// The counters reported by `RawART::cache_stats`; these are updated in the same way as
+// `TunerStats`. Every lookup would write to them, so they are only kept when asked for.
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    stale_hits: AtomicUsize,
+    restarts: AtomicUsize,
+}
+
+/// A membership filter consulted before lookups, along with the counters reported by
+/// `RawART::filter_stats`.
+struct MembershipFilter {
+    filter: KeyFilter,
+    queries: AtomicUsize,
+    negatives: AtomicUsize,
+    false_positives: AtomicUsize,
+}
+
+impl MembershipFilter {
+    fn new(filter: KeyFilter) -> Self {
+        MembershipFilter {
+            filter,
+            queries: AtomicUsize::new(0),
+            negatives: AtomicUsize::new(0),
+            false_positives: AtomicUsize::new(0),
+        }
+    }
+}
+
+fn hash_key<K: for<'a> Digital<'a>>(k: &K) -> u64 {
+    let mut digits = SmallVec::<[u8; 32]>::new();
+    digits.extend(k.digits());
+    hash_digits(&digits[..])
+}
+
+impl TunerStats {
+    fn new() -> Self {
+        TunerStats {
+            ops: AtomicUsize::new(0),
+            samples: AtomicUsize::new(0),
+            probes: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            skipped: (0..TUNE_MAX_PREFIX_LEN + 1).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+/// The state of an adaptive tree; see `RawART::with_adaptive_prefix`.
+struct PrefixTuner {
+    stats: TunerStats,
+    // A prefix length whose cache entries are being filled in, along with the smallest prefix
+    // that has not been filled in yet.
+    pending: Option<(usize, SmallVec<[u8; TUNE_MAX_PREFIX_LEN]>)>,
 }
 
-impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
+impl<T, C, A, L, const PREFIX: usize, S> Default for RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator + Default,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
+    fn default() -> Self {
+        RawART::new()
+    }
+}
+
+impl<T, C, A, L, const PREFIX: usize, S> Drop for RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
+    fn drop(&mut self) {
+        // Allocators that free everything when they are dropped let us skip the walk, unless
+        // there are elements to drop.
+        if A::FREES_ON_DROP && !mem::needs_drop::<T>() {
+            return;
+        }
+        unsafe { free_tree(self.root.swap_null(), &mut self.alloc) }
+    }
+}
+
+impl<T, C, A, L, const PREFIX: usize, S> RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator + Default,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
     pub fn new() -> Self {
         RawART::with_prefix_buckets(8)
     }
 
     /// Construct a new ART that caches interior nodes corresponding to prefixes of length
-    /// `prefix_len`. The prefix length must be positive, and it cannot exceed 8.
+    /// `prefix_len`. The prefix length must be positive, and it cannot exceed the cache's
+    /// `MAX_PREFIX_LEN`.
     pub fn with_prefix_buckets(prefix_len: usize) -> Self {
-        assert!(prefix_len <= 8);
-        assert!(prefix_len > 0);
+        RawART::with_prefix_cache(prefix_len, C::new())
+    }
+
+    /// Like `with_prefix_buckets`, but uses `cache` as the prefix cache. This is useful for caches
+    /// that take parameters, such as the capacity of a `PartialPrefixCache`.
+    pub fn with_prefix_cache(prefix_len: usize, cache: C) -> Self {
+        RawART::with_prefix_levels_cache(&[prefix_len], cache)
+    }
+
+    /// Construct a new ART that caches interior nodes for prefixes of each of the lengths in
+    /// `levels`.
+    ///
+    /// Operations start from the node for the longest cached prefix of their key that is present
+    /// in the cache, so caching a few levels (e.g. `&[2, 4, 8]`) lets both shallow and deep parts
+    /// of the tree benefit. Every level adds work to insertions and deletions.
+    pub fn with_prefix_levels(levels: &[usize]) -> Self {
+        RawART::with_prefix_levels_cache(levels, C::new())
+    }
+
+    /// Like `with_prefix_levels`, but uses `cache` as the prefix cache.
+    pub fn with_prefix_levels_cache(levels: &[usize], cache: C) -> Self {
+        RawART::with_prefix_levels_cache_in(levels, cache, A::default())
+    }
+
+    /// Construct a new ART that picks the length of its cached prefixes at runtime.
+    ///
+    /// The tree samples the paths taken by its operations, along with how often they hit in the
+    /// cache, and periodically switches to the prefix length that would have let them skip the
+    /// most interior nodes. Switching is incremental: the cache entries for the new length are
+    /// filled in a few at a time as the tree is modified, and the old length is used until they
+    /// are all present. `prefix_len` returns the length currently in use.
+    ///
+    /// Decisions are only made while the tree is modified. Read-only workloads can call
+    /// `tune_prefix_len` to apply them.
+    pub fn with_adaptive_prefix() -> Self {
+        RawART::with_adaptive_prefix_cache(C::new())
+    }
+
+    /// Like `with_adaptive_prefix`, but uses `cache` as the prefix cache.
+    pub fn with_adaptive_prefix_cache(cache: C) -> Self {
+        let mut res = RawART::with_prefix_cache(cmp::min(8, C::MAX_PREFIX_LEN), cache);
+        res.tuner = Some(Box::new(PrefixTuner {
+            stats: TunerStats::new(),
+            pending: None,
+        }));
+        res
+    }
+}
+
+impl<T, C, A, L, const PREFIX: usize, S> RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
+    /// Like `new`, but allocates the tree's nodes from `alloc`.
+    pub fn new_in(alloc: A) -> Self {
+        RawART::with_prefix_levels_cache_in(&[8], C::new(), alloc)
+    }
+
+    /// Like `with_prefix_levels_cache`, but allocates the tree's nodes from `alloc`.
+    pub fn with_prefix_levels_cache_in(levels: &[usize], cache: C, alloc: A) -> Self {
+        let () = Self::VALID_PREFIX;
+        let () = Self::VALID_ALLOCATOR;
+        let () = Self::VALID_ANNOTATIONS;
+        assert!(!levels.is_empty());
+        let mut prefix_levels = SmallVec::<[usize; 4]>::new();
+        prefix_levels.extend(levels.iter().cloned());
+        prefix_levels.sort();
+        prefix_levels.dedup();
+        for &level in prefix_levels.iter() {
+            assert!(level <= C::MAX_PREFIX_LEN);
+            assert!(level > 0);
+        }
         RawART {
             len: 0,
             root: ChildPtr::null(),
-            buckets: C::new(),
-            prefix_target: prefix_len,
+            alloc,
+            buckets: cache,
+            prefix_levels,
+            tuner: None,
+            counters: None,
+            filter: None,
+            shrink: ShrinkThresholds::default(),
+            leaves: PhantomData,
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.len
+    /// Whether nodes at the lowest level of the tree store their children's elements (see
+    /// `MultiValueLeaves`).
+    fn multi_value() -> bool {
+        L::MULTI_VALUE && !stores_inline::<T>()
     }
 
-    fn hash_lookup(&self, digits: &[u8]) -> (bool, Option<Result<*mut T, MarkedPtr<T>>>) {
-        if digits.len() <= self.prefix_target {
-            (false, None)
-        } else {
-            let res = self.buckets.lookup(&digits[0..self.prefix_target]);
-            (
-                true,
-                match res {
-                    Some(ptr) => Some({
-                        match unsafe { ptr.get_raw().unwrap() } {
-                            Ok(leaf) => Ok(leaf),
-                            Err(_) => Err(ptr),
-                        }
-                    }),
-                    None => None,
-                },
-            )
+    const VALID_PREFIX: () = assert!(
+        PREFIX.is_multiple_of(8) && PREFIX >= PREFIX_LEN && PREFIX <= MAX_PREFIX_CAPACITY,
+        "PREFIX must be a multiple of 8 between PREFIX_LEN and MAX_PREFIX_CAPACITY"
+    );
+
+    const VALID_ALLOCATOR: () = assert!(
+        !C::TAGS_NODES || A::KEEPS_NODES,
+        "TaggedPrefixCache requires an allocator that keeps freed nodes, such as SlabAllocator"
+    );
+
+    // Operations that start at a cached node skip the ancestors whose counts would change.
+    const VALID_ANNOTATIONS: () = assert!(
+        !S::LEAF_COUNTS || !C::ENABLED,
+        "LeafCounts requires a tree without a prefix cache"
+    );
+
+    /// Whether a prefix that is missing from the cache is known not to start any key (see
+    /// `PrefixCache::COMPLETE`). That does not hold if leaves are stored inline, as they are
+    /// never cached (see `cache_entry`).
+    const COMPLETE: bool = C::COMPLETE && !stores_inline::<T>();
+
+    /// The type of the Node4s created to split a prefix.
+    fn node4() -> NodeType {
+        NODE_4
+            .with_prefix_capacity(PREFIX)
+            .with_generation(C::TAGS_NODES)
+            .with_leaf_count(S::LEAF_COUNTS)
+            .with_agg_slot(S::AGG_SLOTS)
+    }
+
+    /// The length of the longest prefix used to look up nodes in the cache.
+    pub fn prefix_len(&self) -> usize {
+        *self.prefix_levels.last().unwrap()
+    }
+
+    /// The prefix lengths cached by this tree, in increasing order.
+    pub fn prefix_levels(&self) -> &[usize] {
+        &self.prefix_levels[..]
+    }
+
+    /// Get a reference to the prefix cache.
+    pub fn prefix_cache(&self) -> &C {
+        &self.buckets
+    }
+
+    /// Get a reference to the allocator for the tree's nodes.
+    pub fn allocator(&self) -> &A {
+        &self.alloc
+    }
+
+    /// Statistics about the prefix cache: how often operations hit in it since the call to
+    /// `enable_cache_stats` (or the last call to `reset_cache_stats`), and its current size. The
+    /// counters are 0 unless the tree is recording them.
+    pub fn cache_stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        self.buckets.stats(&mut stats);
+        if let Some(ref counters) = self.counters {
+            stats.hits = read(&counters.hits);
+            stats.misses = read(&counters.misses);
+            stats.stale_hits = read(&counters.stale_hits);
+            stats.restarts = read(&counters.restarts);
         }
+        stats
     }
 
-    // TODO: replace with NonNull
-    pub unsafe fn lookup_raw(&self, k: &T::Key) -> Option<*mut T> {
-        let mut digits = SmallVec::<[u8; 32]>::new();
-        digits.extend(k.digits());
-        let _check = false;
-        trace!(_check, "lookup_raw");
-        unsafe fn lookup_raw_recursive<T: Element>(
-            curr: MarkedPtr<T>,
-            k: &T::Key,
-            digits: &[u8],
-            mut consumed: usize,
-            dont_check: bool,
-        ) -> Option<*mut T> {
-            let _check = false;
-            match curr.get_raw() {
-                None => None,
-                Some(Ok(leaf_node)) => {
-                    if (dont_check && digits.len() == consumed) || (*leaf_node).matches(k) {
-                        trace!(
-                            _check,
-                            "FOUND dont_check={}, consumed_check={}, matches={}",
-                            dont_check,
-                            digits.len() == consumed,
-                            (*leaf_node).matches(k)
-                        );
-                        Some(leaf_node)
-                    } else {
-                        trace!(_check);
-                        None
-                    }
-                }
-                Some(Err(inner_node)) => {
-                    consumed = (*inner_node).consumed as usize;
-                    trace!(
-                        _check,
-                        "[lookup, d={}] found an inner node {:?}@{:?}",
-                        digits[consumed],
-                        *inner_node,
-                        inner_node
-                    );
-                    if consumed >= digits.len() {
-                        trace!(_check, "consumed too big");
-                        return None;
-                    }
-                    // handle prefixes now
-                    (*inner_node)
-                        .prefix_matches_optimistic(&digits[consumed..])
-                        .and_then(|(dont_check_new, con)| {
-                            consumed += con;
-                            // let new_digits = &digits[consumed..];
-                            if digits.len() == consumed {
-                                trace!(_check);
-                                // Our digits were entirely consumed, but this is a non-leaf node.
-                                // That means our node is not present.
-                                return None;
-                            }
-                            with_node!(&*inner_node, nod, {
-                                nod.find_raw(digits[consumed]).and_then(|next_node| {
-                                    trace!(_check);
-                                    lookup_raw_recursive(
-                                        (&*next_node).to_marked(),
-                                        k,
-                                        digits,
-                                        consumed + 1,
-                                        dont_check && dont_check_new,
-                                    )
-                                })
-                            })
-                        })
-                }
-            }
+    /// Start counting the cache hits and misses reported by `cache_stats`, along with the probe
+    /// lengths of the cache's hash tables.
+    ///
+    /// Lookups take `&self`, so recording them means writing to memory that every thread reading
+    /// the tree shares. Trees leave this off until they are asked for statistics.
+    pub fn enable_cache_stats(&mut self) {
+        if self.counters.is_none() {
+            self.counters = Some(CacheCounters::default());
+            self.buckets.record_stats(true);
         }
-        if C::ENABLED {
-            trace!(_check);
-            let (elligible, opt) = self.hash_lookup(digits.as_slice());
-            let node_ref = if let Some(ptr) = opt {
-                match ptr {
-                    Ok(leaf) => {
-                        return if (*leaf).matches(k) {
-                            trace!(_check);
-                            Some(leaf)
-                        } else {
-                            trace!(_check);
-                            None
-                        }
-                    }
-                    Err(node) => node,
+    }
+
+    /// Stop counting cache hits and misses, and discard the counts so far.
+    pub fn disable_cache_stats(&mut self) {
+        self.counters = None;
+        self.buckets.record_stats(false);
+    }
+
+    /// Reset the counters reported by `cache_stats`.
+    pub fn reset_cache_stats(&mut self) {
+        if let Some(ref mut counters) = self.counters {
+            *counters = CacheCounters::default();
+        }
+        self.buckets.reset_stats();
+    }
+
+    fn count(&self, counter: impl FnOnce(&CacheCounters) -> &AtomicUsize) {
+        if let Some(ref counters) = self.counters {
+            bump(counter(counters));
+        }
+    }
+
+    /// Put a counting Bloom filter in front of the tree, sized for `expected_len` keys.
+    ///
+    /// Lookups for keys that the filter rules out return without descending the tree. The filter
+    /// is kept up to date by insertions and deletions, and costs about 5 bytes per key; its false
+    /// positive rate is around 1% up to `expected_len` keys, and rises beyond that. Any existing
+    /// filter is replaced.
+    pub fn enable_bloom_filter(&mut self, expected_len: usize) {
+        let mut filter = CountingBloomFilter::with_capacity(cmp::max(expected_len, self.len));
+        self.visit_range(
+            |e| filter.insert(hash_key(e.key())),
+            None,
+            None,
+            Increasing,
+        );
+        self.filter = Some(Box::new(MembershipFilter::new(KeyFilter::Bloom(filter))));
+    }
+
+    /// Put an xor filter containing the current keys in front of the tree.
+    ///
+    /// This is intended for trees that are no longer modified: the filter is smaller and more
+    /// accurate than a Bloom filter (about 1.2 bytes per key, with a false positive rate of
+    /// 1/256), but it cannot be updated. Deletions leave it in place, but inserting a new key
+    /// discards it; call this method again to rebuild it. Any existing filter is replaced.
+    pub fn enable_xor_filter(&mut self) {
+        let mut hashes = Vec::with_capacity(self.len);
+        self.visit_range(|e| hashes.push(hash_key(e.key())), None, None, Increasing);
+        let filter = XorFilter::build(&mut hashes);
+        self.filter = Some(Box::new(MembershipFilter::new(KeyFilter::Xor(filter))));
+    }
+
+    /// Remove the membership filter, if there is one.
+    pub fn disable_filter(&mut self) {
+        self.filter = None;
+    }
+
+    /// Set the number of children at which deletions shrink each kind of inner node (see
+    /// `ShrinkThresholds`).
+    pub fn set_shrink_thresholds(&mut self, thresholds: ShrinkThresholds) {
+        self.shrink = thresholds;
+    }
+
+    pub fn shrink_thresholds(&self) -> ShrinkThresholds {
+        self.shrink
+    }
+
+    /// Statistics about the membership filter since it was enabled (or since the last call to
+    /// `reset_filter_stats`), or `None` if the tree does not have one.
+    pub fn filter_stats(&self) -> Option<FilterStats> {
+        self.filter.as_ref().map(|f| FilterStats {
+            queries: read(&f.queries),
+            negatives: read(&f.negatives),
+            false_positives: read(&f.false_positives),
+            memory: mem::size_of::<MembershipFilter>() + f.filter.memory(),
+        })
+    }
+
+    /// Walk the tree and report the memory used by each kind of node, its leaves, the prefix cache
+    /// and the filter, along with the shape of the tree.
+    pub fn memory_usage(&self) -> MemoryReport {
+        let mut report = MemoryReport {
+            len: self.len,
+            filter: self.filter_stats().map_or(0, |f| f.memory),
+            ..MemoryReport::default()
+        };
+        let cache = self.cache_stats();
+        report.prefix_cache.count = cache.entries;
+        report.prefix_cache.bytes = cache.memory;
+        unsafe { memory_recursive(self.root.to_marked(), &mut report) };
+        report
+    }
+
+    /// Reset the counters reported by `filter_stats`.
+    pub fn reset_filter_stats(&mut self) {
+        if let Some(ref mut f) = self.filter {
+            for c in [&f.queries, &f.negatives, &f.false_positives].iter() {
+                c.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        Q: Borrow<T::Key> + ?Sized,
+    {
+        unsafe { self.lookup_raw(key.borrow()).is_some() }
+    }
+
+    /// The root of the tree, for code outside this module that walks it directly.
+    pub(crate) unsafe fn root_marked(&self) -> MarkedPtr<T> {
+        self.root.to_marked()
+    }
+
+    /// Call `f` on every element with a key in `[lower_bound, upper_bound)`, in the order given
+    /// by `D`.
+    pub(crate) fn visit_range<F: FnMut(&T), D: Direction>(
+        &self,
+        mut f: F,
+        lower_bound: Option<&T::Key>,
+        upper_bound: Option<&T::Key>,
+        _dir: D,
+    ) {
+        let mut lower_digits = SmallVec::<[u8; 16]>::new();
+        let mut upper_digits = SmallVec::<[u8; 16]>::new();
+        visit_leaf(
+            &self.root,
+            &mut f,
+            lower_bound.map(|x| {
+                lower_digits.extend(x.digits());
+                &lower_digits[..]
+            }),
+            upper_bound.map(|x| {
+                upper_digits.extend(x.digits());
+                &upper_digits[..]
+            }),
+            lower_bound,
+            upper_bound,
+            _dir,
+        );
+    }
+
+    /// Get a reference to the element with key `key`, if it is present.
+    pub fn get<Q>(&self, key: &Q) -> Option<&T>
+    where
+        Q: Borrow<T::Key> + ?Sized,
+    {
+        unsafe { self.lookup_raw(key.borrow()).map(|p| &*p) }
+    }
+
+    /// Get a mutable reference to the value of the element with key `key`, if it is present.
+    ///
+    /// The position of an element in the tree is derived from its key, so only the rest of the
+    /// element (`Element::value_mut`) can be modified in place.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut T::Value>
+    where
+        Q: Borrow<T::Key> + ?Sized,
+    {
+        unsafe { self.lookup_raw(key.borrow()).map(|p| (*p).value_mut()) }
+    }
+
+    /// Insert `elt` into the tree. If an element with the same key was already present, it is
+    /// replaced and returned.
+    pub fn insert(&mut self, elt: T) -> Option<T> {
+        unsafe { self.insert_raw(elt) }.err()
+    }
+
+    /// Remove the element with key `key`, returning true if it was present.
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        Q: Borrow<T::Key> + ?Sized,
+    {
+        self.remove_element(key).is_some()
+    }
+
+    /// Remove the element with key `key` and return it, if it was present.
+    pub fn remove_element<Q>(&mut self, key: &Q) -> Option<T>
+    where
+        Q: Borrow<T::Key> + ?Sized,
+    {
+        unsafe { self.delete_raw(key.borrow()) }
+    }
+
+    /// Probe the cache for the prefixes of `digits`, deepest level first. Returns whether any
+    /// level was eligible, the level of the result, and the cached pointer if there was a hit.
+    ///
+    /// If `fall_back` is false, only the deepest eligible level is probed. For a `COMPLETE`
+    /// cache, a miss there already tells us that the key is not present.
+    ///
+    fn hash_lookup(
+        &self,
+        digits: &[u8],
+        fall_back: bool,
+    ) -> (bool, usize, Option<CacheHit<T>>) {
+        let mut res = (false, 0, None);
+        for &level in self.prefix_levels.iter().rev() {
+            if digits.len() <= level {
+                continue;
+            }
+            if let Some(ptr) = self.buckets.lookup(&digits[0..level]) {
+                // N.B. stale entries for leaves stored in a node's value slots may point to freed
+                // memory, so we only look at the tag.
+                let opt = match unsafe { ptr.get_raw().unwrap() } {
+                    Ok(_) => Ok(ptr),
+                    Err(_) => Err(ptr),
+                };
+                self.count(|c| &c.hits);
+                return (true, level, Some(opt));
+            }
+            res = (true, level, None);
+            if !fall_back {
+                break;
+            }
+        }
+        if res.0 {
+            self.count(|c| &c.misses);
+        }
+        res
+    }
+
+    /// Look up `k` from the root, offering the prefix cache the entry for every cached prefix
+    /// of `digits` along the way.
+    ///
+    /// Like the other lookups, this only compares the bytes of long node prefixes that the
+    /// header stores, and leaves the rest to the final comparison with the leaf's key. The
+    /// entries are therefore only admitted if the key is found.
+    unsafe fn lookup_admitting(&self, k: &T::Key, digits: &[u8]) -> Option<*mut T> {
+        let mut entries = SmallVec::<[(usize, MarkedPtr<T>); 4]>::new();
+        let mut levels = self.prefix_levels.iter().cloned().filter(|&l| l < digits.len());
+        let mut level = levels.next();
+        let mut curr: &MarkedPtr<T> = &self.root;
+        let mut consumed = 0;
+        let mut dont_check = true;
+        let res = loop {
+            match curr.get_raw()? {
+                Ok(leaf) => {
+                    if !((dont_check && digits.len() == consumed) || (*leaf).matches(k)) {
+                        return None;
+                    }
+                    // Any remaining prefixes end within the leaf's key. Leaves stored inline are
+                    // not cached (see `cache_entry`).
+                    while let Some(l) = level.filter(|_| !curr.is_inline()) {
+                        entries.push((l, curr.clone()));
+                        level = levels.next();
+                    }
+                    break leaf;
+                }
+                Err(inner) => {
+                    consumed = (*inner).consumed as usize;
+                    if consumed >= digits.len() {
+                        return None;
+                    }
+                    let (dont_check_new, con) =
+                        (*inner).prefix_matches_optimistic(&digits[consumed..])?;
+                    consumed += con;
+                    dont_check &= dont_check_new;
+                    // Prefixes that end within this node's prefix map to this node.
+                    while let Some(l) = level.filter(|&l| l <= consumed) {
+                        entries.push((l, curr.clone()));
+                        level = levels.next();
+                    }
+                    if digits.len() == consumed {
+                        return None;
+                    }
+                    curr = &*with_node!(&*inner, node, node.find_raw(digits[consumed]), T)?;
+                    consumed += 1;
+                }
+            }
+        };
+        for (l, ptr) in entries {
+            debug_assert!(self.find_prefix_entry(&digits[0..l]) == Some(ptr.clone()));
+            self.buckets.admit(&digits[0..l], ptr);
+        }
+        Some(res)
+    }
+
+    /// Find the node that the prefix cache should map the prefix `digits` to, if any keys start
+    /// with it and it is not a leaf stored inline (see `cache_entry`).
+    unsafe fn find_prefix_entry(&self, digits: &[u8]) -> Option<MarkedPtr<T>> {
+        let target = digits.len();
+        let mut curr = self.root.to_marked();
+        loop {
+            match curr.get() {
+                None => return None,
+                Some(Ok(_)) if curr.is_inline() => return None,
+                Some(Ok(leaf)) => {
+                    if leaf.key().digits().take(target).eq(digits[0..target].iter().cloned()) {
+                        return Some(curr);
+                    }
+                    return None;
+                }
+                Some(Err(inner)) => {
+                    let consumed = inner.consumed as usize;
+                    let count = inner.count as usize;
+                    let n = cmp::min(count, target - consumed);
+                    let matches = if n <= inner.typ.prefix_capacity() {
+                        inner.prefix()[0..n] == digits[consumed..consumed + n]
+                    } else {
+                        let min = with_node!(inner, node, node.get_min(), T)
+                            .expect("inner nodes must be nonempty");
+                        min.key()
+                            .digits()
+                            .skip(consumed)
+                            .take(n)
+                            .eq(digits[consumed..consumed + n].iter().cloned())
+                    };
+                    if !matches {
+                        return None;
+                    }
+                    if consumed + count >= target {
+                        return Some(curr);
+                    }
+                    let next = with_node!(
+                        inner,
+                        node,
+                        node.find(digits[consumed + count]).map(|c| c.to_marked()),
+                        T
+                    );
+                    match next {
+                        Some(c) => curr = c,
+                        None => return None,
+                    }
+                }
+            }
+        }
+    }
+
+    // TODO: replace with NonNull
+    pub(crate) unsafe fn lookup_raw(&self, k: &T::Key) -> Option<*mut T> {
+        let mut digits = SmallVec::<[u8; 32]>::new();
+        digits.extend(k.digits());
+        let filter = match self.filter {
+            Some(ref f) => f,
+            None => return self.lookup_digits(k, &digits),
+        };
+        bump(&filter.queries);
+        if !filter.filter.may_contain(hash_digits(&digits[..])) {
+            bump(&filter.negatives);
+            return None;
+        }
+        let res = self.lookup_digits(k, &digits);
+        if res.is_none() {
+            bump(&filter.false_positives);
+        }
+        res
+    }
+
+    unsafe fn lookup_digits(&self, k: &T::Key, digits: &SmallVec<[u8; 32]>) -> Option<*mut T> {
+        let _check = false;
+        trace!(_check, "lookup_raw");
+        // N.B. `curr` is a reference so that leaves stored inline are returned from their node.
+        unsafe fn lookup_raw_recursive<T: Element>(
+            curr: &MarkedPtr<T>,
+            k: &T::Key,
+            digits: &[u8],
+            mut consumed: usize,
+            dont_check: bool,
+        ) -> Option<*mut T> {
+            let _check = false;
+            match curr.get_raw() {
+                None => None,
+                Some(Ok(leaf_node)) => {
+                    if (dont_check && digits.len() == consumed) || (*leaf_node).matches(k) {
+                        trace!(
+                            _check,
+                            "FOUND dont_check={}, consumed_check={}, matches={}",
+                            dont_check,
+                            digits.len() == consumed,
+                            (*leaf_node).matches(k)
+                        );
+                        Some(leaf_node)
+                    } else {
+                        trace!(_check);
+                        None
+                    }
+                }
+                Some(Err(inner_node)) => {
+                    consumed = (*inner_node).consumed as usize;
+                    trace!(
+                        _check,
+                        "[lookup, d={}] found an inner node {:?}@{:?}",
+                        digits[consumed],
+                        *inner_node,
+                        inner_node
+                    );
+                    if consumed >= digits.len() {
+                        trace!(_check, "consumed too big");
+                        return None;
+                    }
+                    // handle prefixes now
+                    (*inner_node)
+                        .prefix_matches_optimistic(&digits[consumed..])
+                        .and_then(|(dont_check_new, con)| {
+                            consumed += con;
+                            // let new_digits = &digits[consumed..];
+                            if digits.len() == consumed {
+                                trace!(_check);
+                                // Our digits were entirely consumed, but this is a non-leaf node.
+                                // That means our node is not present.
+                                return None;
+                            }
+                            with_node!(&*inner_node, nod, {
+                                nod.find_raw(digits[consumed]).and_then(|next_node| {
+                                    trace!(_check);
+                                    lookup_raw_recursive(
+                                        &*next_node,
+                                        k,
+                                        digits,
+                                        consumed + 1,
+                                        dont_check && dont_check_new,
+                                    )
+                                })
+                            })
+                        })
                 }
-            } else if C::COMPLETE && elligible && self.len > 1 {
+            }
+        }
+        if C::ENABLED {
+            trace!(_check);
+            let (eligible, _, opt) = self.hash_lookup(digits.as_slice(), !Self::COMPLETE);
+            self.observe(digits.as_slice(), if eligible { Some(opt.is_some()) } else { None });
+            let cached;
+            let node_ref = if let Some(ptr) = opt {
+                match ptr {
+                    Ok(leaf) if leaf.is_stored() => {
+                        // The leaf may have moved to another node since it was cached.
+                        return lookup_raw_recursive(&self.root, k, digits.as_slice(), 0, true);
+                    }
+                    Ok(leaf) => {
+                        debug_assert!(!leaf.is_inline());
+                        return if leaf.get().unwrap().ok().unwrap().matches(k) {
+                            trace!(_check);
+                            Some(leaf.get_raw().unwrap().ok().unwrap())
+                        } else {
+                            trace!(_check);
+                            None
+                        }
+                    }
+                    Err(node) => {
+                        cached = node;
+                        &cached
+                    }
+                }
+            } else if Self::COMPLETE && eligible && self.len > 1 {
                 trace!(_check);
                 return None;
+            } else if eligible && C::ADMITS {
+                trace!(_check);
+                return self.lookup_admitting(k, digits.as_slice());
             } else {
                 trace!(_check);
-                self.root.to_marked()
+                &self.root
             };
             trace!(_check);
             lookup_raw_recursive(node_ref, k, digits.as_slice(), 0, true)
         } else {
-            lookup_raw_recursive(self.root.to_marked(), k, digits.as_slice(), 0, true)
+            lookup_raw_recursive(&self.root, k, digits.as_slice(), 0, true)
+        }
+    }
+
+    pub(crate) unsafe fn delete_raw(&mut self, k: &T::Key) -> Option<T> {
+        let mut digits = SmallVec::<[u8; 32]>::new();
+        digits.extend(k.digits());
+        let res = self.delete_digits(k, &digits);
+        if res.is_some() {
+            if let Some(KeyFilter::Bloom(ref mut f)) = self.filter.as_mut().map(|f| &mut f.filter) {
+                f.remove(hash_digits(&digits[..]));
+            }
         }
+        res
     }
 
-    pub unsafe fn delete_raw(&mut self, k: &T::Key) -> Option<T> {
+    unsafe fn delete_digits(&mut self, k: &T::Key, digits: &SmallVec<[u8; 32]>) -> Option<T> {
         // Also, consider hypothesis that promoting last doesn't work, and is leading to failed
         // lookups
         //
         // TODO: This method (particularly delete_raw_recursive) is way too long; should break it
         // out into more helpers.
-        let mut digits = SmallVec::<[u8; 32]>::new();
-        digits.extend(k.digits());
         use self::PartialDeleteResult::*;
         let _check = false;
         trace!(_check, "delete_raw {:?}", &digits[..]);
-        unsafe fn delete_raw_recursive<T: Element, C: PrefixCache<T>>(
+        #[allow(clippy::too_many_arguments, clippy::type_complexity)]
+        unsafe fn delete_raw_recursive<T: Element, C: PrefixCache<T>, A: NodeAllocator>(
             k: &T::Key,
             mut curr: MarkedPtr<T>,
-            curr_ptr: Option<&mut ChildPtr<T>>,
-            parent: Option<(u8, Result<MarkedPtr<T>, &mut ChildPtr<T>>)>,
+            // These are raw pointers because they point into nodes that are also reached, and
+            // changed, through references made further down.
+            curr_ptr: Option<*mut ChildPtr<T>>,
+            parent: Option<(u8, Result<MarkedPtr<T>, *mut ChildPtr<T>>)>,
             digits: &[u8],
             mut consumed: usize,
-            target: usize,
+            targets: &[usize],
+            // the levels in `targets` that are known to have entries for every prefix
+            complete: &[usize],
             buckets: &mut C,
+            alloc: &mut A,
+            shrink: &ShrinkThresholds,
             is_root: bool,
             // return the deleted node
         ) -> PartialDeleteResult<T> {
@@ -430,19 +1277,6 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
             if curr.is_null() {
                 return Failure;
             }
-            unsafe fn move_val_out<T>(mut cptr: ChildPtr<T>) -> T {
-                let res = {
-                    // first we read the memory out
-                    let r = cptr.get_mut().unwrap().unwrap();
-                    ptr::read(r)
-                };
-                // Now we want to deallocate the memory that once held the element, but we don't
-                // want to run its destructor if it has one.
-                let cptr2 = mem::transmute::<ChildPtr<T>, ChildPtr<mem::ManuallyDrop<T>>>(cptr);
-                mem::drop(cptr2);
-                res
-            }
-
             let rest_opts = match curr.get_mut().unwrap() {
                 Ok(leaf_node) => {
                     trace!(_check);
@@ -455,15 +1289,19 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                     Ok(ref mut marked_parent) => {
                                         let p_ref = marked_parent.get_mut().unwrap().err().unwrap();
                                         trace!(_check, "{:?}", p_ref);
-                                        if p_ref.children == 2 {
+                                        // Collapsing or shrinking the node replaces it, which
+                                        // needs the pointer to it that only a traversal from the
+                                        // root has.
+                                        let left = p_ref.children as usize - 1;
+                                        if left == 1 || left <= shrink.get(p_ref.typ) {
                                             trace!(_check, "[delete] returning partial");
                                             return Partial;
                                         }
                                         p_ref
                                     }
-                                    Err(ref mut parent_ptr) => {
+                                    Err(parent_ptr) => {
                                         trace!(_check);
-                                        parent_ptr.get_mut().unwrap().err().unwrap()
+                                        (*parent_ptr).get_mut().unwrap().err().unwrap()
                                     }
                                 },
                                 node,
@@ -473,22 +1311,26 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                         DeleteResult::Success(deleted) => {
                                             // we are deleteing an individual node. Time to check
                                             // if it is in buckets: if it is we should remove it.
-                                            if C::ENABLED && digits.len() >= target
-                                                && consumed <= target
-                                            {
-                                                trace!(_check);
-                                                if C::COMPLETE {
-                                                    debug_assert!(
-                                                        buckets
-                                                            .lookup(&digits[0..target])
-                                                            .is_some()
+                                            for &target in targets {
+                                                if C::ENABLED && digits.len() >= target
+                                                    && consumed <= target
+                                                {
+                                                    trace!(_check);
+                                                    if C::COMPLETE && complete.contains(&target) {
+                                                        debug_assert!(
+                                                            buckets
+                                                                .lookup(&digits[0..target])
+                                                                .is_some()
+                                                        );
+                                                    }
+                                                    buckets.insert(
+                                                        &digits[0..target],
+                                                        MarkedPtr::null(),
                                                     );
                                                 }
-                                                buckets
-                                                    .insert(&digits[0..target], MarkedPtr::null());
                                             }
                                             trace!(_check);
-                                            (Success(move_val_out(deleted)), None)
+                                            (Success(take_leaf(deleted, alloc)), None)
                                         }
                                         DeleteResult::Singleton {
                                             deleted,
@@ -496,39 +1338,52 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                             last_d,
                                         } => {
                                             trace!(_check);
-                                            if C::ENABLED && digits.len() >= target
-                                                && consumed <= target
-                                            {
-                                                trace!(_check);
-                                                if C::COMPLETE {
-                                                    debug_assert!(
-                                                        buckets
-                                                            .lookup(&digits[0..target])
-                                                            .is_some()
+                                            // `last` replaces `node`, so it cannot stay in its
+                                            // value slots.
+                                            let last = detach_leaf(last, alloc);
+                                            for &target in targets {
+                                                if C::ENABLED && digits.len() >= target
+                                                    && consumed <= target
+                                                {
+                                                    trace!(_check);
+                                                    if C::COMPLETE && complete.contains(&target) {
+                                                        debug_assert!(
+                                                            buckets
+                                                                .lookup(&digits[0..target])
+                                                                .is_some()
+                                                        );
+                                                    }
+                                                    buckets.insert(
+                                                        &digits[0..target],
+                                                        MarkedPtr::null(),
                                                     );
                                                 }
-                                                buckets
-                                                    .insert(&digits[0..target], MarkedPtr::null());
                                             }
                                             if C::ENABLED {
                                                 trace!(_check);
                                                 if let Ok(_leaf) = last.get().unwrap() {
                                                     let mut leaf_digits =
-                                                        SmallVec::<[u8; 8]>::new();
+                                                        SmallVec::<[u8; 32]>::new();
                                                     let leaf: &T = _leaf;
-                                                    leaf_digits.extend(leaf.key().digits().take(8));
-                                                    if leaf_digits.len() >= target
-                                                        && consumed <= target
-                                                    {
+                                                    leaf_digits.extend(leaf.key().digits());
+                                                    for &target in targets {
+                                                        if leaf_digits.len() < target
+                                                            || consumed > target
+                                                        {
+                                                            continue;
+                                                        }
                                                         trace!(_check);
                                                         buckets.insert(
                                                             &leaf_digits[0..target],
-                                                            last.to_marked(),
-                                                        );
-                                                        debug_assert_eq!(
-                                                            buckets.lookup(&leaf_digits[0..target]),
-                                                            Some(last.to_marked())
+                                                            cache_entry(last.to_marked()),
                                                         );
+                                                        if C::COMPLETE && !last.is_inline() {
+                                                            debug_assert_eq!(
+                                                                buckets
+                                                                    .lookup(&leaf_digits[0..target]),
+                                                                Some(last.to_marked())
+                                                            );
+                                                        }
                                                         // N.B. when debugging deletes, consider
                                                         // this extra consistency check. This is
                                                         // off by default because it does an O(n)
@@ -545,54 +1400,55 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                                 }
                                             }
                                             debug_assert!(deleted.get().unwrap().is_ok());
-                                            (Success(move_val_out(deleted)), Some((last, last_d)))
+                                            (Success(take_leaf(deleted, alloc)), Some((last, last_d)))
                                         }
                                         DeleteResult::Failure => unreachable!(),
                                     }
                                 }
                             );
+                            if asgn.is_none() {
+                                // The deleted element has been taken out of its leaf or value
+                                // slot, so the parent is free to move.
+                                if let Err(parent_ptr) = parent_ref {
+                                    let p = (*parent_ptr).get().unwrap().err().unwrap();
+                                    if (p.children as usize) <= shrink.get(p.typ) {
+                                        shrink_and_remap(&mut *parent_ptr, buckets, alloc, targets);
+                                    }
+                                }
+                            }
                             if let Some((mut c_ptr, last_d)) = asgn {
                                 let _check_2 = true;
                                 trace!(_check);
                                 // we are promoting a "last" so we must increase its prefix
                                 // length
-                                let mut switch = false; // flag for inserting a new interior node
-
-                                // flag for invalidating the cache (as it may contain the node we are deleting)
-                                let mut replace = false;
-                                let mut ds = SmallVec::<[u8; 8]>::new();
+                                //
+                                // `remap` holds the cached levels whose entries must point to the
+                                // promoted node afterwards: those covered by the parent we are
+                                // deleting (as the cache may contain it), and those that the
+                                // promoted node covers once its prefix grows.
+                                let mut remap = SmallVec::<[usize; 4]>::new();
                                 {
                                     let _p_marked = match parent_ref {
                                         Ok(ref m) => m.clone(),
-                                        Err(ref ptr) => ptr.to_marked(),
+                                        Err(ptr) => (*ptr).to_marked(),
                                     };
                                     let pp = match parent_ref {
                                         Ok(ref m) => m.get().unwrap().err().unwrap(),
-                                        Err(ref ptr) => ptr.get().unwrap().err().unwrap(),
+                                        Err(ptr) => (*ptr).get().unwrap().err().unwrap(),
                                     };
-                                    if C::ENABLED && pp.consumed as usize <= target
-                                        && target <= pp.consumed as usize + pp.count as usize
-                                    {
-                                        trace!(_check);
-                                        // We want to construct enough context to clear out the
-                                        // cache below. Because digits[..] may be too short to fill
-                                        // the hash prefix cache, we need to fill in additional
-                                        // context from the interior nodes.
-                                        //
-                                        // In this case, we start the work by filling in the prefix
-                                        // not present in 'pp'. Below we do the same for `inner` in
-                                        // case it replaces 'pp'.
-                                        replace = true;
-                                        if digits.len() < target {
-                                            for dd in &digits[0..pp.consumed as usize] {
-                                                ds.push(*dd)
-                                            }
+                                    for &target in targets {
+                                        if C::ENABLED && pp.consumed as usize <= target
+                                            && target <= pp.consumed as usize + pp.count as usize
+                                        {
+                                            trace!(_check);
+                                            remap.push(target);
+                                        } else if C::ENABLED && digits.len() >= target {
+                                            trace!(_check);
+                                            debug_assert!(
+                                                buckets.lookup(&digits[0..target])
+                                                    != Some(_p_marked.clone())
+                                            );
                                         }
-                                    } else if C::ENABLED && digits.len() >= target {
-                                        trace!(_check);
-                                        debug_assert!(
-                                            buckets.lookup(&digits[0..target]) != Some(_p_marked)
-                                        );
                                     }
                                     if let Err(inner) = c_ptr.get_mut().unwrap() {
                                         debug_assert!(Some(last_d) != T::Key::STOP_CHARACTER);
@@ -600,17 +1456,17 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                         // node. As a result, we have to modify its prefix and
                                         // potentially insert it into the prefix cache.
                                         let parent_count = pp.count;
-                                        let mut prefix_digits =
-                                            SmallVec::<[u8; PREFIX_LEN + 1]>::new();
-                                        for dd in &pp.prefix
-                                            [0..cmp::min(parent_count as usize, PREFIX_LEN)]
-                                        {
-                                            prefix_digits.push(*dd);
-                                        }
+                                        let stored = cmp::min(
+                                            parent_count as usize,
+                                            pp.typ.prefix_capacity(),
+                                        );
+                                        let mut prefix_digits = SmallVec::<[u8; 32]>::new();
+                                        prefix_digits
+                                            .extend(pp.prefix()[0..stored].iter().cloned());
                                         prefix_digits.push(last_d);
                                         inner.append_prefix(
                                             prefix_digits.as_slice(),
-                                            prefix_digits.len() as u32,
+                                            parent_count + 1,
                                         );
                                         trace!(
                                             _check_2,
@@ -630,62 +1486,44 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                             )
                                         );
                                         debug_assert_eq!(inner.consumed, pp.consumed);
-                                        if C::ENABLED && inner.consumed as usize <= target
-                                            && target
-                                                <= inner.consumed as usize + inner.count as usize
-                                        {
-                                            trace!(_check);
-                                            switch = true;
-                                            if digits.len() < target {
-                                                if !replace {
-                                                    trace!(_check);
-                                                    for dd in &digits[0..pp.consumed as usize] {
-                                                        ds.push(*dd);
-                                                    }
-                                                }
-                                                for dd in &inner.prefix
-                                                    [0..cmp::min(inner.count as usize, PREFIX_LEN)]
-                                                {
-                                                    trace!(_check);
-                                                    ds.push(*dd);
-                                                }
+                                        for &target in targets {
+                                            if C::ENABLED && inner.consumed as usize <= target
+                                                && target
+                                                    <= inner.consumed as usize
+                                                        + inner.count as usize
+                                                && !remap.contains(&target)
+                                            {
+                                                trace!(_check);
+                                                remap.push(target);
                                             }
                                         }
                                     }
-                                    if C::ENABLED && replace && !switch && digits.len() < target {
-                                        trace!(_check);
-                                        for dd in
-                                            &pp.prefix[0..cmp::min(pp.count as usize, PREFIX_LEN)]
-                                        {
-                                            ds.push(*dd);
-                                        }
-                                    }
                                 }
                                 let c_marked = c_ptr.to_marked();
-                                mem::swap(parent_ref.err().unwrap(), &mut c_ptr);
-                                if C::ENABLED {
+                                ptr::swap(parent_ref.err().unwrap(), &mut c_ptr);
+                                // `c_ptr` now holds the old parent, which has no children left.
+                                free_node(c_ptr, alloc);
+                                if C::ENABLED && !remap.is_empty() {
                                     trace!(_check);
-                                    if switch || replace {
-                                        let mut dsn = SmallVec::<[u8; 8]>::new();
-                                        let mut d_slice = &digits[..];
-                                        if digits.len() < target {
-                                            debug_assert!(target <= 8);
-                                            // need to construct new digits
-                                            d_slice = ds.as_slice();
-                                        }
-                                        if consumed <= target {
-                                            // there's an edge case here. If consumed == target,
-                                            // and digits is of lenght >= target, then the promoted
-                                            // node will not have the same target-length prefix as
-                                            // digits[..]. It will share all but the last element.
-                                            for d in &digits[0..target - 1] {
-                                                dsn.push(*d)
-                                            }
-                                            dsn.push(last_d);
-                                            d_slice = &dsn[..]
-                                        }
+                                    // Every key below the promoted node shares the prefixes in
+                                    // `remap`. `digits` may be too short to name them, and nodes
+                                    // only store the first `PREFIX` bytes of their prefixes, so
+                                    // we read them off of a leaf.
+                                    let mut ds = SmallVec::<[u8; 32]>::new();
+                                    match c_marked.get().unwrap() {
+                                        Ok(leaf) => ds.extend(leaf.key().digits()),
+                                        Err(inner) => with_node!(
+                                            inner,
+                                            nod,
+                                            ds.extend(nod.get_min().unwrap().key().digits()),
+                                            T
+                                        ),
+                                    }
+                                    for &target in remap.iter() {
+                                        debug_assert!(ds.len() >= target);
                                         trace!(_check);
-                                        buckets.insert(&d_slice[0..target], c_marked);
+                                        buckets
+                                            .insert(&ds[0..target], cache_entry(c_marked.clone()));
                                     }
                                 }
                             }
@@ -748,14 +1586,21 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                 trace!(_check);
                 let next_digit = digits[consumed + matched];
                 with_node_mut!(&mut *inner_node, node, {
-                    if let Some(c_ptr) = node.find_mut(next_digit) {
+                    // As with insertions, we decrement leaf counts on the way down. If the delete
+                    // succeeds `node` may have been freed, so we only touch it again to restore
+                    // the count when nothing was removed.
+                    let counts = node.typ.counts_leaves();
+                    if counts {
+                        *node.leaves_mut() -= 1;
+                    }
+                    let res = if let Some(c_ptr) = node.find_mut(next_digit) {
                         trace!(_check);
                         consumed += matched + 1;
                         let marked = c_ptr.to_marked();
                         delete_raw_recursive(
                             k,
                             marked,
-                            Some(c_ptr),
+                            Some(c_ptr as *mut _),
                             Some((
                                 next_digit,
                                 match curr_ptr {
@@ -765,14 +1610,21 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                             )),
                             digits,
                             consumed,
-                            target,
+                            targets,
+                            complete,
                             buckets,
+                            alloc,
+                            shrink,
                             false,
                         )
                     } else {
                         trace!(_check);
                         Failure
+                    };
+                    if counts && !res.is_success() {
+                        *node.leaves_mut() += 1;
                     }
+                    res
                 })
             } else if let Some(cp) = curr_ptr {
                 if !is_root {
@@ -781,23 +1633,34 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                 }
                 trace!(_check);
                 // we are in the root, set curr to null.
-                let c_ptr = cp.swap_null();
-                if C::ENABLED && digits.len() >= target {
-                    buckets.insert(&digits[0..target], MarkedPtr::null());
+                let c_ptr = (*cp).swap_null();
+                if C::ENABLED {
+                    for &target in targets {
+                        if digits.len() >= target {
+                            buckets.insert(&digits[0..target], MarkedPtr::null());
+                        }
+                    }
                 }
-                Success(move_val_out(c_ptr))
+                Success(take_leaf(c_ptr, alloc))
             } else {
                 trace!(_check);
                 Partial
             }
         }
+        self.tune_step(TUNE_SAMPLES, TUNE_FILL_STEP);
+        let targets = Self::maintained_levels(&self.prefix_levels, &self.tuner);
+        let complete = if Self::COMPLETE { &self.prefix_levels[..] } else { &[][..] };
         let mut res = Partial;
         if C::ENABLED {
-            let (elligible, opt) = self.hash_lookup(digits.as_slice());
+            let (eligible, level, opt) = self.hash_lookup(digits.as_slice(), !Self::COMPLETE);
+            self.observe(digits.as_slice(), if eligible { Some(opt.is_some()) } else { None });
             res = if let Some(ptr) = opt {
                 trace!(_check, "cache hit");
                 match ptr {
-                    Ok(_leaf) => Partial,
+                    Ok(_leaf) => {
+                        self.count(|c| &c.stale_hits);
+                        Partial
+                    }
                     Err(inner) => {
                         #[cfg(debug_assertions)]
                         with_node!(
@@ -809,27 +1672,34 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                 min_ds.extend(min.key().digits());
                                 if _check {
                                     assert_eq!(
-                                        &min_ds[0..self.prefix_target - 1],
-                                        &digits[0..self.prefix_target - 1]
+                                        &min_ds[0..level - 1],
+                                        &digits[0..level - 1]
                                     );
                                 }
                             },
                             T
                         );
-                        delete_raw_recursive(
+                        let res = delete_raw_recursive(
                             k,
                             inner,
                             None,
                             None,
                             &digits[..],
                             0,
-                            self.prefix_target,
+                            &targets[..],
+                            complete,
                             &mut self.buckets,
+                            &mut self.alloc,
+                            &self.shrink,
                             false,
-                        )
+                        );
+                        if let Partial = res {
+                            self.count(|c| &c.restarts);
+                        }
+                        res
                     }
                 }
-            } else if C::COMPLETE && elligible && self.len > 1 {
+            } else if Self::COMPLETE && eligible && self.len > 1 {
                 return None;
             } else {
                 Partial
@@ -840,12 +1710,15 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
             res = delete_raw_recursive(
                 k,
                 marked_root,
-                Some(&mut self.root),
+                Some(&mut self.root as *mut _),
                 None,
                 &digits[..],
                 0,
-                self.prefix_target,
+                &targets[..],
+                complete,
                 &mut self.buckets,
+                &mut self.alloc,
+                &self.shrink,
                 true,
             );
         }
@@ -860,33 +1733,59 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
         }
     }
 
-    pub unsafe fn insert_raw(&mut self, elt: T) -> Result<(), T> {
+    pub(crate) unsafe fn insert_raw(&mut self, elt: T) -> Result<(), T> {
         let mut digits = SmallVec::<[u8; 32]>::new();
         digits.extend(elt.key().digits());
-        unsafe fn insert_raw_recursive<T: Element, C: PrefixCache<T>>(
+        let res = self.insert_digits(elt, &digits);
+        if res.is_ok() {
+            // A new key was added. Xor filters cannot be updated, so they are discarded.
+            let discard = match self.filter.as_mut().map(|f| &mut f.filter) {
+                Some(&mut KeyFilter::Bloom(ref mut f)) => {
+                    f.insert(hash_digits(&digits[..]));
+                    false
+                }
+                Some(&mut KeyFilter::Xor(_)) => true,
+                None => false,
+            };
+            if discard {
+                self.filter = None;
+            }
+        }
+        res
+    }
+
+    unsafe fn insert_digits(&mut self, elt: T, digits: &SmallVec<[u8; 32]>) -> Result<(), T> {
+        #[allow(clippy::too_many_arguments)]
+        unsafe fn insert_raw_recursive<T: Element, C: PrefixCache<T>, A: NodeAllocator>(
             curr: MarkedPtr<T>,
             mut e: T,
             digits: &[u8],
             mut consumed: usize,
             pptr: Option<*mut ChildPtr<T>>,
             buckets: &mut C,
-            target: usize,
+            alloc: &mut A,
+            targets: &[usize],
+            node4: NodeType,
+            values: bool,
         ) -> PartialResult<T> {
             use self::PartialResult::*;
             debug_assert!(consumed <= digits.len());
             if curr.is_null() {
                 // Case 1: We found a null pointer, just replace it with a new leaf.
-                let new_leaf = ChildPtr::<T>::from_leaf(Box::into_raw(Box::new(e)));
+                let new_leaf = alloc_leaf(e, alloc);
                 (*pptr.unwrap()) = new_leaf;
-                if C::ENABLED && digits.len() >= target && consumed <= target {
-                    debug_assert!(buckets.lookup(&digits[0..target]).is_none());
-                    buckets.insert(&digits[0..target], (*pptr.unwrap()).to_marked());
+                for &target in targets {
+                    if C::ENABLED && digits.len() >= target && consumed <= target {
+                        debug_assert!(buckets.lookup(&digits[0..target]).is_none());
+                        let entry = cache_entry((*pptr.unwrap()).to_marked());
+                        buckets.insert(&digits[0..target], entry);
+                    }
                 }
 
                 return Success;
             }
             match curr.get_raw().unwrap() {
-                Ok(ln) => {
+                Ok(_) => {
                     debug_assert!(pptr.is_some());
                     // Case 2: We found a leaf node. We need to construct a new inner node with a the
                     // prefix corresponding to the shared prefix of this leaf node and `e`, add
@@ -897,7 +1796,10 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                     // these last few steps while we have still borrowed lead_node. We instead
                     // return the leaf's digits so we can do the rest of the loop outside of the
                     // match.
-                    let leaf_node = &mut *ln;
+                    //
+                    // N.B. `curr` is a copy, so we go through `pptr` to modify leaves that are
+                    // stored inline.
+                    let leaf_node = (*pptr.unwrap()).get_mut().unwrap().ok().unwrap();
                     if leaf_node.matches(e.key()) {
                         // Found a matching leaf node. We swap in our value and return the old one.
                         leaf_node.replace_matching(&mut e);
@@ -908,36 +1810,46 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                     let mut leaf_digits = SmallVec::<[u8; 8]>::new();
                     leaf_digits.extend(leaf_node.key().digits());
                     let pp = pptr.unwrap();
-                    let n4: Box<RawNode<Node4<T>>> = make_node_from_common_prefix(
+                    // The new node stores values if both keys end right below it.
+                    let last = digits.len() - 1;
+                    let values = values
+                        && leaf_digits.len() == digits.len()
+                        && leaf_digits[consumed..last] == digits[consumed..last];
+                    let n4_raw: *mut RawNode<Node4<T>> = make_node_from_common_prefix(
                         &leaf_digits[consumed..],
                         &digits[consumed..],
                         consumed as u32,
+                        if values { node4.with_values() } else { node4 },
+                        alloc,
                     );
-                    let prefix_len = n4.count as usize;
-                    let mut n4_raw = Box::into_raw(n4);
+                    let prefix_len = (*n4_raw).count as usize;
+                    if node4.counts_leaves() {
+                        *(*n4_raw).leaves_mut() = 2;
+                    }
                     let mut leaf_ptr = ChildPtr::from_node(n4_raw);
-                    let new_leaf = ChildPtr::from_leaf(Box::into_raw(Box::new(e)));
-                    mem::swap(&mut *pp, &mut leaf_ptr);
+                    ptr::swap(pp, &mut leaf_ptr);
 
-                    if C::ENABLED && consumed <= target
-                        && target <= consumed + (*n4_raw).count as usize
-                    {
-                        buckets.insert(&digits[0..target], (*pp).to_marked());
-                        debug_assert!((*pp).get().unwrap().is_err());
-                    } else if C::ENABLED && digits.len() >= target && consumed <= target {
-                        debug_assert!(buckets.lookup(&digits[0..target]).is_none());
-                        buckets.insert(&digits[0..target], (*pp).to_marked());
+                    for &target in targets {
+                        if C::ENABLED && consumed <= target
+                            && target <= consumed + (*n4_raw).count as usize
+                        {
+                            buckets.insert(&digits[0..target], (*pp).to_marked());
+                            debug_assert!((*pp).get().unwrap().is_err());
+                        } else if C::ENABLED && digits.len() >= target && consumed <= target {
+                            debug_assert!(buckets.lookup(&digits[0..target]).is_none());
+                            buckets.insert(&digits[0..target], (*pp).to_marked());
 
-                        // buckets.insert(&digits[0..target], new_leaf.to_marked());
-                    }
+                            // buckets.insert(&digits[0..target], new_leaf.to_marked());
+                        }
 
-                    if C::ENABLED && leaf_digits.len() >= target && consumed <= target {
-                        buckets.insert(&leaf_digits[0..target], (*pp).to_marked());
-                    }
-                    if C::ENABLED && C::COMPLETE && leaf_digits.len() >= target
-                        && consumed <= target
-                    {
-                        debug_assert!(buckets.lookup(&leaf_digits[0..target]).is_some())
+                        if C::ENABLED && leaf_digits.len() >= target && consumed <= target {
+                            buckets.insert(&leaf_digits[0..target], (*pp).to_marked());
+                        }
+                        if C::ENABLED && C::COMPLETE && leaf_digits.len() >= target
+                            && consumed <= target
+                        {
+                            debug_assert!(buckets.lookup(&leaf_digits[0..target]).is_some())
+                        }
                     }
                     // n4_raw has now replaced the leaf, we need to reinsert the leaf, along with
                     // our child pointer.
@@ -947,13 +1859,21 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                   digits,
                                   consumed);
 
-                    (*n4_raw)
-                        .insert(leaf_digits[consumed + prefix_len], leaf_ptr, None)
-                        .unwrap();
+                    let leaf_d = leaf_digits[consumed + prefix_len];
+                    let d = digits[consumed + prefix_len];
+                    let new_leaf = if values {
+                        let old = take_leaf(leaf_ptr, alloc);
+                        let leaf_ptr = store_value(&mut *n4_raw, leaf_d, old);
+                        (*n4_raw).insert(leaf_d, leaf_ptr, None, alloc).unwrap();
+                        store_value(&mut *n4_raw, d, e)
+                    } else {
+                        // The leaf may have been stored in its old parent's value slots.
+                        let leaf_ptr = detach_leaf(leaf_ptr, alloc);
+                        (*n4_raw).insert(leaf_d, leaf_ptr, None, alloc).unwrap();
+                        alloc_leaf(e, alloc)
+                    };
 
-                    (*n4_raw)
-                        .insert(digits[consumed + prefix_len], new_leaf, None)
-                        .unwrap()
+                    (*n4_raw).insert(d, new_leaf, None, alloc).unwrap()
                 }
                 Err(inn) => {
                     let inner_node = &mut *inn;
@@ -966,7 +1886,7 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                     consumed = inner_node.consumed as usize;
                     // found an interior node. need to continue the search!
                     let (matched, min_ref) = inner_node.get_matching_prefix(
-                        &digits[..],
+                        digits,
                         consumed,
                         PhantomData as PhantomData<T>,
                     );
@@ -988,87 +1908,145 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                         let d = digits[consumed];
 
                         with_node_mut!(inner_node, nod, {
-                            // TODO validate the prefix logic here:
-                            // if there's an optimistic prefix we may have to adjust its
-                            // length...  for now it may be safer to just truncate the prefix
-                            nod.count = cmp::min(nod.count, PREFIX_LEN as u32);
+                            // Leaf counts are bumped on the way down and restored if we turn out
+                            // not to add a new leaf. If `nod` grows below, the new node inherits
+                            // the updated count.
+                            let counts = nod.typ.counts_leaves();
+                            if counts {
+                                *nod.leaves_mut() += 1;
+                            }
                             if let Some(next_ptr) = nod.find_mut(d) {
                                 let pp = Some(next_ptr as *mut _);
 
-                                return insert_raw_recursive(
+                                let res = insert_raw_recursive(
                                     next_ptr.to_marked(),
                                     e,
                                     digits,
                                     consumed + 1,
                                     pp,
                                     buckets,
-                                    target,
+                                    alloc,
+                                    targets,
+                                    node4,
+                                    values,
                                 );
+                                if counts && !res.is_success() {
+                                    *nod.leaves_mut() -= 1;
+                                }
+                                return res;
                             }
                             let full = nod.is_full();
                             if C::ENABLED && full && pptr.is_none() {
                                 return Failure(e);
                             }
-                            let c_ptr = ChildPtr::<T>::from_leaf(Box::into_raw(Box::new(e)));
-                            let _r = nod.insert(d, c_ptr, pptr);
-                            debug_assert!(_r.is_ok());
+                            // N.B. if `nod` is full, inserting replaces it and we free it, so we
+                            // read its header beforehand.
+                            let (nod_consumed, nod_count) =
+                                (nod.consumed as usize, nod.count as usize);
+                            let store = nod.typ.stores_values() && consumed + 1 == digits.len();
+                            let c_ptr = if store && !full {
+                                store_value(nod, d, e)
+                            } else {
+                                alloc_leaf(e, alloc)
+                            };
+                            let r = nod.insert(d, c_ptr, pptr, alloc);
+                            debug_assert!(r.is_ok());
+                            if let Ok(Some(old)) = r {
+                                free_node(old, alloc);
+                            }
+                            if store && full {
+                                // `nod` has been replaced by a larger node, with room for `e`.
+                                let new_nod = (*pptr.unwrap()).get_mut().unwrap().err().unwrap();
+                                with_node_mut!(new_nod, n, adopt_leaf(n, d, alloc), T);
+                            }
                             if C::ENABLED {
-                                if nod.consumed as usize <= target
-                                    && target <= nod.consumed as usize + nod.count as usize
-                                {
-                                    if full {
-                                        let marked_p = (*pptr.unwrap()).to_marked();
-                                        buckets.insert(&digits[0..target], marked_p.clone());
-                                    }
-                                } else if digits.len() >= target && consumed <= target && !full {
-                                    #[cfg(debug_assertions)]
+                                for &target in targets {
+                                    if nod_consumed <= target && target <= nod_consumed + nod_count
                                     {
-                                        if let Some(ptr) = buckets.lookup(&digits[0..target]) {
-                                            match ptr.get().unwrap() {
-                                                Ok(_leaf) => eprintln!("overwriting leaf node!"),
-                                                Err(other_inner) =>
-                                                    eprintln!("overwriting inner node: {:?} ptr={:?} pptr={:?} inner={:?}",
-                                                              other_inner,
-                                                              ptr,
-                                                              pptr.map(|x| &*x),
-                                                              inn),
+                                        if full {
+                                            let marked_p = (*pptr.unwrap()).to_marked();
+                                            buckets.insert(&digits[0..target], marked_p.clone());
+                                        }
+                                    } else if digits.len() >= target && consumed <= target && !full
+                                    {
+                                        #[cfg(debug_assertions)]
+                                        {
+                                            if let Some(ptr) = buckets.lookup(&digits[0..target]) {
+                                                match ptr.get().unwrap() {
+                                                    Ok(_leaf) => {
+                                                        eprintln!("overwriting leaf node!")
+                                                    }
+                                                    Err(other_inner) =>
+                                                        eprintln!("overwriting inner node: {:?} ptr={:?} pptr={:?} inner={:?}",
+                                                                  other_inner,
+                                                                  ptr,
+                                                                  pptr.map(|x| &*x),
+                                                                  inn),
+                                                }
+                                                panic!("Overwriting leaf insertion");
                                             }
-                                            panic!("Overwriting leaf insertion");
                                         }
-                                    }
 
-                                    buckets.insert(&digits[0..target], MarkedPtr::from_node(nod));
-                                } else if full && consumed <= target {
-                                    let marked_p = (*pptr.unwrap()).to_marked();
-                                    // If we were full we have to remap all leaves that are
-                                    // children of nod to the new value.
-                                    let mut mp = marked_p.clone();
-                                    let new_nod = mp.get_mut().unwrap().err().unwrap();
-                                    with_node_mut!(
-                                        new_nod,
-                                        nod,
-                                        {
-                                            nod.local_foreach(|_, n| {
-                                                if let Ok(leaf) = n.get().unwrap() {
-                                                    let mut ds = SmallVec::<[u8; 8]>::new();
-                                                    ds.extend(leaf.key().digits());
-                                                    if ds.len() < target {
-                                                        return;
+                                        buckets
+                                            .insert(&digits[0..target], MarkedPtr::from_node(nod));
+                                    } else if full && consumed <= target {
+                                        let marked_p = (*pptr.unwrap()).to_marked();
+                                        // If we were full we have to remap all leaves that are
+                                        // children of nod to the new value.
+                                        let mut mp = marked_p.clone();
+                                        let new_nod = mp.get_mut().unwrap().err().unwrap();
+                                        // Collect the prefixes first: inserting into the cache
+                                        // writes the node's generation.
+                                        let mut prefixes = Vec::new();
+                                        with_node_mut!(
+                                            new_nod,
+                                            nod,
+                                            {
+                                                nod.local_foreach(|_, n| {
+                                                    if let Ok(leaf) = n.get().unwrap() {
+                                                        let mut ds = SmallVec::<[u8; 8]>::new();
+                                                        ds.extend(leaf.key().digits());
+                                                        if ds.len() < target {
+                                                            return;
+                                                        }
+                                                        ds.truncate(target);
+                                                        prefixes.push(ds);
                                                     }
-                                                    buckets
-                                                        .insert(&ds[0..target], marked_p.clone());
-                                                }
-                                            });
-                                        },
-                                        T
-                                    );
+                                                });
+                                            },
+                                            T
+                                        );
+                                        for ds in prefixes {
+                                            buckets.insert(&ds[..], marked_p.clone());
+                                        }
+                                    }
                                 }
                             }
 
                             return Success;
                         });
                     } else {
-                        let inner_d = inner_node.prefix[matched];
+                        // Only the first `prefix_capacity` bytes of the prefix are stored in the
+                        // node; past that we have to read them off of a leaf.
+                        let cap = inner_node.typ.prefix_capacity();
+                        let min_ref = match min_ref {
+                            None if inner_node.count as usize > cap => with_node!(
+                                &*inner_node,
+                                node,
+                                node.get_min().map(|m| m as *const T),
+                                T
+                            ),
+                            _ => min_ref,
+                        };
+                        let inner_d = if matched < cap {
+                            inner_node.prefix()[matched]
+                        } else {
+                            (*min_ref.unwrap())
+                                .key()
+                                .digits()
+                                .nth(consumed + matched)
+                                .unwrap()
+                        };
                         if pptr.is_none() {
                             return Failure(e);
                         }
@@ -1076,11 +2054,13 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                         //
                         // Here we have to figure out where the mismatch is and create a new parent
                         // node for the inner node and our current node.
+                        //
+                        // `adjust_prefix` drops the first `by` bytes of `n`'s prefix. It must be
+                        // called after `n.consumed` has been updated.
                         unsafe fn adjust_prefix<R, T: Element>(
                             n: &mut RawNode<R>,
                             by: usize,
                             leaf: Option<*const T>,
-                            consumed: usize,
                         ) {
                             debug_assert!(by > 0);
                             debug_assert!(
@@ -1089,15 +2069,23 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                                 by,
                                 n.count
                             );
+                            let cap = n.typ.prefix_capacity();
                             let old_count = n.count as usize;
                             n.count -= by as u32;
-                            let start: *const _ = &n.prefix[by];
-                            ptr::copy(start, &mut n.prefix[0], n.count as usize);
-                            if old_count > PREFIX_LEN {
+                            let stored = cmp::min(n.count as usize, cap);
+                            let consumed = n.consumed as usize;
+                            let prefix = n.prefix_mut();
+                            if by < cap {
+                                prefix.copy_within(by..by + cmp::min(stored, cap - by), 0);
+                            }
+                            if old_count > cap {
+                                // Fill in the bytes that were not stored in the node before.
+                                let known = cap.saturating_sub(by);
                                 let leaf_ref = &*leaf.unwrap();
-                                for (p, d) in n.prefix[PREFIX_LEN - by..]
+                                let skip = consumed + known;
+                                for (p, d) in prefix[known..stored]
                                     .iter_mut()
-                                    .zip(leaf_ref.key().digits().skip(consumed))
+                                    .zip(leaf_ref.key().digits().skip(skip))
                                 {
                                     *p = d;
                                 }
@@ -1113,48 +2101,66 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                         // containing `e`.
                         let common_prefix_digits = &digits[consumed..consumed + matched];
                         debug_assert_eq!(common_prefix_digits.len(), matched);
-                        let n4: Box<RawNode<Node4<T>>> =
-                            make_node_with_prefix(&common_prefix_digits[..], consumed as u32);
+                        let n4_raw: *mut RawNode<Node4<T>> =
+                            make_node_with_prefix(
+                                common_prefix_digits,
+                                consumed as u32,
+                                node4,
+                                alloc,
+                            );
+                        let n4 = &mut *n4_raw;
+                        if node4.counts_leaves() {
+                            *n4.leaves_mut() = inner_node.leaves() + 1;
+                        }
                         inner_node.consumed += n4.count + 1;
                         debug_assert_eq!(n4.count as usize, common_prefix_digits.len());
-                        let update_cache_inner = C::ENABLED && consumed <= target
-                            && target <= consumed + n4.count as usize;
-                        consumed += n4.count as usize;
+                        let (n4_consumed, n4_count) = (consumed, n4.count as usize);
+                        consumed += n4_count;
                         let by = matched + 1;
-                        adjust_prefix(inner_node, by, min_ref, consumed);
+                        adjust_prefix(inner_node, by, min_ref);
 
                         // Now allocate a node to contain `e`, insert it into the prefix cache if
                         // necessary, and insert it into n4.
-                        let c_ptr = ChildPtr::<T>::from_leaf(Box::into_raw(Box::new(e)));
-
-                        let mut n4_raw = Box::into_raw(n4);
-                        let _r = (*n4_raw).insert(digits[consumed], c_ptr, None);
+                        let c_ptr = alloc_leaf(e, alloc);
+                        let _r = n4.insert(digits[consumed], c_ptr, None, alloc);
                         debug_assert!(_r.is_ok());
                         let mut n4_cptr = ChildPtr::from_node(n4_raw);
                         // Now swap `inner` with n4 (thereby inserting it into the tree) and insert
                         // `inner` as a child of n4.
                         let pp = pptr.unwrap();
-                        mem::swap(&mut *pp, &mut n4_cptr);
-                        if update_cache_inner {
-                            buckets.insert(&digits[0..target], (*pp).to_marked());
-                            debug_assert!((*pp).get().unwrap().is_err());
-                        } else if C::ENABLED && digits.len() >= target && consumed <= target {
-                            buckets.insert(&digits[0..target], (*pp).to_marked());
+                        ptr::swap(pp, &mut n4_cptr);
+                        for &target in targets {
+                            if C::ENABLED && n4_consumed <= target
+                                && target <= n4_consumed + n4_count
+                            {
+                                buckets.insert(&digits[0..target], (*pp).to_marked());
+                                debug_assert!((*pp).get().unwrap().is_err());
+                            } else if C::ENABLED && digits.len() >= target && consumed <= target {
+                                buckets.insert(&digits[0..target], (*pp).to_marked());
+                            }
                         }
 
-                        (*n4_raw).insert(inner_d, n4_cptr, None).unwrap()
+                        n4.insert(inner_d, n4_cptr, None, alloc).unwrap()
                     }
                 }
             };
             Success
         }
         if C::ENABLED {
+            self.tune_step(TUNE_SAMPLES, TUNE_FILL_STEP);
+            let targets = Self::maintained_levels(&self.prefix_levels, &self.tuner);
             let e = {
                 let (node_ref, consumed, pptr) = {
-                    let (_, opt) = self.hash_lookup(digits.as_slice());
+                    // An insertion can start from any level, so we fall back to shallower levels
+                    // even if the cache is complete.
+                    let (_, level, opt) = self.hash_lookup(digits.as_slice(), true);
+                    self.observe(digits.as_slice(), None);
                     if let Some(Err(inner)) = opt {
-                        (inner, self.prefix_target, None)
+                        (inner, level, None)
                     } else {
+                        if let Some(Ok(_leaf)) = opt {
+                            self.count(|c| &c.stale_hits);
+                        }
                         let root_alias = Some(&mut self.root as *mut _);
                         (self.root.to_marked(), 0, root_alias)
                     }
@@ -1166,9 +2172,15 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                     consumed,
                     pptr,
                     &mut self.buckets,
-                    self.prefix_target,
+                    &mut self.alloc,
+                    &targets[..],
+                    Self::node4(),
+                    Self::multi_value(),
                 ) {
-                    PartialResult::Failure(e) => e,
+                    PartialResult::Failure(e) => {
+                        self.count(|c| &c.restarts);
+                        e
+                    }
                     PartialResult::Success => {
                         self.len += 1;
                         return Ok(());
@@ -1187,7 +2199,10 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                 0,
                 root_alias,
                 &mut self.buckets,
-                self.prefix_target,
+                &mut self.alloc,
+                &targets[..],
+                Self::node4(),
+                Self::multi_value(),
             ) {
                 PartialResult::Success => {
                     self.len += 1;
@@ -1205,7 +2220,10 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
                 0,
                 root_alias,
                 &mut self.buckets,
-                self.prefix_target,
+                &mut self.alloc,
+                &self.prefix_levels[..],
+                Self::node4(),
+                Self::multi_value(),
             ) {
                 PartialResult::Success => {
                     self.len += 1;
@@ -1218,13 +2236,646 @@ impl<T: Element, C: PrefixCache<T>> RawART<T, C> {
     }
 }
 
+/// Prefix length tuning.
+///
+/// See `with_adaptive_prefix`. None of these do anything for trees with a fixed prefix length.
+impl<T, C, A, L, const PREFIX: usize, S> RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
+    /// Pick a new prefix length if the statistics collected so far suggest one, and finish
+    /// filling in the cache entries for it.
+    pub fn tune_prefix_len(&mut self) {
+        self.tune_step(1, usize::MAX);
+    }
+
+    /// The prefix lengths that mutations have to keep up to date in the cache: those in use,
+    /// along with one whose entries are being filled in.
+    ///
+    /// This takes the fields it reads rather than `&self`, so that the result can be held while
+    /// the rest of the tree is borrowed mutably. It only copies the levels while one is pending.
+    fn maintained_levels<'a>(
+        prefix_levels: &'a [usize],
+        tuner: &Option<Box<PrefixTuner>>,
+    ) -> Cow<'a, [usize]> {
+        match tuner.as_ref().and_then(|t| t.pending.as_ref()) {
+            Some(&(level, _)) if !prefix_levels.contains(&level) => {
+                let mut res = prefix_levels.to_vec();
+                res.push(level);
+                Cow::Owned(res)
+            }
+            _ => Cow::Borrowed(prefix_levels),
+        }
+    }
+
+    /// Record an operation on `digits`. `hit` says whether the cache had an entry for the key, if
+    /// it was probed.
+    fn observe(&self, digits: &[u8], hit: Option<bool>) {
+        let tuner = match self.tuner {
+            Some(ref t) => t,
+            None => return,
+        };
+        let st = &tuner.stats;
+        let ops = bump(&st.ops);
+        if let Some(h) = hit {
+            bump(&st.probes);
+            if h {
+                bump(&st.hits);
+            }
+        }
+        if !ops.is_multiple_of(TUNE_SAMPLE_INTERVAL) {
+            return;
+        }
+        bump(&st.samples);
+        // Only keys longer than a prefix are looked up with it.
+        let max_len = cmp::min(
+            cmp::min(C::MAX_PREFIX_LEN, TUNE_MAX_PREFIX_LEN),
+            digits.len().saturating_sub(1),
+        );
+        // The entry for a prefix of length `l` is the first node on the path to `digits` whose
+        // prefix extends to `l`, so an operation starting there skips every node whose prefix
+        // ends before `l`.
+        let mut curr = unsafe { self.root.to_marked() };
+        while let Some(Err(inner)) = unsafe { curr.get() } {
+            let end = (inner.consumed + inner.count) as usize;
+            for l in (end + 1)..(max_len + 1) {
+                bump(&st.skipped[l]);
+            }
+            if end >= digits.len() {
+                break;
+            }
+            let next = with_node!(
+                inner,
+                node,
+                node.find(digits[end]).map(|c| unsafe { c.to_marked() }),
+                T
+            );
+            match next {
+                Some(c) => curr = c,
+                None => break,
+            }
+        }
+    }
+
+    /// Reconsider the prefix length if at least `min_samples` samples have been collected, and
+    /// fill in up to `fill` cache entries for a new one.
+    fn tune_step(&mut self, min_samples: usize, fill: usize) {
+        let current = self.prefix_len();
+        let choice = match self.tuner {
+            None => return,
+            Some(ref t) if t.pending.is_some() => None,
+            Some(ref mut t) => {
+                if read(&t.stats.samples) < min_samples {
+                    return;
+                }
+                let choice = Self::choose_prefix_len(&t.stats, current);
+                t.stats = TunerStats::new();
+                choice
+            }
+        };
+        if let Some(level) = choice {
+            if C::COMPLETE {
+                let mut cursor = SmallVec::new();
+                cursor.extend((0..level).map(|_| 0));
+                self.tuner.as_mut().unwrap().pending = Some((level, cursor));
+            } else {
+                // Incomplete caches are filled in by lookups that miss, so there is nothing to
+                // wait for.
+                self.finish_retarget(level);
+            }
+        }
+        unsafe { self.fill_pending(fill) };
+    }
+
+    fn choose_prefix_len(st: &TunerStats, current: usize) -> Option<usize> {
+        let max_len = cmp::min(C::MAX_PREFIX_LEN, TUNE_MAX_PREFIX_LEN);
+        let skipped = |l: usize| read(&st.skipped[l]);
+        let best_score = (1..max_len + 1).map(&skipped).max().unwrap_or(0);
+        if best_score == 0 {
+            return None;
+        }
+        // Longer prefixes mean more cache entries, so we settle for the shortest length that does
+        // nearly as well as the best one.
+        let best = (1..max_len + 1)
+            .find(|&l| skipped(l) * 8 >= best_score * 7)
+            .unwrap();
+        if best == current {
+            return None;
+        }
+        let mut current_score = if current <= max_len {
+            skipped(current)
+        } else {
+            0
+        };
+        let probes = read(&st.probes);
+        if !C::COMPLETE && probes > 0 {
+            // Operations that miss in the cache start from the root.
+            current_score = current_score * read(&st.hits) / probes;
+        }
+        if best < current || skipped(best) > current_score + current_score / 8 {
+            Some(best)
+        } else {
+            None
+        }
+    }
+
+    /// Fill in up to `budget` cache entries for the pending prefix length, in increasing order of
+    /// prefixes. Mutations keep the entries that have already been filled in up to date.
+    unsafe fn fill_pending(&mut self, budget: usize) {
+        let (level, mut cursor) = match self.tuner.as_mut().and_then(|t| t.pending.take()) {
+            Some(p) => p,
+            None => return,
+        };
+        let mut digits = SmallVec::<[u8; 32]>::new();
+        for _ in 0..budget {
+            let leaf = match first_leaf_from(&self.root, &cursor[..]) {
+                Some(leaf) => leaf,
+                None => return self.finish_retarget(level),
+            };
+            digits.clear();
+            digits.extend((*leaf).key().digits());
+            if digits.len() < level {
+                // This key is too short to have a prefix of length `level`. Move on to the next
+                // one.
+                cursor.clear();
+                cursor.extend(digits.iter().cloned());
+                cursor.push(0);
+                continue;
+            }
+            let prefix = &digits[0..level];
+            // Only leaves stored inline have no entry.
+            if let Some(entry) = self.find_prefix_entry(prefix) {
+                self.buckets.insert(prefix, entry);
+            }
+            // Skip the rest of the keys starting with `prefix`.
+            cursor.clear();
+            cursor.extend(prefix.iter().cloned());
+            while cursor.last() == Some(&255) {
+                cursor.pop();
+            }
+            match cursor.last_mut() {
+                Some(d) => *d += 1,
+                None => return self.finish_retarget(level),
+            }
+        }
+        self.tuner.as_mut().unwrap().pending = Some((level, cursor));
+    }
+
+    fn finish_retarget(&mut self, level: usize) {
+        for &old in self.prefix_levels.iter() {
+            if old != level {
+                self.buckets.clear_prefix_len(old);
+            }
+        }
+        self.prefix_levels.clear();
+        self.prefix_levels.push(level);
+        self.tuner.as_mut().unwrap().pending = None;
+    }
+}
+
+/// The smallest leaf below `curr` whose digits are at least `from`.
+unsafe fn first_leaf_from<T: Element>(curr: &MarkedPtr<T>, from: &[u8]) -> Option<*const T> {
+    let inner = match curr.get() {
+        None => return None,
+        Some(Ok(leaf)) => {
+            return if leaf.key().digits().ge(from.iter().cloned()) {
+                Some(leaf)
+            } else {
+                None
+            };
+        }
+        Some(Err(inner)) => inner,
+    };
+    let min = with_node!(inner, node, node.get_min(), T).expect("inner nodes must be nonempty");
+    let consumed = inner.consumed as usize;
+    let pos = consumed + inner.count as usize;
+    if from.len() <= consumed {
+        return Some(min);
+    }
+    // Only the first few bytes of the prefix are stored in the node, so we read it off of the
+    // minimum leaf.
+    let bound = &from[consumed..cmp::min(pos, from.len())];
+    let ord = min.key()
+        .digits()
+        .skip(consumed)
+        .take(bound.len())
+        .cmp(bound.iter().cloned());
+    match ord {
+        cmp::Ordering::Less => None,
+        cmp::Ordering::Greater => Some(min),
+        cmp::Ordering::Equal if from.len() <= pos => Some(min),
+        cmp::Ordering::Equal => {
+            let d = from[pos];
+            if let Some(c) = with_node!(inner, node, node.find(d), T) {
+                if let Some(leaf) = first_leaf_from(c, from) {
+                    return Some(leaf);
+                }
+            }
+            // Everything below the next child is larger than `from`.
+            let mut next: Option<u8> = None;
+            with_node!(
+                inner,
+                node,
+                node.local_foreach(|cd, _| if cd > d && next.is_none_or(|n| cd < n) {
+                    next = Some(cd);
+                }),
+                T
+            );
+            let next = with_node!(inner, node, next.and_then(|cd| node.find(cd)), T);
+            next.and_then(|c| match c.get() {
+                None => None,
+                Some(Ok(leaf)) => Some(leaf as *const T),
+                Some(Err(n)) => with_node!(n, node, node.get_min().map(|m| m as *const T), T),
+            })
+        }
+    }
+}
+
+/// Compaction.
+impl<T, C, A, L, const PREFIX: usize, S> RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
+    /// Release memory that the tree no longer needs.
+    ///
+    /// Inner nodes are upgraded to a larger node type when they fill up, but by default they keep
+    /// that type as their children are removed (see `set_shrink_thresholds`): after a mass
+    /// deletion, a tree can be full of `Node256`s with a handful of children. This moves the
+    /// children of every inner node into the smallest node type that can hold them, and then
+    /// shrinks the prefix cache to fit its entries.
+    ///
+    /// The old nodes go back to the tree's allocator. A `SlabAllocator` keeps their memory for
+    /// later nodes rather than returning it (see `SlabAllocator`), so this lowers the tree's
+    /// `memory_usage` but not the memory the slab has reserved.
+    pub fn shrink_to_fit(&mut self) {
+        let targets = Self::maintained_levels(&self.prefix_levels, &self.tuner);
+        unsafe {
+            shrink_recursive(&mut self.root, &mut self.buckets, &mut self.alloc, &targets[..])
+        };
+        self.buckets.shrink_to_fit();
+    }
+}
+
+/// Rebuilding and converting the prefix cache.
+impl<T, C, A, L, const PREFIX: usize, S> RawART<T, C, A, L, PREFIX, S>
+where
+    T: Element,
+    C: PrefixCache<T>,
+    A: NodeAllocator,
+    L: LeafLayout,
+    S: NodeAnnotations,
+{
+    /// Walk the tree and insert the entry for every cached prefix of every key into the prefix
+    /// cache, replacing whatever it held before.
+    ///
+    /// The tree keeps the cache up to date as it is modified, so this is only needed when the
+    /// cache starts out cold, as it does after `convert_cache`. For caches that are not `COMPLETE`,
+    /// it fills the cache with an arbitrary subset of the entries.
+    pub fn rebuild_prefix_cache(&mut self) {
+        if !C::ENABLED {
+            return;
+        }
+        let targets = Self::maintained_levels(&self.prefix_levels, &self.tuner);
+        for &target in targets.iter() {
+            self.buckets.clear_prefix_len(target);
+        }
+        unsafe { rebuild_recursive(self.root.to_marked(), 0, &mut self.buckets, &targets[..]) };
+        // Any level that was being filled in is now complete.
+        let pending = self.tuner.as_ref().and_then(|t| t.pending.as_ref().map(|p| p.0));
+        if let Some(level) = pending {
+            self.finish_retarget(level);
+        }
+    }
+
+    /// Switch the tree to a different kind of prefix cache, keeping its contents, prefix lengths
+    /// and tuning state. This turns an `ARTSet` into a `CachingARTSet` (or back) without
+    /// reinserting every key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the tree's prefix lengths exceeds `C2::MAX_PREFIX_LEN`.
+    pub fn convert_cache<C2: PrefixCache<T>>(self) -> RawART<T, C2, A, L, PREFIX, S> {
+        let () = RawART::<T, C2, A, L, PREFIX, S>::VALID_ANNOTATIONS;
+        for &level in self.prefix_levels.iter() {
+            assert!(level <= C2::MAX_PREFIX_LEN);
+        }
+        // `RawART` frees its nodes when it is dropped, so we move the fields we keep out by hand
+        // and drop the old cache.
+        let mut old = mem::ManuallyDrop::new(self);
+        let mut res = RawART {
+            len: old.len,
+            root: old.root.swap_null(),
+            alloc: unsafe { ptr::read(&old.alloc) },
+            prefix_levels: mem::replace(&mut old.prefix_levels, SmallVec::new()),
+            buckets: C2::new(),
+            tuner: old.tuner.take(),
+            counters: old.counters.take().map(|_| CacheCounters::default()),
+            filter: old.filter.take(),
+            shrink: old.shrink,
+            leaves: PhantomData,
+        };
+        unsafe { ptr::drop_in_place(&mut old.buckets) };
+        // Only the nodes of trees with a tagged cache have room for a generation.
+        if C::TAGS_NODES != C2::TAGS_NODES {
+            unsafe { set_generations_recursive(&mut res.root, C2::TAGS_NODES, &mut res.alloc) };
+        }
+        // The counts so far describe the old cache, but a tree that was recording them keeps
+        // doing so.
+        if res.counters.is_some() {
+            res.buckets.record_stats(true);
+        }
+        if C2::ENABLED {
+            res.rebuild_prefix_cache();
+        }
+        res
+    }
+}
+
+/// Insert the cache entries for every key at or below `curr`, which hangs `depth` digits into
+/// its keys.
+unsafe fn rebuild_recursive<T: Element, C: PrefixCache<T>>(
+    curr: MarkedPtr<T>,
+    depth: usize,
+    buckets: &mut C,
+    targets: &[usize],
+) {
+    let mut digits = SmallVec::<[u8; 32]>::new();
+    match curr.get() {
+        None => {}
+        Some(Ok(leaf)) => {
+            digits.extend(leaf.key().digits());
+            for &target in targets {
+                if depth <= target && target <= digits.len() {
+                    buckets.insert(&digits[0..target], cache_entry(curr.clone()));
+                }
+            }
+        }
+        Some(Err(inner)) => {
+            let (consumed, count) = (inner.consumed as usize, inner.count as usize);
+            let min =
+                with_node!(inner, node, node.get_min(), T).expect("inner nodes must be nonempty");
+            digits.extend(min.key().digits());
+            for &target in targets {
+                if consumed <= target && target <= consumed + count {
+                    buckets.insert(&digits[0..target], curr.clone());
+                }
+            }
+            with_node!(
+                inner,
+                node,
+                node.local_foreach(|_, c| {
+                    rebuild_recursive(c, consumed + count + 1, buckets, targets)
+                }),
+                T
+            );
+        }
+    }
+}
+
+/// Move every node at or below `cptr` into one with a generation if `on` is true, or without one
+/// otherwise (see `set_has_generation`).
+unsafe fn set_generations_recursive<T: Element, A: NodeAllocator>(
+    cptr: &mut ChildPtr<T>,
+    on: bool,
+    alloc: &mut A,
+) {
+    if !matches!(cptr.get(), Some(Err(_))) {
+        return;
+    }
+    set_has_generation(cptr, on, alloc);
+    with_node_mut!(
+        cptr.get_mut().unwrap().err().unwrap(),
+        node,
+        {
+            let mut ds = SmallVec::<[u8; 64]>::new();
+            node.local_foreach(|d, _| ds.push(d));
+            for d in ds {
+                set_generations_recursive(node.find_mut(d).unwrap(), on, alloc);
+            }
+        },
+        T
+    )
+}
+
+/// Add the nodes and leaves at or below `curr` to `report`.
+unsafe fn memory_recursive<T: Element>(curr: MarkedPtr<T>, report: &mut MemoryReport) {
+    match curr.get_raw() {
+        None => {}
+        Some(Ok(_)) if curr.is_inline() => report.inline_leaves += 1,
+        Some(Ok(_)) if curr.is_stored() => report.stored_leaves += 1,
+        Some(Ok(_)) => report.leaves.add(leaf_layout::<T>().size()),
+        Some(Err(inner)) => {
+            let bytes = with_node!(&*inner, node, node.allocation().1.size(), T);
+            node_kinds!(kind_field, report, (*inner).typ).add(bytes);
+            report.children += (*inner).children as usize;
+            if (*inner).count as usize > (*inner).typ.prefix_capacity() {
+                report.implicit_prefixes += 1;
+            }
+            with_node!(&*inner, node, node.local_foreach(|_, c| memory_recursive(c, report)), T);
+        }
+    }
+}
+
+/// Shrink every inner node at or below `cptr` (see `shrink_node`), pointing the cache entries for
+/// any node that moves at its replacement.
+unsafe fn shrink_recursive<T: Element, C: PrefixCache<T>, A: NodeAllocator>(
+    cptr: &mut ChildPtr<T>,
+    buckets: &mut C,
+    alloc: &mut A,
+    targets: &[usize],
+) {
+    match cptr.get_mut() {
+        Some(Err(inner)) => with_node_mut!(
+            inner,
+            node,
+            {
+                let mut ds = SmallVec::<[u8; 64]>::new();
+                node.local_foreach(|d, _| ds.push(d));
+                for d in ds {
+                    shrink_recursive(node.find_mut(d).unwrap(), buckets, alloc, targets);
+                }
+            },
+            T
+        ),
+        _ => return,
+    }
+    shrink_and_remap(cptr, buckets, alloc, targets);
+}
+
+/// The cache entry for a prefix whose node or leaf is `ptr`. Leaves stored inline are left out
+/// of the cache: the entry would be a copy of the element, and a hit would still have to find
+/// the element in the tree to return a reference to it.
+fn cache_entry<T>(ptr: MarkedPtr<T>) -> MarkedPtr<T> {
+    if ptr.is_inline() {
+        MarkedPtr::null()
+    } else {
+        ptr
+    }
+}
+
+/// Shrink the inner node that `cptr` points to (see `shrink_node`). If it moves, point the cache
+/// entries for it at its replacement.
+unsafe fn shrink_and_remap<T: Element, C: PrefixCache<T>, A: NodeAllocator>(
+    cptr: &mut ChildPtr<T>,
+    buckets: &mut C,
+    alloc: &mut A,
+    targets: &[usize],
+) {
+    if !shrink_node(cptr, alloc) || !C::ENABLED {
+        return;
+    }
+    let inner = cptr.get().unwrap().err().unwrap();
+    let (consumed, count) = (inner.consumed as usize, inner.count as usize);
+    let min = with_node!(inner, node, node.get_min(), T).expect("inner nodes must be nonempty");
+    let mut digits = SmallVec::<[u8; 32]>::new();
+    digits.extend(min.key().digits());
+    for &target in targets {
+        if consumed <= target && target <= consumed + count {
+            buckets.insert(&digits[0..target], cptr.to_marked());
+        }
+    }
+    // Longer prefixes of the keys of leaf children are cached as pointing to the node as well
+    // (see the node upgrade case in `insert_digits`).
+    let marked = cptr.to_marked();
+    with_node!(
+        inner,
+        node,
+        node.local_foreach(|_, c| {
+            if let Ok(leaf) = c.get().unwrap() {
+                let mut ds = SmallVec::<[u8; 32]>::new();
+                ds.extend(leaf.key().digits());
+                for &target in targets {
+                    if consumed + count < target && target <= ds.len() {
+                        buckets.insert(&ds[0..target], marked.clone());
+                    }
+                }
+            }
+        }),
+        T
+    );
+}
+
+/// Order statistics.
+///
+/// These rely on the per-node leaf counts in `RawNode::leaves`, which trees keep when they are
+/// built with `LeafCounts`. Nodes only store the count of their own subtree, so finding how many
+/// leaves sit below the lower siblings of a child means adding up their counts: `rank` and
+/// `select` take O(depth × fan-out) time, and a path through `Node256`s reads up to 255 counts
+/// per level.
+impl<T: Element, A: NodeAllocator, L: LeafLayout, const PREFIX: usize>
+    RawART<T, NullBuckets<T>, A, L, PREFIX, LeafCounts>
+{
+    /// The number of elements with keys strictly less than `key`.
+    pub fn rank<Q>(&self, key: &Q) -> usize
+    where
+        Q: Borrow<T::Key> + ?Sized,
+    {
+        let k = key.borrow();
+        let mut digits = SmallVec::<[u8; 32]>::new();
+        digits.extend(k.digits());
+        let mut res = 0;
+        let mut curr = unsafe { self.root.to_marked() };
+        loop {
+            match unsafe { curr.get() } {
+                None => return res,
+                Some(Ok(leaf)) => return res + if leaf.key() < k { 1 } else { 0 },
+                Some(Err(inner)) => {
+                    let consumed = inner.consumed as usize;
+                    let matched = if consumed < digits.len() {
+                        inner
+                            .get_matching_prefix(&digits[..], consumed, PhantomData as PhantomData<T>)
+                            .0
+                    } else {
+                        0
+                    };
+                    let pos = consumed + matched;
+                    if matched < inner.count as usize || pos >= digits.len() {
+                        // `key` diverges from every key below `inner` at the same point, so they
+                        // all compare to it the same way.
+                        let min = with_node!(inner, node, node.get_min(), T)
+                            .expect("inner nodes must be nonempty");
+                        return res + if min.key() < k { inner.leaves() as usize } else { 0 };
+                    }
+                    let d = digits[pos];
+                    let mut next = MarkedPtr::null();
+                    with_node!(
+                        inner,
+                        node,
+                        node.local_foreach(|b, child| if b < d {
+                            res += unsafe { subtree_leaves(&child) };
+                        } else if b == d {
+                            next = child;
+                        }),
+                        T
+                    );
+                    curr = next;
+                }
+            }
+        }
+    }
+
+    /// The element at position `ix` in key order, if there are more than `ix` elements.
+    pub fn select(&self, mut ix: usize) -> Option<&T> {
+        if ix >= self.len {
+            return None;
+        }
+        // N.B. we follow references to the children rather than copies, so that leaves stored
+        // inline are returned from their node.
+        let mut curr: &MarkedPtr<T> = &self.root;
+        loop {
+            match unsafe { curr.get() } {
+                None => return None,
+                Some(Ok(leaf)) => {
+                    debug_assert_eq!(ix, 0);
+                    return Some(leaf);
+                }
+                Some(Err(inner)) => {
+                    debug_assert!(ix < inner.leaves() as usize);
+                    let mut next = None;
+                    with_node!(
+                        inner,
+                        node,
+                        node.local_foreach(|d, child| {
+                            if next.is_some() {
+                                return;
+                            }
+                            let n = unsafe { subtree_leaves(&child) };
+                            if ix < n {
+                                next = Some(d);
+                            } else {
+                                ix -= n;
+                            }
+                        }),
+                        T
+                    );
+                    curr = with_node!(inner, node, node.find(next?).unwrap(), T);
+                }
+            }
+        }
+    }
+
+    /// The number of elements with keys in `[lower_bound, upper_bound)`.
+    pub fn count_range(&self, lower_bound: Option<&T::Key>, upper_bound: Option<&T::Key>) -> usize {
+        let lo = lower_bound.map(|k| self.rank(k)).unwrap_or(0);
+        let hi = upper_bound.map(|k| self.rank(k)).unwrap_or(self.len);
+        hi.saturating_sub(lo)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use super::super::rand;
-    use super::super::rand::Rng;
-    // Use StdRng::rom_seed to debug test failures with deterministic inputs
-    // use super::super::rand::{Rng, SeedableRng, StdRng};
+    use super::super::rand::{Rng, SeedableRng, StdRng};
+    // Use StdRng::from_seed to debug test failures with deterministic inputs
     use std::fmt::{Debug, Error, Formatter};
 
     macro_rules! for_each_set {
@@ -1277,8 +2928,7 @@ mod tests {
             {
                 let mut v1 = random_vec(!0, 1 << 18);
                 {
-                    let mut i = 0;
-                    for item in v1.iter() {
+                    for (i, item) in v1.iter().enumerate() {
                         s.add(*item);
                         assert!(
                             s.contains(item),
@@ -1286,7 +2936,6 @@ mod tests {
                             i,
                             DebugVal(*item)
                         );
-                        i += 1;
                     }
                 }
                 let mut missing = Vec::new();
@@ -1315,8 +2964,7 @@ mod tests {
                 }
                 let mut failures = 0;
                 {
-                    let mut ix = 0;
-                    for i in v2.iter() {
+                    for (ix, i) in v2.iter().enumerate() {
                         let mut fail = 0;
                         if !s.contains(i) {
                             eprintln!("[{}] {:?} no longer in the set!", ix, DebugVal(*i));
@@ -1336,29 +2984,572 @@ mod tests {
                             fail = 1;
                         }
                         failures += fail;
-                        ix += 1;
                     }
                 }
-                assert_eq!(failures, 0);
-                let mut failed = false;
-                for i in v2.iter() {
-                    if s.contains(i) {
-                        eprintln!("Deleted {:?}, but it's still there!", DebugVal(*i));
-                        failed = true;
-                    };
+                assert_eq!(failures, 0);
+                let mut failed = false;
+                for i in v2.iter() {
+                    if s.contains(i) {
+                        eprintln!("Deleted {:?}, but it's still there!", DebugVal(*i));
+                        failed = true;
+                    };
+                }
+                assert!(!failed);
+                for i in v1.iter() {
+                    assert!(
+                        s.contains(i),
+                        "Didn't delete {:?}, but it is gone!",
+                        DebugVal(*i)
+                    );
+                }
+            },
+            CachingARTSet - u64,
+            SwissCachingARTSet - u64,
+            TaggedCachingARTSet - u64,
+            PartialCachingARTSet - u64,
+            ARTSet - u64
+        );
+    }
+
+    #[test]
+    fn long_prefix_cache() {
+        let mut rng = rand::thread_rng();
+        let hosts = ["https://example.com/", "https://example.org/", "file:///usr/share/"];
+        let mut v: Vec<String> = (0..(1 << 12))
+            .map(|_| {
+                let host = hosts[rng.gen_range(0, hosts.len())];
+                let path: String = (0..rng.gen_range(1, 24))
+                    .map(|_| ['a', 'b', '/', '.'][rng.gen_range(0, 4)])
+                    .collect();
+                format!("{}{}", host, path)
+            })
+            .collect();
+        for target in [16, 24, 32].iter() {
+            let mut s = LongCachingARTSet::<String>::with_prefix_buckets(*target);
+            for x in v.iter() {
+                s.add(x.clone());
+                assert!(s.contains(x));
+            }
+            v.sort();
+            v.dedup();
+            assert_eq!(s.len(), v.len());
+            for x in v.iter() {
+                assert!(s.contains(x), "target={} missing {:?}", target, x);
+            }
+            for x in v.iter().step_by(2) {
+                assert!(s.remove(x));
+            }
+            for (i, x) in v.iter().enumerate() {
+                assert_eq!(s.contains(x), i % 2 == 1, "target={} key={:?}", target, x);
+            }
+        }
+    }
+
+    fn test_prefix_levels<T, C>(mut s: RawART<ArtElement<T>, C>, mut v: Vec<T>)
+    where
+        T: for<'a> Digital<'a> + Ord + Clone + Debug,
+        C: PrefixCache<ArtElement<T>>,
+    {
+        for x in v.iter() {
+            s.add(x.clone());
+            assert!(s.contains(x));
+        }
+        v.sort();
+        v.dedup();
+        assert_eq!(s.len(), v.len());
+        for x in v.iter() {
+            assert!(s.contains(x), "levels={:?} missing {:?}", s.prefix_levels(), x);
+        }
+        for x in v.iter().step_by(2) {
+            assert!(s.remove(x), "levels={:?} failed to remove {:?}", s.prefix_levels(), x);
+        }
+        for (i, x) in v.iter().enumerate() {
+            assert_eq!(s.contains(x), i % 2 == 1, "levels={:?} key={:?}", s.prefix_levels(), x);
+        }
+        for x in v.iter().step_by(2) {
+            s.add(x.clone());
+        }
+        for x in v.iter() {
+            assert!(s.contains(x), "levels={:?} missing {:?}", s.prefix_levels(), x);
+        }
+    }
+
+    #[test]
+    fn multi_level_prefix_cache() {
+        // dense keys fill out the shallow levels, sparse ones only the deep ones.
+        let mut ints = random_vec(1 << 20, 1 << 12);
+        ints.extend(random_vec(!0, 1 << 12));
+        let strs = random_string_vec(12, 1 << 12);
+        let s = CachingARTSet::<u64>::with_prefix_levels(&[5, 2, 3]);
+        assert_eq!(s.prefix_levels(), &[2, 3, 5]);
+        test_prefix_levels(s, ints.clone());
+        test_prefix_levels(SwissCachingARTSet::<u64>::with_prefix_levels(&[2, 3, 5]), ints.clone());
+        test_prefix_levels(TaggedCachingARTSet::<u64>::with_prefix_levels(&[2, 3, 5]), ints.clone());
+        test_prefix_levels(
+            PartialCachingARTSet::<u64>::with_prefix_levels_cache(
+                &[2, 3, 5],
+                PartialPrefixCache::with_capacity(64),
+            ),
+            ints.clone(),
+        );
+        test_prefix_levels(CachingARTSet::<String>::with_prefix_levels(&[2, 4, 8]), strs);
+        test_prefix_levels(
+            LongCachingARTSet::<u64>::with_prefix_levels(&[1, 2, 3, 4, 5, 6, 7]),
+            ints,
+        );
+    }
+
+    #[test]
+    fn adaptive_prefix_len() {
+        // Small integers only differ in their last two bytes, so all of the branching happens at
+        // the bottom of the tree.
+        let mut s = CachingARTSet::<u64>::with_adaptive_prefix();
+        assert_eq!(s.prefix_len(), 8);
+        for x in 0..(1 << 16) {
+            s.add(x);
+        }
+        s.tune_prefix_len();
+        assert_eq!(s.prefix_len(), 7);
+        for x in 0..(1 << 16) {
+            assert!(s.contains(&x), "missing {}", x);
+        }
+        assert!(!s.contains(&(1 << 16)));
+
+        // Switching lengths while the tree is modified keeps the cache consistent.
+        let v = random_vec(!0, 1 << 16);
+        test_prefix_levels(CachingARTSet::<u64>::with_adaptive_prefix(), v.clone());
+        test_prefix_levels(PartialCachingARTSet::<u64>::with_adaptive_prefix(), v);
+        let strs = random_string_vec(12, 1 << 14);
+        test_prefix_levels(CachingARTSet::<String>::with_adaptive_prefix(), strs.clone());
+        test_prefix_levels(LongCachingARTSet::<String>::with_adaptive_prefix(), strs);
+    }
+
+    #[test]
+    fn cache_stats() {
+        let mut s = CachingARTSet::<u64>::with_prefix_buckets(6);
+        for x in 0..(1 << 12) {
+            s.add(x);
+        }
+        // Nothing is counted until the tree is asked to.
+        assert!(s.contains(&0));
+        let stats = s.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+        assert_eq!(stats.probe_lengths, [0; PROBE_HISTOGRAM_LEN]);
+        s.enable_cache_stats();
+        for x in 0..(1 << 12) {
+            assert!(s.contains(&x));
+        }
+        let stats = s.cache_stats();
+        assert_eq!(stats.hits, 1 << 12);
+        assert_eq!(stats.misses, 0);
+        assert_eq!(stats.stale_hits, 0);
+        // All of the keys share the same 6-byte prefix.
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.probe_lengths.iter().sum::<usize>(), 1 << 12);
+        assert!(stats.memory > 0);
+
+        assert!(!s.contains(&(1 << 40)));
+        assert_eq!(s.cache_stats().misses, 1);
+        s.reset_cache_stats();
+        let stats = s.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+        assert_eq!(stats.probe_lengths, [0; PROBE_HISTOGRAM_LEN]);
+        assert_eq!(stats.entries, 1);
+        s.disable_cache_stats();
+        assert!(s.contains(&0));
+        assert_eq!(s.cache_stats().hits, 0);
+
+        // With a single key, the cache entry is for its leaf, which an insertion cannot start
+        // from. After that, the cached node's prefix covers the key's 7th byte, so an insertion
+        // that differs there has to split it, which needs its parent: it restarts from the root.
+        let mut s = CachingARTSet::<u64>::with_prefix_buckets(6);
+        s.enable_cache_stats();
+        s.add(0x100);
+        s.add(0x101);
+        assert_eq!(s.cache_stats().stale_hits, 1);
+        s.add(0x200);
+        let stats = s.cache_stats();
+        assert_eq!((stats.hits, stats.stale_hits, stats.restarts), (2, 1, 1));
+        assert!(s.contains(&0x200));
+    }
+
+    #[test]
+    fn membership_filters() {
+        const N: u64 = 1 << 12;
+        let mut s = CachingARTSet::<u64>::new();
+        for x in 0..N {
+            s.add(x * 2);
+        }
+        assert!(s.filter_stats().is_none());
+        s.enable_bloom_filter(N as usize);
+        for x in 0..N {
+            assert!(s.contains(&(x * 2)));
+            assert!(!s.contains(&(x * 2 + 1)));
+        }
+        let stats = s.filter_stats().unwrap();
+        assert_eq!(stats.queries, 2 * N as usize);
+        assert_eq!(stats.negatives + stats.false_positives, N as usize);
+        assert!(stats.false_positive_rate() < 0.05);
+        assert!(stats.memory > 0);
+
+        // The Bloom filter follows insertions and deletions.
+        for x in 0..N / 2 {
+            assert!(s.remove(&(x * 2)));
+            s.add(x * 2 + 1);
+        }
+        for x in 0..N {
+            assert_eq!(s.contains(&(x * 2)), x >= N / 2);
+            assert_eq!(s.contains(&(x * 2 + 1)), x < N / 2);
+        }
+        s.reset_filter_stats();
+        assert_eq!(s.filter_stats().unwrap().queries, 0);
+
+        // Filters survive changing the prefix cache.
+        let mut s = s.convert_cache::<NullBuckets<_>>();
+        s.enable_xor_filter();
+        for x in 0..N {
+            assert_eq!(s.contains(&(x * 2)), x >= N / 2);
+            assert_eq!(s.contains(&(x * 2 + 1)), x < N / 2);
+        }
+        for x in N * 2..N * 4 {
+            assert!(!s.contains(&x));
+        }
+        let stats = s.filter_stats().unwrap();
+        assert_eq!(stats.negatives + stats.false_positives, 3 * N as usize);
+        assert!(stats.false_positive_rate() < 0.02);
+
+        // Deletions leave the xor filter in place; insertions discard it.
+        assert!(s.remove(&1));
+        assert!(!s.contains(&1));
+        assert!(s.filter_stats().is_some());
+        s.add(1);
+        assert!(s.filter_stats().is_none());
+        assert!(s.contains(&1));
+        s.enable_bloom_filter(0);
+        s.disable_filter();
+        assert!(s.filter_stats().is_none());
+    }
+
+    /// Check that every inner node at or below `ptr` has the smallest type that fits its children.
+    unsafe fn assert_compact<T: Element>(ptr: MarkedPtr<T>) {
+        if let Some(Err(inner)) = ptr.get() {
+            let typ = match inner.children {
+                0..=4 => NODE_4,
+                5..=16 => NODE_16,
+                17..=32 => NODE_32,
+                33..=48 => NODE_48,
+                _ => NODE_256,
+            };
+            assert_eq!(inner.typ.kind(), typ, "{} children", inner.children);
+            with_node!(inner, node, node.local_foreach(|_, c| assert_compact(c)), T);
+        }
+    }
+
+    fn test_shrink_to_fit<C: PrefixCache<ArtElement<u64>>>(mut s: RawART<ArtElement<u64>, C>) {
+        // Dense keys fill out nodes all the way to `Node256`.
+        let keys: Vec<u64> = (0..(1 << 16)).collect();
+        for round in 0..3 {
+            for k in keys.iter() {
+                s.add(*k);
+            }
+            // Leave every 100th key, so that most nodes keep only a few children.
+            for k in keys.iter().filter(|k| *k % 100 != round) {
+                assert!(s.remove(k));
+            }
+            let before = s.cache_stats();
+            s.shrink_to_fit();
+            unsafe { assert_compact(s.root.to_marked()) };
+            let after = s.cache_stats();
+            assert_eq!(before.entries, after.entries);
+            assert!(after.memory <= before.memory);
+            assert_eq!(after.tombstones, 0);
+            for k in keys.iter() {
+                assert_eq!(s.contains(k), *k % 100 == round, "round {} key {}", round, k);
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        test_shrink_to_fit(ARTSet::<u64>::new());
+        test_shrink_to_fit(CachingARTSet::<u64>::with_prefix_buckets(7));
+        test_shrink_to_fit(CachingARTSet::<u64>::with_prefix_levels(&[6, 7]));
+        test_shrink_to_fit(SwissCachingARTSet::<u64>::with_prefix_buckets(7));
+        test_shrink_to_fit(PartialCachingARTSet::<u64>::with_prefix_buckets(7));
+        test_shrink_to_fit(TaggedCachingARTSet::<u64>::with_prefix_buckets(7));
+        test_shrink_to_fit(LongCachingARTSet::<u64>::with_prefix_buckets(7));
+    }
+
+    #[test]
+    fn node32() {
+        // The root's children are the keys' first bytes, inserted in random order so that they
+        // land all over a `Node32`'s sorted keys.
+        let mut rng = StdRng::from_seed(&[3][..]);
+        let mut digits: Vec<u64> = (0..256).collect();
+        for n in 17..=33 {
+            rng.shuffle(&mut digits[..]);
+            let keys: Vec<u64> = digits[..n].iter().map(|d| d << 56 | d).collect();
+            let mut s = ARTSet::<u64>::new();
+            let mut m = MultiValueARTMap::<u64, u64>::new();
+            for k in keys.iter() {
+                s.add(*k);
+                m.add(*k >> 56, *k);
+            }
+            let report = s.memory_usage();
+            assert_eq!(report.node32.count, if n <= 32 { 1 } else { 0 });
+            assert_eq!(m.memory_usage().stored_leaves, n);
+            let mut sorted = keys.clone();
+            sorted.sort();
+            let mut visited = Vec::new();
+            s.for_each_range(|k| visited.push(*k), None, None);
+            assert_eq!(visited, sorted);
+            visited.clear();
+            s.for_each_range_rev(|k| visited.push(*k), None, None);
+            visited.reverse();
+            assert_eq!(visited, sorted);
+            for k in keys.iter() {
+                assert!(s.contains(k));
+                assert!(!s.contains(&(k + 1)));
+                assert_eq!(m.get(&(k >> 56)).map(|e| *e.value()), Some(*k));
+            }
+            // Remove keys until the root fits in a `Node16`.
+            for k in keys[16..].iter() {
+                assert!(s.remove(k));
+                assert!(m.remove(&(k >> 56)));
+            }
+            s.shrink_to_fit();
+            m.shrink_to_fit();
+            unsafe { assert_compact(s.root.to_marked()) };
+            unsafe { assert_compact(m.root.to_marked()) };
+            for k in keys.iter().take(16) {
+                assert!(s.contains(k));
+                assert_eq!(m.get(&(k >> 56)).map(|e| *e.value()), Some(*k));
+            }
+        }
+    }
+
+    /// Check that no inner node at or below `ptr` has as few children as `t` shrinks it at.
+    unsafe fn assert_above_thresholds<T: Element>(ptr: MarkedPtr<T>, t: &ShrinkThresholds) {
+        if let Some(Err(inner)) = ptr.get() {
+            assert!(inner.children as usize > t.get(inner.typ), "{:?}", inner.typ);
+            with_node!(inner, node, node.local_foreach(|_, c| assert_above_thresholds(c, t)), T);
+        }
+    }
+
+    #[test]
+    fn shrink_thresholds() {
+        fn run<C: PrefixCache<ArtPair<u64, u64>>, L: LeafLayout>(t: ShrinkThresholds) {
+            let before = live_allocations();
+            {
+                let mut m = RawART::<ArtPair<u64, u64>, C, CountingAllocator, L>::
+                    with_prefix_buckets(7);
+                m.set_shrink_thresholds(t);
+                let mut model = ::std::collections::BTreeMap::new();
+                // Keys with up to 64 children per node at the lowest level. Caching 7-byte
+                // prefixes makes deletions start from those nodes, the parents of the leaves.
+                let v = random_vec(1 << 12, 1 << 14);
+                for x in v.iter() {
+                    assert_eq!(m.replace(*x, !x), model.insert(*x, !x).map(|y| (*x, y)));
+                }
+                for (i, x) in v.iter().enumerate() {
+                    if i % 8 == 0 {
+                        assert_eq!(m.replace(*x, *x), model.insert(*x, *x).map(|y| (*x, y)));
+                    } else {
+                        assert_eq!(m.take(x), model.remove_entry(x));
+                    }
+                }
+                unsafe { assert_above_thresholds(m.root.to_marked(), &t) };
+                for x in v.iter() {
+                    assert_eq!(m.get(x).map(|e| *e.value()), model.get(x).cloned());
                 }
-                assert!(!failed);
-                for i in v1.iter() {
-                    assert!(
-                        s.contains(i),
-                        "Didn't delete {:?}, but it is gone!",
-                        DebugVal(*i)
-                    );
+                for x in v.iter() {
+                    m.remove(x);
                 }
-            },
-            CachingARTSet - u64,
-            ARTSet - u64
+                assert!(m.is_empty());
+            }
+            assert_eq!(live_allocations(), before);
+        }
+        for &t in [ShrinkThresholds::EAGER, ShrinkThresholds::HYSTERESIS].iter() {
+            run::<NullBuckets<_>, SingleValueLeaves>(t);
+            run::<NullBuckets<_>, MultiValueLeaves>(t);
+            run::<HashSetPrefixCache<_>, SingleValueLeaves>(t);
+            run::<TaggedPrefixCache<_>, MultiValueLeaves>(t);
+            run::<PartialPrefixCache<_>, SingleValueLeaves>(t);
+        }
+
+        // A node with 17 children sits at the boundary between `Node16` and `Node32`.
+        let keys: Vec<u64> = (0..17).collect();
+        let node32s = |t: ShrinkThresholds| {
+            let mut s = ARTSet::<u64>::new();
+            s.set_shrink_thresholds(t);
+            for k in keys.iter() {
+                s.add(*k);
+            }
+            s.remove(&16);
+            let res = s.memory_usage().node32.count;
+            s.add(16);
+            res
+        };
+        assert_eq!(node32s(ShrinkThresholds::NEVER), 1);
+        assert_eq!(node32s(ShrinkThresholds::EAGER), 0);
+        assert_eq!(node32s(ShrinkThresholds::HYSTERESIS), 1);
+        assert_eq!(ARTSet::<u64>::new().shrink_thresholds(), ShrinkThresholds::NEVER);
+    }
+
+    #[test]
+    fn tagged_prefix_cache_ignores_stale_entries() {
+        let mut s = TaggedCachingARTSet::<u64>::with_prefix_levels(&[5, 6]);
+        let mut v = random_vec(1 << 32, 1 << 14);
+        for x in v.iter() {
+            s.add(*x);
+        }
+        v.sort();
+        v.dedup();
+        // Modify the tree behind the back of the current cache, freeing and splitting nodes that
+        // it points to, and then put it back.
+        let stale = mem::replace(&mut s.buckets, TaggedPrefixCache::new());
+        let removed = v.split_off(v.len() / 2);
+        for x in removed.iter() {
+            assert!(s.remove(x));
+        }
+        let added = random_vec(1 << 32, 1 << 12);
+        for x in added.iter() {
+            s.add(*x);
+        }
+        s.buckets = stale;
+        assert!(s.prefix_cache().stale_entries() > 0);
+        for x in v.iter().chain(added.iter()) {
+            assert!(s.contains(x), "missing {}", x);
+        }
+        for x in removed.iter().filter(|x| !added.contains(x)) {
+            assert!(!s.contains(x), "{} should be gone", x);
+            s.add(*x);
+        }
+        for x in v.iter().chain(added.iter()).chain(removed.iter()) {
+            s.remove(x);
+        }
+        assert_eq!(s.len(), 0);
+
+        // Only the nodes of trees with this cache have room for generations, so converting to
+        // and from it moves every node.
+        let mut s = ARTSet::<u64>::with_prefix_levels(&[5, 6]);
+        for x in v.iter() {
+            s.add(*x);
+        }
+        let plain = s.memory_usage().nodes();
+        let s = s.convert_cache::<TaggedPrefixCache<_>>();
+        let tagged = s.memory_usage().nodes();
+        assert_eq!(tagged.count, plain.count);
+        assert_eq!(tagged.bytes, plain.bytes + 8 * plain.count);
+        assert!(s.cache_stats().entries > 0);
+        assert_eq!(s.prefix_cache().stale_entries(), 0);
+        for x in v.iter() {
+            assert!(s.contains(x));
+        }
+        let s = s.convert_cache::<NullBuckets<_>>();
+        assert_eq!(s.memory_usage().nodes().bytes, plain.bytes);
+    }
+
+    #[test]
+    fn rebuild_and_convert_cache() {
+        let mut v = random_vec(!0, 1 << 14);
+        v.extend(random_vec(1 << 20, 1 << 12));
+        let mut s = ARTSet::<u64>::new();
+        for x in v.iter() {
+            s.add(*x);
+        }
+        v.sort();
+        v.dedup();
+        let mut s = s.convert_cache::<HashSetPrefixCache<_>>();
+        assert!(s.cache_stats().entries > 0);
+        // Removals from a complete cache check that the entries they touch are present.
+        let removed = v.split_off(v.len() / 2);
+        for x in removed.iter() {
+            assert!(s.remove(x));
+        }
+        for x in v.iter() {
+            assert!(s.contains(x));
+        }
+
+        // Rebuilding a cache from scratch gives the same entries as maintaining it.
+        let entries = s.cache_stats().entries;
+        s.buckets = HashSetPrefixCache::new();
+        s.rebuild_prefix_cache();
+        assert_eq!(s.cache_stats().entries, entries);
+        let s = s.convert_cache::<LongPrefixCache<_>>();
+        assert_eq!(s.cache_stats().entries, entries);
+        let mut s = s.convert_cache::<NullBuckets<_>>();
+        for x in v.iter() {
+            assert!(s.contains(x));
+        }
+        for x in removed.iter() {
+            assert!(!s.contains(x));
+            s.add(*x);
+        }
+        let mut s = s.convert_cache::<PartialPrefixCache<_>>();
+        for x in v.iter() {
+            assert!(s.remove(x));
+        }
+        for x in removed.iter() {
+            assert!(s.contains(x));
+        }
+        assert_eq!(s.len(), removed.len());
+
+        let mut s = CachingARTSet::<u64>::with_prefix_levels(&[2, 5]);
+        for x in v.iter() {
+            s.add(*x);
+        }
+        let entries = s.cache_stats().entries;
+        let mut s = s.convert_cache::<NullBuckets<_>>()
+            .convert_cache::<SwissPrefixCache<_>>();
+        assert_eq!(s.prefix_levels(), &[2, 5]);
+        assert_eq!(s.cache_stats().entries, entries);
+        for x in removed.iter() {
+            s.add(*x);
+        }
+        for x in v.iter() {
+            assert!(s.remove(x));
+        }
+        for x in removed.iter() {
+            assert!(s.contains(x));
+        }
+    }
+
+    #[test]
+    fn partial_prefix_cache() {
+        let mut s = PartialCachingARTSet::<u64>::with_prefix_cache(
+            3,
+            PartialPrefixCache::with_capacity(16),
         );
+        let mut v = random_vec(1 << 40, 1 << 14);
+        for x in v.iter() {
+            s.add(*x);
+        }
+        v.sort();
+        v.dedup();
+        // hammer a few hot keys so their prefixes get admitted, interleaved with colder ones
+        // that churn the cache.
+        for round in 0..64 {
+            for x in v.iter().take(8) {
+                assert!(s.contains(x));
+            }
+            assert!(s.contains(&v[(round * 97) % v.len()]));
+        }
+        assert!(!s.prefix_cache().is_empty());
+        assert!(s.prefix_cache().len() <= 16);
+        // mutations through (possibly stale-looking) cached prefixes must keep the cache valid.
+        let (removed, kept): (Vec<u64>, Vec<u64>) = v.iter().partition(|x| *x % 2 == 0);
+        for x in removed.iter() {
+            assert!(s.remove(x));
+            assert!(!s.contains(x));
+        }
+        for x in kept.iter() {
+            assert!(s.contains(x));
+        }
+        for x in removed.iter() {
+            assert!(!s.contains(x));
+        }
+        assert_eq!(s.len(), kept.len());
     }
 
     #[test]
@@ -1403,10 +3594,492 @@ mod tests {
                 }
             },
             CachingARTSet - String,
+            SwissCachingARTSet - String,
+            TaggedCachingARTSet - String,
+            PartialCachingARTSet - String,
             ARTSet - String
         );
     }
 
+    thread_local! {
+        static LIVE_ALLOCATIONS: ::std::cell::Cell<isize> = const { ::std::cell::Cell::new(0) };
+    }
+
+    fn live_allocations() -> isize {
+        LIVE_ALLOCATIONS.with(|c| c.get())
+    }
+
+    /// Allocates from a slab, keeping track of the number of allocations live on this thread.
+    /// Unlike the slab, it does not let trees skip freeing their nodes when they are dropped.
+    #[derive(Default)]
+    struct CountingAllocator(SlabAllocator);
+
+    unsafe impl NodeAllocator for CountingAllocator {
+        const KEEPS_NODES: bool = true;
+
+        unsafe fn alloc(&mut self, layout: ::std::alloc::Layout) -> *mut u8 {
+            LIVE_ALLOCATIONS.with(|c| c.set(c.get() + 1));
+            self.0.alloc(layout)
+        }
+
+        unsafe fn dealloc(&mut self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+            LIVE_ALLOCATIONS.with(|c| c.set(c.get() - 1));
+            self.0.dealloc(ptr, layout)
+        }
+
+        unsafe fn alloc_node(&mut self, layout: ::std::alloc::Layout) -> *mut u8 {
+            LIVE_ALLOCATIONS.with(|c| c.set(c.get() + 1));
+            self.0.alloc_node(layout)
+        }
+
+        unsafe fn dealloc_node(&mut self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+            LIVE_ALLOCATIONS.with(|c| c.set(c.get() - 1));
+            self.0.dealloc_node(ptr, layout)
+        }
+    }
+
+    fn test_frees_everything<C: PrefixCache<ArtElement<String>>>() {
+        let v = random_string_vec(20, 1 << 12);
+        let before = live_allocations();
+        {
+            let mut s = RawART::<ArtElement<String>, C, CountingAllocator>::new();
+            for x in v.iter() {
+                s.add(x.clone());
+            }
+            for x in v.iter().step_by(2) {
+                s.remove(x);
+            }
+            s.shrink_to_fit();
+            for x in v.iter() {
+                s.remove(x);
+            }
+            assert!(s.is_empty());
+            assert_eq!(live_allocations(), before);
+            for x in v.iter() {
+                s.add(x.clone());
+            }
+            assert!(live_allocations() > before);
+            let s = s.convert_cache::<NullBuckets<_>>();
+            assert!(v.iter().all(|x| s.contains(x)));
+        }
+        assert_eq!(live_allocations(), before);
+    }
+
+    #[test]
+    fn node_allocators() {
+        test_frees_everything::<NullBuckets<_>>();
+        test_frees_everything::<HashSetPrefixCache<_>>();
+        test_frees_everything::<SwissPrefixCache<_>>();
+        test_frees_everything::<TaggedPrefixCache<_>>();
+        let v = random_vec(1 << 20, 1 << 14);
+        let mut s = RawART::<ArtElement<u64>, NullBuckets<_>, Global>::new();
+        let mut slab = ARTSet::<u64>::new();
+        for x in v.iter() {
+            assert_eq!(s.add(*x), slab.add(*x));
+        }
+        for x in v.iter().step_by(3) {
+            assert_eq!(s.remove(x), slab.remove(x));
+        }
+        for x in v.iter() {
+            assert_eq!(s.contains(x), slab.contains(x));
+        }
+        assert_eq!(s.len(), slab.len());
+        // Churn reuses the slab's memory rather than taking more.
+        let reserved = slab.allocator().reserved();
+        for _ in 0..3 {
+            for x in v.iter() {
+                slab.remove(x);
+            }
+            for x in v.iter() {
+                slab.add(*x);
+            }
+        }
+        assert_eq!(slab.allocator().reserved(), reserved);
+    }
+
+    #[test]
+    fn inline_leaves() {
+        // CI runs this under Miri, with fewer elements.
+        let n = if cfg!(miri) { 1 << 8 } else { 1 << 14 };
+        // `u32`s are stored in their parent's child pointers, so the only allocations are for
+        // inner nodes.
+        fn run<C: PrefixCache<ArtElement<u32>>>(n: usize) {
+            let before = live_allocations();
+            {
+                let mut s =
+                    RawART::<ArtElement<u32>, C, CountingAllocator>::with_prefix_buckets(2);
+                let mut model = ::std::collections::BTreeSet::new();
+                let v: Vec<u32> =
+                    random_vec((n as u64) << 10, n).iter().map(|x| *x as u32).collect();
+                for (i, x) in v.iter().enumerate() {
+                    if i % 3 == 2 {
+                        assert_eq!(s.remove(x), model.remove(x));
+                    } else {
+                        let fresh = model.insert(*x);
+                        assert_eq!(s.replace(*x), if fresh { None } else { Some(*x) });
+                    }
+                }
+                assert_eq!(s.len(), model.len());
+                assert!(live_allocations() - before < (model.len() / 4) as isize);
+                for x in v.iter() {
+                    assert_eq!(s.get(x).map(|e| *e.value()), model.get(x).cloned());
+                }
+                let mut range = Vec::new();
+                let (lo, hi) = ((n << 6) as u32, (n << 9) as u32);
+                s.for_each_range(|x| range.push(*x), Some(&lo), Some(&hi));
+                let expected: Vec<u32> = model.range(lo..hi).cloned().collect();
+                assert_eq!(range, expected);
+                for x in v.iter() {
+                    s.remove(x);
+                }
+                assert!(s.is_empty());
+            }
+            assert_eq!(live_allocations(), before);
+        }
+        run::<NullBuckets<_>>(n);
+        run::<HashSetPrefixCache<_>>(n);
+        run::<SwissPrefixCache<_>>(n);
+        run::<TaggedPrefixCache<_>>(n);
+        run::<PartialPrefixCache<_>>(n);
+
+        let mut s = RankedARTSet::<u32>::new();
+        let mut v: Vec<u32> = random_vec(!0, n / 4).iter().map(|x| *x as u32).collect();
+        for x in v.iter() {
+            s.add(*x);
+        }
+        v.sort();
+        v.dedup();
+        for (i, x) in v.iter().enumerate() {
+            assert_eq!(s.select(i).map(|e| e.value()), Some(x));
+            assert_eq!(s.rank(x), i);
+        }
+
+        // Only element types that ask for it are stored inline.
+        assert!(stores_inline::<ArtElement<i32>>());
+        assert!(!stores_inline::<ArtElement<u64>>());
+        assert!(!stores_inline::<ArtPair<u32, ()>>());
+    }
+
+    #[test]
+    fn multi_value_leaves() {
+        // Keys are dense in their low byte, so most elements are stored in the value slots of the
+        // nodes at the lowest level, which grow and shrink as they are inserted and removed.
+        fn run<C: PrefixCache<ArtPair<u64, u64>>>() {
+            let before = live_allocations();
+            {
+                let mut m = RawART::<ArtPair<u64, u64>, C, CountingAllocator, MultiValueLeaves>::
+                    with_prefix_buckets(2);
+                let mut model = ::std::collections::BTreeMap::new();
+                let mut v = random_vec(1 << 16, 1 << 14);
+                v.extend(random_vec(!0, 1 << 10));
+                for (i, x) in v.iter().enumerate() {
+                    if i % 3 == 2 {
+                        assert_eq!(m.take(x), model.remove_entry(x));
+                    } else {
+                        let old = model.insert(*x, i as u64).map(|y| (*x, y));
+                        assert_eq!(m.replace(*x, i as u64), old);
+                    }
+                }
+                assert_eq!(m.len(), model.len());
+                for x in v.iter() {
+                    assert_eq!(m.get(x).map(|e| *e.value()), model.get(x).cloned());
+                }
+                for x in v.iter().step_by(5) {
+                    if let Some(e) = m.get_mut(x) {
+                        *e += 1;
+                        *model.get_mut(x).unwrap() += 1;
+                    }
+                }
+                let mut range = Vec::new();
+                m.for_each_range(|k, v| range.push((*k, *v)), Some(&(1 << 12)), Some(&(1 << 15)));
+                let expected: Vec<_> =
+                    model.range((1 << 12)..(1 << 15)).map(|(k, v)| (*k, *v)).collect();
+                assert_eq!(range, expected);
+                // Remove most elements, so that nodes can shrink.
+                for x in v.iter().skip(v.len() / 8) {
+                    assert_eq!(m.take(x), model.remove_entry(x));
+                }
+                m.shrink_to_fit();
+                for x in v.iter() {
+                    assert_eq!(m.get(x).map(|e| *e.value()), model.get(x).cloned());
+                }
+                for x in v.iter() {
+                    m.remove(x);
+                }
+                assert!(m.is_empty());
+            }
+            assert_eq!(live_allocations(), before);
+        }
+        run::<NullBuckets<_>>();
+        run::<HashSetPrefixCache<_>>();
+        run::<SwissPrefixCache<_>>();
+        run::<TaggedPrefixCache<_>>();
+        run::<PartialPrefixCache<_>>();
+
+        // Only the nodes are allocated.
+        let v = random_vec(1 << 16, 1 << 14);
+        let before = live_allocations();
+        let mut m = MultiValueARTMap::<u64, u64, CountingAllocator>::default();
+        for x in v.iter() {
+            m.add(*x, *x);
+        }
+        assert!(((live_allocations() - before) as usize) < m.len() / 16);
+
+        // Order statistics and converting the cache.
+        let mut ranked = RawART::<
+            ArtElement<u64>,
+            NullBuckets<_>,
+            SlabAllocator,
+            MultiValueLeaves,
+            PREFIX_LEN,
+            LeafCounts,
+        >::new();
+        let mut s = MultiValueARTSet::<u64>::new();
+        for x in v.iter() {
+            ranked.add(*x);
+            s.add(*x);
+        }
+        let mut v = v;
+        v.sort();
+        v.dedup();
+        for (i, x) in v.iter().enumerate() {
+            assert_eq!(ranked.select(i).map(|e| e.value()), Some(x));
+            assert_eq!(ranked.rank(x), i);
+        }
+        let s: RawART<_, HashSetPrefixCache<_>, _, MultiValueLeaves> = s.convert_cache();
+        for x in v.iter() {
+            assert!(s.contains(x));
+            assert!(!s.contains(&(x + (1 << 16))));
+        }
+    }
+
+    #[test]
+    fn memory_usage() {
+        fn check<T: Element, C: PrefixCache<T>, L: LeafLayout>(
+            m: &RawART<T, C, CountingAllocator, L>,
+            before: isize,
+        ) -> MemoryReport {
+            let report = m.memory_usage();
+            assert_eq!(report.len, m.len());
+            let leaves = report.leaves.count + report.inline_leaves + report.stored_leaves;
+            assert_eq!(leaves, m.len());
+            // Every node and leaf but the root hangs off of a node.
+            assert_eq!(report.children + 1, report.nodes().count + leaves);
+            let allocations = report.nodes().count + report.leaves.count;
+            assert_eq!(allocations as isize, live_allocations() - before);
+            assert_eq!(report.leaves.bytes, report.leaves.count * mem::size_of::<T>());
+            assert!(report.bytes_per_key() > 0.0);
+            report
+        }
+        let v = random_vec(1 << 16, 1 << 12);
+        let before = live_allocations();
+        let mut m = ARTMap::<u64, u64, CountingAllocator>::default();
+        for x in v.iter() {
+            m.add(*x, *x);
+        }
+        let report = check(&m, before);
+        let node4 = PREFIX_LEN + mem::size_of::<RawNode<Node4<ArtPair<u64, u64>>>>();
+        assert_eq!(report.node4.bytes, report.node4.count * node4);
+        assert!(report.average_fan_out() > 4.0);
+        assert_eq!(report.implicit_prefixes, 0);
+        assert_eq!(report.prefix_cache, AllocationStats::default());
+        mem::drop(m);
+
+        let mut m = MultiValueARTMap::<u64, u64, CountingAllocator>::default();
+        for x in v.iter() {
+            m.add(*x, *x);
+        }
+        let multi = check(&m, before);
+        assert!(multi.stored_leaves > 0);
+        assert!(multi.nodes().bytes > report.nodes().bytes);
+        mem::drop(m);
+
+        type Tagged =
+            RawART<ArtElement<u32>, TaggedPrefixCache<ArtElement<u32>>, CountingAllocator>;
+        let mut s = Tagged::with_prefix_buckets(2);
+        for x in v.iter() {
+            s.add(*x as u32);
+        }
+        s.enable_bloom_filter(v.len());
+        let report = check(&s, before);
+        assert_eq!(report.inline_leaves, s.len());
+        assert!(report.prefix_cache.count > 0);
+        assert_eq!(report.prefix_cache.bytes, s.cache_stats().memory);
+        assert!(report.filter > 0);
+        mem::drop(s);
+
+        // Keys that share more than `PREFIX_LEN` bytes.
+        let mut s = RawART::<ArtElement<String>, NullBuckets<_>, CountingAllocator>::default();
+        for i in 0..100 {
+            s.add(format!("a long common prefix {}", i));
+        }
+        let report = check(&s, before);
+        assert!(report.implicit_prefixes > 0);
+    }
+
+    #[test]
+    fn long_prefixes() {
+        // Keys made of a few segments of different lengths, so that nodes' prefixes range from a
+        // few bytes to several times `PREFIX_LEN`. Removing keys merges nodes with their children
+        // and shortens the prefixes of others.
+        fn random_path(rng: &mut StdRng) -> String {
+            const SEGMENTS: [&str; 6] = [
+                "a/",
+                "bcdefgh/",
+                "ijklmnopqrstu/",
+                "vwxyz0123456789abcdefg/",
+                "the quick brown fox jumps over the lazy dog/",
+                "",
+            ];
+            let mut res = String::new();
+            for _ in 0..rng.gen_range(1, 4) {
+                res.push_str(rng.choose(&SEGMENTS).unwrap());
+            }
+            res.push_str(&rng.gen_range::<u32>(0, 1 << 8).to_string());
+            res
+        }
+        fn run<C: PrefixCache<ArtElement<String>>, const P: usize>() -> MemoryReport {
+            let before = live_allocations();
+            let report;
+            {
+                type Set<C, const P: usize> =
+                    RawART<ArtElement<String>, C, CountingAllocator, SingleValueLeaves, P>;
+                let mut s = Set::<C, P>::with_prefix_buckets(4);
+                let mut model = ::std::collections::BTreeSet::new();
+                // Every run uses the same keys, so that their memory use can be compared.
+                let mut rng = StdRng::from_seed(&[4][..]);
+                let v: Vec<String> = (0..1 << 14).map(|_| random_path(&mut rng)).collect();
+                for (i, x) in v.iter().enumerate() {
+                    if i % 4 == 3 {
+                        assert_eq!(s.remove(x), model.remove(x));
+                    } else {
+                        assert_eq!(s.add(x.clone()), !model.insert(x.clone()));
+                    }
+                }
+                assert_eq!(s.len(), model.len());
+                for x in v.iter() {
+                    assert_eq!(s.contains(x), model.contains(x));
+                }
+                let mut range = Vec::new();
+                let (lo, hi) = ("bcdefgh/".to_string(), "the".to_string());
+                s.for_each_range(|x| range.push(x.clone()), Some(&lo), Some(&hi));
+                let expected: Vec<_> = model.range(lo..hi).cloned().collect();
+                assert_eq!(range, expected);
+                report = s.memory_usage();
+                assert_eq!(report.len, s.len());
+                assert_eq!(
+                    (report.nodes().count + report.leaves.count) as isize,
+                    live_allocations() - before
+                );
+                // Remove most keys, so that nodes shrink and merge.
+                for x in v.iter().skip(v.len() / 16) {
+                    assert_eq!(s.remove(x), model.remove(x));
+                }
+                s.shrink_to_fit();
+                for x in v.iter() {
+                    assert_eq!(s.contains(x), model.contains(x));
+                }
+                for x in v.iter() {
+                    s.remove(x);
+                }
+                assert!(s.is_empty());
+            }
+            assert_eq!(live_allocations(), before);
+            report
+        }
+        let short = run::<NullBuckets<_>, PREFIX_LEN>();
+        let medium = run::<NullBuckets<_>, 16>();
+        let long = run::<NullBuckets<_>, 24>();
+        assert!(medium.implicit_prefixes < short.implicit_prefixes);
+        assert!(long.implicit_prefixes < medium.implicit_prefixes);
+        run::<HashSetPrefixCache<_>, 16>();
+        run::<TaggedPrefixCache<_>, 24>();
+        run::<SwissPrefixCache<_>, MAX_PREFIX_CAPACITY>();
+
+        // Value slots follow the node when it stores a longer prefix.
+        type Map = ArtPair<u64, u64>;
+        let mut m = RawART::<Map, NullBuckets<_>, CountingAllocator, MultiValueLeaves, 16>::new();
+        let mut model = ::std::collections::BTreeMap::new();
+        let mut rng = StdRng::from_seed(&[5][..]);
+        let v: Vec<u64> = (0..1 << 14).map(|_| rng.gen_range(0, 1 << 40)).collect();
+        for x in v.into_iter().chain(0..1 << 10) {
+            assert_eq!(m.replace(x, !x), model.insert(x, !x).map(|y| (x, y)));
+        }
+        for (k, v) in model.iter() {
+            assert_eq!(m.get(k).map(|e| *e.value()), Some(*v));
+        }
+        for k in model.keys().step_by(2) {
+            assert!(m.remove(k));
+        }
+        for (i, k) in model.keys().enumerate() {
+            assert_eq!(m.contains(k), i % 2 == 1);
+        }
+    }
+
+    /// A bump allocator that frees nothing until it is dropped.
+    #[derive(Default)]
+    struct Arena {
+        chunks: Vec<Vec<u64>>,
+        used: usize,
+    }
+
+    unsafe impl NodeAllocator for Arena {
+        unsafe fn alloc(&mut self, layout: ::std::alloc::Layout) -> *mut u8 {
+            assert!(layout.align() <= mem::align_of::<u64>());
+            let words = layout.size().div_ceil(8);
+            if self.chunks.last().is_none_or(|c| c.len() - self.used < words) {
+                self.chunks.push(vec![0; cmp::max(words, 1 << 12)]);
+                self.used = 0;
+            }
+            let res = self.chunks.last_mut().unwrap()[self.used..].as_mut_ptr();
+            self.used += words;
+            res as *mut u8
+        }
+
+        unsafe fn dealloc(&mut self, _ptr: *mut u8, _layout: ::std::alloc::Layout) {}
+    }
+
+    #[test]
+    fn custom_allocators() {
+        let v = random_vec(1 << 20, 1 << 14);
+        let mut arena = Arena::default();
+        for _ in 0..2 {
+            // Both trees allocate from the same arena.
+            let mut s1 = ARTSet::<u64, _>::new_in(&mut arena);
+            for x in v.iter() {
+                s1.add(*x);
+            }
+            assert!(v.iter().all(|x| s1.contains(x)));
+        }
+        assert!(arena.chunks.len() > 1);
+
+        let mut s2 = CachingARTSet::<String, _>::new_in(FromGlobalAlloc(::std::alloc::System));
+        let strs = random_string_vec(10, 1 << 10);
+        for x in strs.iter() {
+            s2.add(x.clone());
+        }
+        let removed = strs.iter().step_by(2).collect::<::std::collections::HashSet<_>>();
+        for x in removed.iter() {
+            s2.remove(*x);
+        }
+        for x in strs.iter() {
+            assert_eq!(s2.contains(x), !removed.contains(x));
+        }
+
+        // A slab with a parent allocator returns its chunks to the parent.
+        let before = live_allocations();
+        {
+            let mut counting = CountingAllocator::default();
+            let slab = SlabAllocator::new_in(&mut counting);
+            let mut s3 = CachingARTSet::<String, _>::new_in(slab);
+            for x in strs.iter() {
+                s3.add(x.clone());
+            }
+            assert!(live_allocations() > before);
+        }
+        assert_eq!(live_allocations(), before);
+    }
+
     #[test]
     fn string_set_behavior() {
         for_each_set!(
@@ -1478,6 +4151,51 @@ mod tests {
                 }
             },
             CachingARTSet - String,
+            SwissCachingARTSet - String,
+            TaggedCachingARTSet - String,
+            PartialCachingARTSet - String,
+            ARTSet - String
+        );
+    }
+
+    #[test]
+    fn long_node_prefixes() {
+        // Every key shares more than PREFIX_LEN bytes with the others, so splitting and promoting
+        // nodes has to recover prefix bytes that nodes do not store.
+        let base = "https://example.com/a/very/long/shared/path/";
+        let mut keys = vec![base.to_string()];
+        for split in (PREFIX_LEN + 1)..base.len() {
+            for c in ['X', 'Z'].iter() {
+                let mut k = String::from(&base[..split]);
+                k.push(*c);
+                k.push_str(&base[split + 1..]);
+                keys.push(k);
+            }
+        }
+        for_each_set!(
+            s,
+            {
+                for k in keys.iter().rev() {
+                    s.add(k.clone());
+                }
+                for k in keys.iter() {
+                    assert!(s.contains(k), "missing {:?}", k);
+                }
+                for k in keys.iter().step_by(2) {
+                    assert!(s.remove(k), "failed to remove {:?}", k);
+                }
+                for (i, k) in keys.iter().enumerate() {
+                    assert_eq!(s.contains(k), i % 2 == 1, "key={:?}", k);
+                }
+                for k in keys.iter().step_by(2) {
+                    s.add(k.clone());
+                }
+                for k in keys.iter() {
+                    assert!(s.contains(k), "missing {:?} after reinserting", k);
+                }
+                assert_eq!(s.len(), keys.len());
+            },
+            CachingARTSet - String,
             ARTSet - String
         );
     }
@@ -1487,8 +4205,7 @@ mod tests {
             return;
         }
         eprintln!("v1.len()={:?} v2.len()={:?}", v1.len(), v2.len());
-        let mut ix = 0;
-        for (i, j) in v1.iter().zip(v2.iter()) {
+        for (ix, (i, j)) in v1.iter().zip(v2.iter()).enumerate() {
             if *i != *j {
                 eprintln!(
                     "[{:4?}] {:20?} != {:20?}",
@@ -1497,9 +4214,8 @@ mod tests {
                     DebugVal(j.clone())
                 );
             }
-            ix += 1;
         }
-        assert!(false, "See error logs");
+        panic!("See error logs");
     }
 
     #[test]
@@ -1540,7 +4256,161 @@ mod tests {
         assert_lists_equal(&v1[..q3], &elts[..]);
         elts.clear();
         s.for_each_range_rev(|x| elts.push(*x), Some(&v1[q1]), Some(&v1[q3]));
-        let vs = (&v1[q1..q3]).iter().rev().map(|x| *x).collect::<Vec<_>>();
+        let vs = v1[q1..q3].iter().rev().copied().collect::<Vec<_>>();
         assert_lists_equal(&vs[..], &elts[..]);
     }
+
+    #[test]
+    fn order_statistics() {
+        fn check<T: Clone + Ord + Debug + for<'a> Digital<'a>>(s: &RankedARTSet<T>, sorted: &[T]) {
+            assert_eq!(s.len(), sorted.len());
+            for (i, x) in sorted.iter().enumerate() {
+                assert_eq!(s.rank(x), i, "rank of {:?}", x);
+                assert_eq!(s.select(i).map(|e| e.value()), Some(x));
+            }
+            assert!(s.select(sorted.len()).is_none());
+            if sorted.len() >= 4 {
+                let (q1, q3) = (sorted.len() / 4, 3 * sorted.len() / 4);
+                assert_eq!(s.count_range(Some(&sorted[q1]), Some(&sorted[q3])), q3 - q1);
+                assert_eq!(s.count_range(Some(&sorted[q3]), Some(&sorted[q1])), 0);
+                assert_eq!(s.count_range(None, Some(&sorted[q3])), q3);
+                assert_eq!(s.count_range(Some(&sorted[q1]), None), sorted.len() - q1);
+            }
+        }
+
+        let mut s = RankedARTSet::<u64>::new();
+        let mut v = random_vec(!0, 1 << 12);
+        for x in v.iter() {
+            s.add(*x);
+        }
+        // keys missing from the set are ranked by the elements below them
+        let probe = random_vec(!0, 1 << 8);
+        v.sort();
+        v.dedup();
+        for p in probe.iter() {
+            let expected = v.iter().filter(|x| *x < p).count();
+            assert_eq!(s.rank(p), expected);
+        }
+        check(&s, &v[..]);
+        // removals collapse nodes, which must leave the counts of their ancestors intact
+        let removed: Vec<u64> = v.iter().cloned().step_by(3).collect();
+        for x in removed.iter() {
+            assert!(s.remove(x));
+        }
+        v.retain(|x| removed.binary_search(x).is_err());
+        check(&s, &v[..]);
+
+        let mut ss = RankedARTSet::<String>::new();
+        let mut sv = random_string_vec(10, 1 << 10);
+        for x in sv.iter() {
+            ss.add(x.clone());
+        }
+        sv.sort();
+        sv.dedup();
+        check(&ss, &sv[..]);
+
+        // Only trees that ask for the counts store them.
+        let (mut ranked, mut plain) = (RankedARTSet::<u64>::new(), ARTSet::<u64>::new());
+        for x in v.iter() {
+            ranked.add(*x);
+            plain.add(*x);
+        }
+        let (ranked, plain) = (ranked.memory_usage().nodes(), plain.memory_usage().nodes());
+        assert_eq!(ranked.count, plain.count);
+        assert_eq!(ranked.bytes, plain.bytes + 8 * plain.count);
+    }
+
+    #[test]
+    fn user_defined_element() {
+        struct User {
+            id: u64,
+            name: String,
+        }
+        impl Element for User {
+            type Key = u64;
+            type Value = String;
+            fn key(&self) -> &u64 {
+                &self.id
+            }
+            fn value_mut(&mut self) -> &mut String {
+                &mut self.name
+            }
+            fn matches(&self, k: &u64) -> bool {
+                self.id == *k
+            }
+            fn replace_matching(&mut self, other: &mut User) {
+                debug_assert!(self.matches(other.key()));
+                mem::swap(self, other);
+            }
+        }
+
+        let mut s = RawART::<User, NullBuckets<User>>::new();
+        let ids = random_vec(!0, 1 << 12);
+        for id in ids.iter() {
+            s.insert(User {
+                id: *id,
+                name: format!("user-{}", id),
+            });
+        }
+        for id in ids.iter() {
+            assert!(s.contains(id));
+            assert_eq!(s.get(id).map(|u| &u.name[..]), Some(&format!("user-{}", id)[..]));
+        }
+        let replaced = s.insert(User {
+            id: ids[0],
+            name: String::from("replacement"),
+        });
+        assert_eq!(replaced.map(|u| u.name), Some(format!("user-{}", ids[0])));
+        assert_eq!(s.get(&ids[0]).map(|u| &u.name[..]), Some("replacement"));
+        s.get_mut(&ids[0]).expect("user should be present").push_str(" (renamed)");
+        assert_eq!(s.get(&ids[0]).map(|u| &u.name[..]), Some("replacement (renamed)"));
+        let removed = s.remove_element(&ids[0]).expect("user should be present");
+        assert_eq!(removed.id, ids[0]);
+        assert!(!s.contains(&ids[0]));
+        assert!(!s.remove(&ids[0]));
+    }
+
+    // These exercise the safe API with small inputs so that they remain practical to run under
+    // Miri. Nodes find their prefixes through exposed provenance, so run them with
+    // `MIRIFLAGS=-Zmiri-permissive-provenance cargo +nightly miri test --lib safe_api`, as CI does.
+    #[test]
+    fn safe_api_map() {
+        fn run<C: PrefixCache<ArtPair<u64, u64>>>() {
+            let mut m = RawART::<ArtPair<u64, u64>, C>::with_prefix_buckets(3);
+            let keys = random_vec(!0, 1 << 7);
+            for k in keys.iter() {
+                m.insert(ArtPair::new(*k, 0));
+            }
+            for k in keys.iter() {
+                *m.get_mut(k).expect("key should be present") += *k % 7;
+            }
+            for k in keys.iter() {
+                assert_eq!(m.get(k).map(|p| *p.value()), Some(*k % 7));
+            }
+            for k in keys.iter() {
+                m.remove(k);
+                assert!(m.get(k).is_none());
+                assert!(m.get_mut(k).is_none());
+            }
+            assert_eq!(m.len(), 0);
+        }
+        run::<NullBuckets<_>>();
+        run::<HashSetPrefixCache<_>>();
+        run::<SwissPrefixCache<_>>();
+        run::<TaggedPrefixCache<_>>();
+    }
+
+    #[test]
+    fn safe_api_references_outlive_lookups() {
+        let mut s = ARTSet::<String>::new();
+        for i in 0..64 {
+            s.insert(ArtElement::new(format!("{}", i * 31)));
+        }
+        // the returned references borrow from the set, not from the (temporary) keys
+        let a = s.get(&String::from("31")).expect("should be present");
+        let b = s.get(&String::from("62")).expect("should be present");
+        assert_eq!(a.value(), "31");
+        assert_eq!(b.value(), "62");
+        assert!(s.get(&String::from("32")).is_none());
+    }
 }
@@ -1,18 +1,20 @@
+use std::alloc::Layout;
+use std::cell::UnsafeCell;
 use std::cmp;
 use std::marker::PhantomData;
-use std::mem;
+use std::mem::{self, MaybeUninit};
 use std::ptr;
+use std::slice;
 use super::common::Digital;
 
-extern crate simd;
-
-#[cfg(target_arch = "x86")]
-use std::arch::x86::_mm_movemask_epi8;
-#[cfg(target_arch = "x86_64")]
-use std::arch::x86_64::_mm_movemask_epi8;
+use super::byte_match::{match_byte, match_byte32, match_greater, match_greater32};
+use super::node_alloc::NodeAllocator;
 use super::smallvec::{Array, SmallVec};
 
+/// The number of prefix bytes stored in a node by default. Trees can store more (see `RawART`).
 pub const PREFIX_LEN: usize = 8;
+/// The longest prefix a node can store.
+pub const MAX_PREFIX_CAPACITY: usize = PREFIX_LEN + 8 * 7;
 /// used by the `with_node_mut` macro
 pub type RawMutRef<'a, T> = &'a mut RawNode<T>;
 
@@ -20,30 +22,173 @@ pub type RawMutRef<'a, T> = &'a mut RawNode<T>;
 pub type RawRef<'a, T> = &'a RawNode<T>;
 
 /// a non-owning reference to a `ChildPtr<T>`
-pub struct MarkedPtr<T>(usize, PhantomData<T>);
-pub use self::node_variants::{NODE_16, NODE_256, NODE_4, NODE_48, Node16, Node256, Node48,
-                              NodeType};
+///
+/// The low bits of the word say what it points to: `00` for an inner node, `01` for a leaf and
+/// `11` for a leaf whose element is stored in the word itself (see `Element::INLINE`). Those
+/// elements live in the `ChildPtr` in their parent node, and they are copied along with it, so
+/// references to them must be taken from the node and not from a copy. The rest of the word may
+/// hold padding bytes of the element, so only the byte with the tag bits is ever read as an
+/// integer.
+///
+/// `10` points to a leaf stored in one of the value slots of its parent (see
+/// `NodeType::stores_values`). It moves when the parent is replaced, so copies of these pointers
+/// (in a prefix cache, say) must not be followed.
+pub struct MarkedPtr<T>(UnsafeCell<MaybeUninit<usize>>, PhantomData<T>);
+pub use self::node_variants::{set_has_generation, shrink_node, NODE_16, NODE_256, NODE_32, NODE_4,
+                              NODE_48, Node16, Node256, Node32, Node4, Node48, NodeType};
 
 impl<T> PartialEq for MarkedPtr<T> {
     fn eq(&self, other: &MarkedPtr<T>) -> bool {
-        self.0 == other.0
+        if self.is_inline() || other.is_inline() {
+            // The element's bytes may not all be initialized, so these are compared by where
+            // they are stored.
+            return ptr::eq(self, other);
+        }
+        self.word() == other.word()
     }
 }
 
 impl<T> Eq for MarkedPtr<T> {}
 
+// The word is only written through the pointers that the unsafe `get_raw` and `get_mut` hand
+// out, whose callers must not share the tree while they use them, as for any other leaf.
+unsafe impl<T: Sync> Sync for MarkedPtr<T> {}
+
+const LEAF_TAG: usize = 0b01;
+const STORED_TAG: usize = 0b10;
+const INLINE_TAG: usize = 0b11;
+const TAG_MASK: usize = 0b11;
+
+/// Where an element is stored in the word of a `MarkedPtr` when it is stored inline.
+///
+/// An element that asks to be stored inline (see `Element::INLINE`) is if it fits in a `usize`
+/// next to the byte holding the tag bits, with its alignment. On 64-bit targets that includes
+/// `u32`, `u16` and pairs of them, but not `u64`.
+struct Inline<T>(PhantomData<T>);
+
+/// The byte of a `MarkedPtr`'s word that holds the tag bits: the first one on little-endian
+/// targets and the last one on big-endian targets.
+const TAG_BYTE: usize = if cfg!(target_endian = "big") {
+    mem::size_of::<usize>() - 1
+} else {
+    0
+};
+
+impl<T> Inline<T> {
+    const OFFSET: usize = if cfg!(target_endian = "big") {
+        0
+    } else if mem::align_of::<T>() > 1 {
+        mem::align_of::<T>()
+    } else {
+        1
+    };
+    const FITS: bool = mem::align_of::<T>() <= mem::align_of::<usize>()
+        && Self::OFFSET + mem::size_of::<T>() + if cfg!(target_endian = "big") { 1 } else { 0 }
+            <= mem::size_of::<usize>();
+}
+
+/// Whether leaves holding a `T` are stored inline in their parent's child pointers.
+pub const fn stores_inline<T: Element>() -> bool {
+    T::INLINE && Inline::<T>::FITS
+}
+
+/// How the leaves of a `RawART` are laid out.
+pub trait LeafLayout {
+    /// If true, nodes at the lowest level of the tree store their children's elements in an
+    /// array of value slots next to the node, rather than in separately allocated leaves.
+    const MULTI_VALUE: bool;
+}
+
+/// Every element that does not fit in a child pointer is stored in a leaf of its own.
+pub struct SingleValueLeaves;
+
+impl LeafLayout for SingleValueLeaves {
+    const MULTI_VALUE: bool = false;
+}
+
+/// The elements whose keys end at the lowest level of the tree are stored next to their parent
+/// node, as with the multi-value leaves of the ART paper. This saves allocating a leaf for each
+/// of them, at the cost of larger nodes whose values are copied when they grow or shrink.
+///
+/// This only applies to nodes whose children are the last digits of their keys. With
+/// fixed-length keys, like integers, that holds for every node at the lowest level; with strings
+/// (whose last digit is the null terminator) it never does. Elements that fit in a child pointer
+/// are still stored there.
+pub struct MultiValueLeaves;
+
+impl LeafLayout for MultiValueLeaves {
+    const MULTI_VALUE: bool = true;
+}
+
+/// The optional state that the inner nodes of a `RawART` keep about their subtrees, in words
+/// stored just before them (see `RawNode::leaves`). Each word adds 8 bytes to every inner node,
+/// so trees only keep the ones they ask for.
+pub trait NodeAnnotations {
+    /// If true, each node counts the leaves below it. `RawART::rank`, `RawART::select` and
+    /// `RawART::count_range` need the counts, and every insertion and deletion updates the counts
+    /// of the nodes on its path.
+    const LEAF_COUNTS: bool;
+    /// If true, each node has a word where `AggregatingARTMap` keeps the index of its aggregate
+    /// (see `RawNode::agg_slot`).
+    const AGG_SLOTS: bool = false;
+}
+
+/// Nodes keep nothing beyond what lookups need.
+pub struct NoAnnotations;
+
+impl NodeAnnotations for NoAnnotations {
+    const LEAF_COUNTS: bool = false;
+}
+
+/// Nodes count the leaves below them, which gives the tree order statistics (see `RawART::rank`).
+pub struct LeafCounts;
+
+impl NodeAnnotations for LeafCounts {
+    const LEAF_COUNTS: bool = true;
+}
+
+/// `Element` describes the values stored in the leaves of a `RawART`.
+///
+/// Every element carries its own key, which the tree decomposes into digits to find the
+/// element's position. This lets a record be stored directly in the tree when its key is one of
+/// its fields (e.g. a user keyed by its id), without keeping a second copy of the key around.
+/// `ArtElement` and `ArtPair` are the implementations backing `ARTSet` and `ARTMap`.
+///
+/// Implementations must uphold a few invariants for the tree to behave correctly:
+///
+/// * `key` must return the same value for as long as the element is stored in a tree.
+/// * `matches(k)` must return true if and only if `k` is equal to `self.key()`.
+/// * `replace_matching` is only called with an element that `matches` `self.key()`. After the
+///   call, `self` should hold the new contents and `other` the old ones (usually this is just a
+///   `mem::swap`).
+/// * `value_mut` must not give access to anything that `key` depends on. It is how
+///   `RawART::get_mut` modifies elements in place, and the tree is not told about the change.
 pub trait Element {
     type Key: for<'a> Digital<'a> + PartialOrd;
+    /// The part of the element other than its key.
+    type Value: ?Sized;
+    /// If true, elements that fit in a child pointer next to its tag bits (on 64-bit targets,
+    /// those of at most 4 bytes) are stored there rather than in leaves of their own. That saves
+    /// an allocation per element, but the elements move whenever their parent node does, and
+    /// they are left out of the prefix cache. It is meant for small types without padding, like
+    /// the integers of an `ARTSet<u32>`.
+    const INLINE: bool = false;
     fn key(&self) -> &Self::Key;
+    fn value_mut(&mut self) -> &mut Self::Value;
     fn matches(&self, k: &Self::Key) -> bool;
     fn replace_matching(&mut self, other: &mut Self);
 }
 
 impl<T> Clone for MarkedPtr<T> {
     fn clone(&self) -> Self {
-        MarkedPtr(self.0, PhantomData)
+        MarkedPtr(UnsafeCell::new(unsafe { ptr::read(self.0.get()) }), PhantomData)
     }
 }
+/// An owning pointer to a leaf or an inner node.
+///
+/// `ChildPtr`s do not free what they point to when they are dropped: the memory belongs to the
+/// tree's `NodeAllocator`, so it is freed explicitly with `take_leaf`, `free_node` or
+/// `free_tree`.
 pub struct ChildPtr<T>(MarkedPtr<T>);
 
 impl<T> ::std::ops::Deref for ChildPtr<T> {
@@ -59,21 +204,6 @@ impl<T> ::std::ops::DerefMut for ChildPtr<T> {
     }
 }
 
-impl<T> Drop for ChildPtr<T> {
-    fn drop(&mut self) {
-        unsafe {
-            match self.get_mut() {
-                None => return,
-                Some(Ok(x)) => mem::drop(Box::from_raw(x)),
-                // with_node_mut! will "un-erase" the actual type of the RawNode. We want to call drop
-                // on that to ensure all children are dropped
-                // ... and to avoid undefined behavior, as we could wind up passing free the wrong size :)
-                Some(Err(x)) => with_node_mut!(x, nod, mem::drop(Box::from_raw(nod)), T),
-            }
-        }
-    }
-}
-
 impl<T> ::std::fmt::Debug for MarkedPtr<T> {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
         unsafe {
@@ -92,7 +222,11 @@ impl<T> ::std::fmt::Debug for MarkedPtr<T> {
 
 impl<T> ::std::fmt::Debug for ChildPtr<T> {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        write!(f, "ChildPtr({:?})", (self.0).0 as *mut ())
+        if self.is_inline() {
+            write!(f, "ChildPtr(inline)")
+        } else {
+            write!(f, "ChildPtr({:?})", self.word() as *mut ())
+        }
     }
 }
 
@@ -109,6 +243,14 @@ impl<T> ChildPtr<T> {
         ChildPtr(MarkedPtr::from_leaf(p))
     }
 
+    /// A leaf holding `t` inline. `T` must fit (see `Inline`).
+    unsafe fn inline(t: T) -> Self {
+        debug_assert!(Inline::<T>::FITS);
+        let res = ChildPtr(MarkedPtr::from_word(INLINE_TAG));
+        ptr::write(res.inline_ptr(), t);
+        res
+    }
+
     pub fn swap_null(&mut self) -> Self {
         let mut self_ptr = ChildPtr::null();
         mem::swap(self, &mut self_ptr);
@@ -120,63 +262,204 @@ impl<T> ChildPtr<T> {
     }
 }
 
+/// The layout of a leaf holding a `T`. Leaf pointers are tagged in their low two bits, so leaves
+/// are at least 4-byte aligned.
+pub fn leaf_layout<T>() -> Layout {
+    let layout = Layout::new::<T>();
+    Layout::from_size_align(cmp::max(layout.size(), 1), cmp::max(layout.align(), 4)).unwrap()
+}
+
+/// Allocate a leaf holding `t`, unless it can be stored inline.
+pub unsafe fn alloc_leaf<T: Element, A: NodeAllocator>(t: T, alloc: &mut A) -> ChildPtr<T> {
+    if stores_inline::<T>() {
+        return ChildPtr::inline(t);
+    }
+    let p = alloc.alloc(leaf_layout::<T>()) as *mut T;
+    ptr::write(p, t);
+    ChildPtr::from_leaf(p)
+}
+
+/// Move a leaf out of its parent's value slots into a leaf of its own, so that it can be moved to
+/// another node. Other pointers are returned as they are.
+pub unsafe fn detach_leaf<T: Element, A: NodeAllocator>(
+    cptr: ChildPtr<T>,
+    alloc: &mut A,
+) -> ChildPtr<T> {
+    if cptr.is_stored() {
+        alloc_leaf(take_leaf(cptr, alloc), alloc)
+    } else {
+        cptr
+    }
+}
+
+/// Allocate an inner node, along with its prefix and its value slots if its type has them. The
+/// prefix starts out zeroed.
+pub unsafe fn alloc_node<N: NodeBody, A: NodeAllocator>(
+    node: RawNode<N>,
+    alloc: &mut A,
+) -> *mut RawNode<N> {
+    let (layout, offset, _) = node_layout::<N>(node.typ);
+    let base = alloc.alloc_node(layout);
+    // The prefix and the value slots are reached from the node's address (see `in_allocation`).
+    base.expose_provenance();
+    ptr::write_bytes(base, 0, offset);
+    let p = base.add(offset) as *mut RawNode<N>;
+    ptr::write(p, node);
+    p
+}
+
+/// Free the leaf that `cptr` points to, returning its element.
+pub unsafe fn take_leaf<T, A: NodeAllocator>(cptr: ChildPtr<T>, alloc: &mut A) -> T {
+    if cptr.is_inline() {
+        return ptr::read(cptr.inline_ptr());
+    }
+    if cptr.is_stored() {
+        // The slot belongs to the parent, and is free once `cptr` has been removed from it.
+        return ptr::read(cptr.stored_ptr());
+    }
+    let p = cptr.get_raw().unwrap().ok().unwrap();
+    let res = ptr::read(p);
+    alloc.dealloc(p as *mut u8, leaf_layout::<T>());
+    res
+}
+
+/// Free the inner node that `cptr` points to, but not its children.
+pub unsafe fn free_node<T, A: NodeAllocator>(cptr: ChildPtr<T>, alloc: &mut A) {
+    let inner = cptr.get_raw().unwrap().err().unwrap();
+    // with_node_mut! will "un-erase" the actual type of the RawNode, so that we free the right
+    // number of bytes.
+    with_node_mut!(
+        &mut *inner,
+        nod,
+        {
+            let (base, layout) = nod.allocation();
+            if nod.typ.has_generation() {
+                *RawNode::generation(nod) = 0;
+            }
+            alloc.dealloc_node(base, layout);
+        },
+        T
+    )
+}
+
+/// Free every node and leaf reachable from `cptr`, dropping the elements.
+pub unsafe fn free_tree<T: Element, A: NodeAllocator>(cptr: ChildPtr<T>, alloc: &mut A) {
+    match cptr.get_raw() {
+        None => {}
+        Some(Ok(_)) => mem::drop(take_leaf(cptr, alloc)),
+        Some(Err(inner)) => {
+            with_node!(
+                &*inner,
+                nod,
+                nod.local_foreach(|_, c| free_tree(ChildPtr(c), alloc)),
+                T
+            );
+            free_node(cptr, alloc)
+        }
+    }
+}
+
 impl<T> MarkedPtr<T> {
+    fn from_word(word: usize) -> Self {
+        MarkedPtr(UnsafeCell::new(MaybeUninit::new(word)), PhantomData)
+    }
+
     pub fn null() -> Self {
-        MarkedPtr(0, PhantomData)
+        MarkedPtr::from_word(0)
     }
 
     pub fn from_node<R>(p: *mut RawNode<R>) -> Self {
         debug_assert!(!p.is_null());
-        MarkedPtr(p as usize, PhantomData)
+        MarkedPtr::from_word(p as usize)
     }
 
     pub fn from_leaf(p: *mut T) -> Self {
         debug_assert!(!p.is_null());
-        MarkedPtr((p as usize) | 1, PhantomData)
+        MarkedPtr::from_word((p as usize) | LEAF_TAG)
+    }
+
+    fn from_stored(p: *mut T) -> Self {
+        debug_assert_eq!(p as usize & TAG_MASK, 0);
+        MarkedPtr::from_word((p as usize) | STORED_TAG)
+    }
+
+    /// The tag bits, which are initialized even when an element is stored inline.
+    fn tag(&self) -> usize {
+        unsafe { *(self.0.get() as *const u8).add(TAG_BYTE) as usize & TAG_MASK }
+    }
+
+    /// The whole word. This is only initialized if the pointer is not an inline leaf.
+    fn word(&self) -> usize {
+        debug_assert!(!self.is_inline());
+        unsafe { (*self.0.get()).assume_init() }
     }
 
     pub fn is_null(&self) -> bool {
-        self.0 == 0
+        self.tag() == 0 && self.word() == 0
+    }
+
+    /// Whether this is a leaf stored inline, in which case `get` and friends point into `self`.
+    pub fn is_inline(&self) -> bool {
+        self.tag() == INLINE_TAG
+    }
+
+    /// Whether this is a leaf stored in one of the value slots of its parent.
+    pub fn is_stored(&self) -> bool {
+        self.tag() == STORED_TAG
+    }
+
+    fn stored_ptr(&self) -> *mut T {
+        (self.word() & !TAG_MASK) as *mut T
     }
 
     pub fn raw_eq(&self, other: usize) -> bool {
-        self.0 == other
+        !self.is_inline() && self.word() == other
+    }
+
+    fn inline_ptr(&self) -> *mut T {
+        (self.0.get() as *mut u8).wrapping_add(Inline::<T>::OFFSET) as *mut T
     }
 
     pub unsafe fn get(&self) -> Option<Result<&T, &RawNode<()>>> {
-        if self.0 == 0 {
-            None
-        } else if self.0 & 1 == 1 {
-            Some(Ok(&*((self.0 & !1) as *const T)))
-        } else {
-            Some(Err(&*(self.0 as *const RawNode<()>)))
-        }
+        self.get_raw().map(|r| match r {
+            Ok(leaf) => Ok(&*leaf),
+            Err(inner) => Err(&*inner),
+        })
     }
 
     pub unsafe fn get_raw(&self) -> Option<Result<*mut T, *mut RawNode<()>>> {
-        if self.0 == 0 {
-            None
-        } else if self.0 & 1 == 1 {
-            Some(Ok((self.0 & !1) as *mut T))
-        } else {
-            Some(Err(self.0 as *mut RawNode<()>))
+        match self.tag() {
+            INLINE_TAG => Some(Ok(self.inline_ptr())),
+            STORED_TAG => Some(Ok(self.stored_ptr())),
+            LEAF_TAG => Some(Ok((self.word() & !TAG_MASK) as *mut T)),
+            _ => match self.word() {
+                0 => None,
+                word => Some(Err(word as *mut RawNode<()>)),
+            },
         }
     }
 
     pub unsafe fn get_mut(&mut self) -> Option<Result<&mut T, &mut RawNode<()>>> {
-        if self.0 == 0 {
-            None
-        } else if self.0 & 1 == 1 {
-            Some(Ok(&mut *((self.0 & !1) as *mut T)))
-        } else {
-            Some(Err(&mut *(self.0 as *mut RawNode<()>)))
-        }
+        self.get_raw().map(|r| match r {
+            Ok(leaf) => Ok(&mut *leaf),
+            Err(inner) => Err(&mut *inner),
+        })
+    }
+}
+
+/// The number of leaves in the subtree rooted at `ptr`. Only meaningful for trees that maintain
+/// `RawNode::leaves`.
+pub unsafe fn subtree_leaves<T>(ptr: &MarkedPtr<T>) -> usize {
+    match ptr.get() {
+        None => 0,
+        Some(Ok(_)) => 1,
+        Some(Err(inner)) => inner.leaves() as usize,
     }
 }
 
 unsafe fn place_in_hole_at<T>(slice: &mut [T], at: usize, v: T, buff_len: usize) {
-    let raw_p = slice.get_unchecked_mut(0) as *mut T;
-    let target = raw_p.offset(at as isize);
+    let raw_p = slice.as_mut_ptr();
+    let target = raw_p.add(at);
     ptr::copy(target, raw_p.offset(at as isize + 1), buff_len - at - 1);
     ptr::write(target, v);
 }
@@ -196,6 +479,13 @@ mod place_test {
     }
 }
 
+/// An inner node.
+///
+/// The node's prefix is stored in the bytes of its allocation before it (see `RawNode::prefix`),
+/// followed by the annotation words its type asks for (see `NodeType::annotation_words`), and its
+/// value slots, if it has them, just after it. None of them are part of the `RawNode`, so
+/// references to the node do not cover them: they are reached with pointers that have the
+/// provenance of the whole allocation, which `alloc_node` exposes.
 #[repr(C)]
 #[derive(Debug)]
 pub struct RawNode<Footer> {
@@ -203,21 +493,228 @@ pub struct RawNode<Footer> {
     pub children: u16,
     pub count: u32,
     pub consumed: u32,
-    pub prefix: [u8; PREFIX_LEN],
     node: Footer,
 }
 
+/// The part of a node that depends on its type.
+pub trait NodeBody {
+    /// The element type of the tree.
+    type Elt;
+    /// The largest number of children a node of this type can have.
+    const CAPACITY: usize;
+    /// A body with no children.
+    fn empty() -> Self;
+}
+
+/// The layout of the allocation for a node of type `typ` with body `N`: its prefix and annotation
+/// words, the node, and its value slots if it has them. Returns the layout along with the offsets
+/// of the node and of its first value slot.
+fn node_layout<N: NodeBody>(typ: NodeType) -> (Layout, usize, usize) {
+    let header = typ.prefix_capacity() + typ.annotation_words() * 8;
+    let align = cmp::max(mem::align_of::<RawNode<N>>(), mem::align_of::<u64>());
+    let header = Layout::from_size_align(header, align).unwrap();
+    let (mut layout, node) = header.extend(Layout::new::<RawNode<N>>()).unwrap();
+    debug_assert_eq!(node, header.size());
+    let mut slots = 0;
+    if typ.stores_values() {
+        let slot = value_layout::<N::Elt>();
+        let array = Layout::from_size_align(slot.size() * N::CAPACITY, slot.align()).unwrap();
+        let (res, offset) = layout.extend(array).unwrap();
+        layout = res;
+        slots = offset;
+    }
+    (layout.pad_to_align(), node, slots)
+}
+
+/// The layout of a value slot. Pointers to the slots are tagged like pointers to leaves.
+fn value_layout<T>() -> Layout {
+    leaf_layout::<T>().pad_to_align()
+}
+
+impl<N: NodeBody> RawNode<N> {
+    /// The start and the layout of the node's allocation.
+    pub fn allocation(&self) -> (*mut u8, Layout) {
+        let (layout, node, _) = node_layout::<N>(self.typ);
+        (self.in_allocation(-(node as isize)), layout)
+    }
+
+    fn value_slot(&self, i: usize) -> *mut N::Elt {
+        debug_assert!(self.typ.stores_values() && i < N::CAPACITY);
+        let (_, node, slots) = node_layout::<N>(self.typ);
+        let offset = slots - node + i * value_layout::<N::Elt>().size();
+        self.in_allocation(offset as isize) as *mut N::Elt
+    }
+
+    /// The index of the value slot that `p` points to, if it is one of this node's.
+    fn value_index(&self, p: *mut N::Elt) -> Option<usize> {
+        let stride = value_layout::<N::Elt>().size();
+        let offset = (p as usize).wrapping_sub(self.value_slot(0) as usize);
+        if offset < stride * N::CAPACITY {
+            Some(offset / stride)
+        } else {
+            None
+        }
+    }
+}
+
+/// Store `t` in a free value slot of `node`, returning a pointer to it to insert at `d`.
+///
+/// The children of a `Node256` each have their own slot. Other nodes look for a slot that none of
+/// their children use, so they must not be full.
+pub unsafe fn store_value<T: Element, N: NodeBody<Elt = T>>(
+    node: &mut RawNode<N>,
+    d: u8,
+    t: T,
+) -> ChildPtr<T>
+where
+    RawNode<N>: Node<T>,
+{
+    let i = if N::CAPACITY == 256 {
+        d as usize
+    } else {
+        let mut used = 0_u64;
+        node.local_foreach(|_, c| {
+            if c.is_stored() {
+                if let Some(i) = node.value_index(c.stored_ptr()) {
+                    used |= 1 << i;
+                }
+            }
+        });
+        (!used).trailing_zeros() as usize
+    };
+    let p = node.value_slot(i);
+    ptr::write(p, t);
+    ChildPtr(MarkedPtr::from_stored(p))
+}
+
+/// Move the elements of the children of `node` that are stored in another node's value slots
+/// into its own. Nodes that are replaced by a larger or a smaller one call this before they are
+/// freed.
+pub unsafe fn rehome_values<T: Element, N: NodeBody<Elt = T>>(node: &mut RawNode<N>)
+where
+    RawNode<N>: Node<T>,
+{
+    let mut ds = SmallVec::<[u8; 64]>::new();
+    node.local_foreach(|d, c| {
+        if c.is_stored() && node.value_index(c.stored_ptr()).is_none() {
+            ds.push(d);
+        }
+    });
+    for d in ds {
+        let slot = node.find_raw(d).unwrap();
+        let t = ptr::read((*slot).stored_ptr());
+        ptr::write(slot, store_value(node, d, t));
+    }
+}
+
+/// Move the element of the child of `node` at `d` from its own leaf into a value slot.
+pub unsafe fn adopt_leaf<T: Element, N: NodeBody<Elt = T>, A: NodeAllocator>(
+    node: &mut RawNode<N>,
+    d: u8,
+    alloc: &mut A,
+) where
+    RawNode<N>: Node<T>,
+{
+    let slot = node.find_raw(d).unwrap();
+    let t = take_leaf(ptr::read(slot), alloc);
+    ptr::write(slot, store_value(node, d, t));
+}
+
 impl<T> RawNode<T> {
-    pub fn append_prefix(&mut self, d: &[u8], total_count: u32) {
-        debug_assert!(d.len() <= PREFIX_LEN);
-        unsafe {
-            ptr::copy(
-                &self.prefix[0],
-                &mut self.prefix[d.len()],
-                PREFIX_LEN - d.len(),
-            );
-            ptr::copy(&d[0], &mut self.prefix[0], d.len());
+    /// A pointer to the byte `offset` bytes from the start of the node, in the node's allocation.
+    /// It has the provenance of the whole allocation rather than that of `self`, which only
+    /// covers the node itself.
+    fn in_allocation(&self, offset: isize) -> *mut u8 {
+        let addr = (self as *const Self).addr().wrapping_add_signed(offset);
+        ptr::with_exposed_provenance_mut(addr)
+    }
+
+    /// The bytes of its prefix that the node stores: the first `typ.prefix_capacity()` of them,
+    /// of which the first `count` are valid. They sit just before its annotation words.
+    pub fn prefix(&self) -> &[u8] {
+        let cap = self.typ.prefix_capacity();
+        unsafe { slice::from_raw_parts(self.prefix_start(), cap) }
+    }
+
+    pub fn prefix_mut(&mut self) -> &mut [u8] {
+        let cap = self.typ.prefix_capacity();
+        unsafe { slice::from_raw_parts_mut(self.prefix_start(), cap) }
+    }
+
+    fn prefix_start(&self) -> *mut u8 {
+        let header = self.typ.prefix_capacity() + self.typ.annotation_words() * 8;
+        self.in_allocation(-(header as isize))
+    }
+
+    /// The annotation word `i` words before the node, starting from 1.
+    fn annotation(&self, i: usize) -> *mut u64 {
+        debug_assert!(0 < i && i <= self.typ.annotation_words());
+        self.in_allocation(-8 * i as isize) as *mut u64
+    }
+
+    /// The number of leaves below the node. Only nodes whose type `counts_leaves` have one.
+    pub fn leaves(&self) -> u64 {
+        unsafe { *self.leaves_ptr() }
+    }
+
+    pub fn leaves_mut(&mut self) -> &mut u64 {
+        unsafe { &mut *self.leaves_ptr() }
+    }
+
+    fn leaves_ptr(&self) -> *mut u64 {
+        debug_assert!(self.typ.counts_leaves());
+        self.annotation(1 + self.typ.has_generation() as usize)
+    }
+
+    /// A number that `TaggedPrefixCache` stores next to its pointers to the node, so that it can
+    /// tell when the node has been freed. It is 0 until a cache asks for it, and is reset to 0
+    /// when the node is freed. Only nodes whose type `has_generation` have one.
+    ///
+    /// The generation is the word just before the node, so that it can be found without reading
+    /// the node's type. This takes a pointer rather than a reference as the node may have been
+    /// freed.
+    pub fn generation(node: *const Self) -> *mut u64 {
+        ptr::with_exposed_provenance_mut(node.addr().wrapping_sub(8))
+    }
+
+    /// The index that `AggregatingARTMap` gives the node in its table of aggregates, or 0 if it
+    /// has not given it one yet. Only nodes whose type `has_agg_slot` have one.
+    pub fn agg_slot(&self) -> u64 {
+        unsafe { *self.agg_slot_ptr() }
+    }
+
+    pub fn agg_slot_mut(&mut self) -> &mut u64 {
+        unsafe { &mut *self.agg_slot_ptr() }
+    }
+
+    fn agg_slot_ptr(&self) -> *mut u64 {
+        debug_assert!(self.typ.has_agg_slot());
+        let before = self.typ.has_generation() as usize + self.typ.counts_leaves() as usize;
+        self.annotation(1 + before)
+    }
+
+    /// Copy the prefix and the annotations of `old`, whose type has the same flags (apart from
+    /// perhaps `has_generation`), to the new node `self`. Its generation stays at 0, as the
+    /// generation of `old` identifies `old`.
+    fn copy_header<O>(&mut self, old: &RawNode<O>) {
+        self.prefix_mut().copy_from_slice(old.prefix());
+        if self.typ.counts_leaves() {
+            *self.leaves_mut() = old.leaves();
         }
+        if self.typ.has_agg_slot() {
+            *self.agg_slot_mut() = old.agg_slot();
+        }
+    }
+
+    /// Prepend `total_count` bytes to the node's prefix. `d` holds those bytes, though only the
+    /// first `typ.prefix_capacity()` of them are needed (and used).
+    pub fn append_prefix(&mut self, d: &[u8], total_count: u32) {
+        let cap = self.typ.prefix_capacity();
+        let n = cmp::min(total_count as usize, cap);
+        debug_assert!(d.len() >= n);
+        let prefix = self.prefix_mut();
+        prefix.copy_within(0..cap - n, n);
+        prefix[..n].copy_from_slice(&d[..n]);
         self.count += total_count;
         self.consumed -= total_count;
     }
@@ -236,23 +733,26 @@ impl RawNode<()> {
             consumed,
             digits
         );
-        let count = cmp::min(self.count as usize, PREFIX_LEN);
+        let cap = self.typ.prefix_capacity();
+        let count = cmp::min(self.count as usize, cap);
+        let prefix = self.prefix();
         for i in 0..count {
-            if digits[consumed + i] != self.prefix[i] {
+            if digits[consumed + i] != prefix[i] {
                 return (i, None);
             }
         }
-        if self.count as usize > PREFIX_LEN {
-            let mut matches = PREFIX_LEN;
+        if self.count as usize > cap {
+            let mut matches = cap;
             with_node!(
                 self,
                 node,
                 {
                     let min_node = node.get_min()
                         .expect("node with implicit prefix must be nonempty");
-                    for (d, m) in digits[consumed + PREFIX_LEN..]
+                    for (d, m) in digits[consumed + cap..]
                         .iter()
-                        .zip(min_node.key().digits().skip(consumed + PREFIX_LEN))
+                        .zip(min_node.key().digits().skip(consumed + cap))
+                        .take(self.count as usize - cap)
                     {
                         if *d != m {
                             break;
@@ -275,12 +775,12 @@ impl<T> RawNode<T> {
         if digits.len() < count {
             return None;
         }
-        for i in 0..cmp::min(count, PREFIX_LEN) {
-            if digits[i] != self.prefix[i] {
-                return None;
-            }
+        let cap = self.typ.prefix_capacity();
+        let n = cmp::min(count, cap);
+        if digits[..n] != self.prefix()[..n] {
+            return None;
         }
-        Some((count <= PREFIX_LEN, count))
+        Some((count <= cap, count))
     }
 }
 
@@ -313,13 +813,18 @@ impl Direction for Decreasing {
 
 pub trait Node<T: Element>: Sized {
     // insert assumes that 'd' is not present in the node. This is enforced in debug buids
-    unsafe fn insert(
+    //
+    // If the node is full and `pptr` is given, the node is upgraded to a larger one allocated
+    // from `alloc`, which replaces it in `*pptr`. The old node is returned for the caller to free
+    // once it is done with it: it cannot be freed while `self` borrows it.
+    unsafe fn insert<A: NodeAllocator>(
         &mut self,
         d: u8,
         ptr: ChildPtr<T>,
         // Error == ptr, indicates there was no space _and_ could not upgrade
         pptr: Option<*mut ChildPtr<T>>,
-    ) -> Result<(), ChildPtr<T>>;
+        alloc: &mut A,
+    ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>>;
     unsafe fn delete(&mut self, d: u8) -> DeleteResult<T>;
     fn is_full(&self) -> bool;
     fn get_min(&self) -> Option<&T>;
@@ -327,7 +832,7 @@ pub trait Node<T: Element>: Sized {
     fn find(&self, d: u8) -> Option<&ChildPtr<T>> {
         self.find_raw(d).map(|raw_ptr| unsafe { &*raw_ptr })
     }
-    fn find_mut(&self, d: u8) -> Option<&mut ChildPtr<T>> {
+    fn find_mut(&mut self, d: u8) -> Option<&mut ChildPtr<T>> {
         self.find_raw(d).map(|raw_ptr| unsafe {
             debug_assert!(!(*raw_ptr).is_null());
             &mut *raw_ptr
@@ -362,50 +867,38 @@ where
     }
 }
 
-pub fn make_node_with_prefix<T>(prefix: &[u8], consumed: u32) -> Box<RawNode<Node4<T>>> {
-    let mut new_node = Box::new(RawNode {
-        typ: NODE_4,
+/// Allocate an empty `Node4` with the given prefix. `typ` is `NODE_4`, along with any of the
+/// flags that `NodeType` carries.
+pub unsafe fn make_node_with_prefix<T, A: NodeAllocator>(
+    prefix: &[u8],
+    consumed: u32,
+    typ: NodeType,
+    alloc: &mut A,
+) -> *mut RawNode<Node4<T>> {
+    debug_assert_eq!(typ.kind(), NODE_4);
+    let new_node = RawNode {
+        typ,
         children: 0,
-        consumed: consumed,
+        consumed,
         count: prefix.len() as u32,
-        prefix: [0; PREFIX_LEN],
-        node: Node4 {
-            keys: [0; 4],
-            ptrs: unsafe { mem::transmute::<[usize; 4], [ChildPtr<T>; 4]>([0 as usize; 4]) },
-        },
-    });
-    let new_len = cmp::min(prefix.len(), PREFIX_LEN);
-    if prefix.len() > 0 {
-        unsafe {
-            ptr::copy_nonoverlapping(
-                &prefix[0] as *const _,
-                &mut new_node.prefix[0] as *mut _,
-                new_len,
-            );
-        }
-    }
-    new_node
+        node: Node4::empty(),
+    };
+    let res = alloc_node(new_node, alloc);
+    let new_len = cmp::min(prefix.len(), typ.prefix_capacity());
+    (*res).prefix_mut()[..new_len].copy_from_slice(&prefix[..new_len]);
+    res
 }
 
-pub fn make_node_from_common_prefix<T>(
+pub unsafe fn make_node_from_common_prefix<T, A: NodeAllocator>(
     d1: &[u8],
     d2: &[u8],
     consumed: u32,
-) -> Box<RawNode<Node4<T>>> {
+    typ: NodeType,
+    alloc: &mut A,
+) -> *mut RawNode<Node4<T>> {
     let mut common_prefix_digits = SmallVec::<[u8; 32]>::new();
     get_matching_prefix_slice(d1.iter(), d2.iter(), &mut common_prefix_digits);
-    make_node_with_prefix(&common_prefix_digits[..], consumed)
-}
-
-pub struct Node4<T> {
-    keys: [u8; 4],
-    ptrs: [ChildPtr<T>; 4],
-}
-
-impl<T> ::std::fmt::Debug for Node4<T> {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        write!(f, "Node4({:?}, {:?})", self.keys, &self.ptrs[..])
-    }
+    make_node_with_prefix(&common_prefix_digits[..], consumed, typ, alloc)
 }
 
 pub fn visit_leaf<T, F, D>(
@@ -425,7 +918,7 @@ pub fn visit_leaf<T, F, D>(
         if s.is_none() {
             return;
         }
-        debug_assert!(s.unwrap().len() > 0);
+        debug_assert!(!s.unwrap().is_empty());
         let slice = s.unwrap();
         if slice.len() <= by {
             *s = None;
@@ -448,7 +941,7 @@ pub fn visit_leaf<T, F, D>(
             PrefixIter {
                 ix: 0,
                 len: node.count as usize,
-                node: node,
+                node,
                 _min: SmallVec::new(),
                 _marker: PhantomData,
             }
@@ -466,8 +959,8 @@ pub fn visit_leaf<T, F, D>(
             if self.ix >= self.len {
                 return None;
             }
-            if self.ix < PREFIX_LEN {
-                let res = self.node.prefix[self.ix];
+            if self.ix < self.node.typ.prefix_capacity() {
+                let res = self.node.prefix()[self.ix];
                 self.ix += 1;
                 return Some(res);
             }
@@ -476,7 +969,7 @@ pub fn visit_leaf<T, F, D>(
     }
     match unsafe { c.get() } {
         None => {}
-        Some(Ok(ref leaf)) => {
+        Some(Ok(leaf)) => {
             if let Some(up) = rval {
                 if up <= leaf.key() {
                     return;
@@ -521,16 +1014,120 @@ mod node_variants {
     use super::*;
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct NodeType(u16);
+    // Kinds are numbered in increasing order of capacity. Adding one means listing it in
+    // `node_kinds!` (which also gives it a field in `MemoryReport` and `ShrinkThresholds`),
+    // implementing `Node` and `NodeBody` for it, and giving it a place in the growth order (the
+    // `insert` of the next smaller kind).
     pub const NODE_4: NodeType = NodeType(1);
     pub const NODE_16: NodeType = NodeType(2);
-    pub const NODE_48: NodeType = NodeType(3);
-    pub const NODE_256: NodeType = NodeType(4);
+    pub const NODE_32: NodeType = NodeType(3);
+    pub const NODE_48: NodeType = NodeType(4);
+    pub const NODE_256: NodeType = NodeType(5);
+
+    /// The bits that hold one of the types above. The rest hold flags.
+    const KIND_MASK: u16 = 0xff;
+    /// Set on the types of nodes that are followed by an array of value slots, one for each child
+    /// they can hold, that store the elements of their leaf children (see `MultiValueLeaves`).
+    const STORES_VALUES: u16 = 1 << 8;
+    /// The number of 8-byte words of prefix that nodes of this type store in addition to
+    /// `PREFIX_LEN` bytes.
+    const PREFIX_WORDS_SHIFT: u16 = 9;
+    const PREFIX_WORDS_MASK: u16 = 0b111 << PREFIX_WORDS_SHIFT;
+    /// Set on the types of nodes that count the leaves below them (see `RawNode::leaves`).
+    const LEAF_COUNT: u16 = 1 << 12;
+    /// Set on the types of nodes that have a generation (see `RawNode::generation`).
+    const GENERATION: u16 = 1 << 13;
+    /// Set on the types of nodes that have an aggregate slot (see `RawNode::agg_slot`).
+    const AGG_SLOT: u16 = 1 << 14;
+
+    impl NodeType {
+        /// The type without its flags: `NODE_4`, `NODE_16`, `NODE_32`, `NODE_48` or `NODE_256`.
+        pub fn kind(self) -> NodeType {
+            NodeType(self.0 & KIND_MASK)
+        }
+
+        pub fn stores_values(self) -> bool {
+            self.0 & STORES_VALUES != 0
+        }
+
+        pub fn with_values(self) -> NodeType {
+            NodeType(self.0 | STORES_VALUES)
+        }
+
+        /// The number of bytes of its prefix that a node of this type stores.
+        pub fn prefix_capacity(self) -> usize {
+            PREFIX_LEN + ((self.0 & PREFIX_WORDS_MASK) >> PREFIX_WORDS_SHIFT) as usize * 8
+        }
+
+        /// `self`, storing `bytes` bytes of prefix. `bytes` must be a multiple of 8 between
+        /// `PREFIX_LEN` and `MAX_PREFIX_CAPACITY`.
+        pub fn with_prefix_capacity(self, bytes: usize) -> NodeType {
+            debug_assert!(bytes.is_multiple_of(8));
+            debug_assert!((PREFIX_LEN..=MAX_PREFIX_CAPACITY).contains(&bytes));
+            let words = ((bytes - PREFIX_LEN) / 8) as u16;
+            NodeType((self.0 & !PREFIX_WORDS_MASK) | (words << PREFIX_WORDS_SHIFT))
+        }
+
+        pub fn counts_leaves(self) -> bool {
+            self.0 & LEAF_COUNT != 0
+        }
+
+        /// `self`, counting the leaves below it if `on` is true.
+        pub fn with_leaf_count(self, on: bool) -> NodeType {
+            if on {
+                NodeType(self.0 | LEAF_COUNT)
+            } else {
+                NodeType(self.0 & !LEAF_COUNT)
+            }
+        }
+
+        pub fn has_generation(self) -> bool {
+            self.0 & GENERATION != 0
+        }
+
+        /// `self`, with a generation if `on` is true.
+        pub fn with_generation(self, on: bool) -> NodeType {
+            if on {
+                NodeType(self.0 | GENERATION)
+            } else {
+                NodeType(self.0 & !GENERATION)
+            }
+        }
+
+        pub fn has_agg_slot(self) -> bool {
+            self.0 & AGG_SLOT != 0
+        }
+
+        /// `self`, with an aggregate slot if `on` is true.
+        pub fn with_agg_slot(self, on: bool) -> NodeType {
+            if on {
+                NodeType(self.0 | AGG_SLOT)
+            } else {
+                NodeType(self.0 & !AGG_SLOT)
+            }
+        }
+
+        /// The number of 8-byte words of annotations (such as the leaf count) that nodes of this
+        /// type store between their prefix and the node. The generation, if there is one, comes
+        /// last.
+        pub fn annotation_words(self) -> usize {
+            self.has_generation() as usize
+                + self.counts_leaves() as usize
+                + self.has_agg_slot() as usize
+        }
+
+        /// `self`, with the flags of `other`: it stores values, as much of its prefix as `other`
+        /// does, and the same annotations.
+        pub fn like(self, other: NodeType) -> NodeType {
+            NodeType(self.kind().0 | (other.0 & !KIND_MASK))
+        }
+    }
 
     fn advance_or(s: &mut Option<&[u8]>, b: usize) -> usize {
         if s.is_none() {
             return b;
         }
-        debug_assert!(s.unwrap().len() > 0);
+        debug_assert!(!s.unwrap().is_empty());
         let slice = s.unwrap();
         let res = slice[0] as usize;
         if slice.len() == 1 {
@@ -541,66 +1138,99 @@ mod node_variants {
         res
     }
 
-    // (very) ad-hoc polymorphism!
-    macro_rules! n416_delete {
-        ($slf: expr, $d: expr) => {{
-            let _res = match $slf.find_internal($d) {
-                None => DeleteResult::Failure,
-                Some((ix, ptr)) => {
-                    let deleted = (*ptr).swap_null();
-                    //  trace!(true, "d={} slf={:?}", $d, $slf);
-                    if ix + 1 < $slf.node.keys[..].len() {
-                        ptr::copy(
-                            &$slf.node.keys[ix + 1],
-                            &mut $slf.node.keys[ix],
-                            $slf.children as usize - ix,
-                        );
-                        ptr::copy(
-                            &$slf.node.ptrs[ix + 1],
-                            &mut $slf.node.ptrs[ix],
-                            $slf.children as usize - ix,
-                        );
-                    }
-                    debug_assert!($slf.children > 0);
-                    $slf.children -= 1;
-                    ptr::write(
-                        &mut $slf.node.ptrs[$slf.children as usize],
-                        ChildPtr::null(),
-                    );
-                    if $slf.children == 1 {
-                        let mut c_ptr = ChildPtr::null();
-                        debug_assert!(
-                            !$slf.node.ptrs[0].is_null(),
-                            "{:?} Uh oh! {:?}",
-                            $slf as *const _,
-                            $slf
-                        );
-                        mem::swap(&mut $slf.node.ptrs[0], &mut c_ptr);
+    /// An empty node of type `typ`, with the same header as `old`.
+    unsafe fn empty_like<T, N: NodeBody, A: NodeAllocator>(
+        old: &RawNode<()>,
+        typ: NodeType,
+        node: N,
+        alloc: &mut A,
+    ) -> ChildPtr<T> {
+        let new_node = RawNode {
+            typ,
+            children: 0,
+            count: old.count,
+            consumed: old.consumed,
+            node,
+        };
+        let res = alloc_node(new_node, alloc);
+        (*res).copy_header(old);
+        ChildPtr::from_node(res)
+    }
 
-                        debug_assert!(
-                            Some($slf.node.keys[0]) != T::Key::STOP_CHARACTER
-                                || c_ptr.get().unwrap().is_ok(),
-                            "Singleton is stop {:#?}",
-                            $slf
-                        );
-                        DeleteResult::Singleton {
-                            deleted: deleted,
-                            last: c_ptr,
-                            last_d: $slf.node.keys[0],
-                        }
-                    } else {
-                        DeleteResult::Success(deleted)
-                    }
+    /// Move the children of the inner node that `cptr` points to into a new node of the smallest
+    /// type that can hold them. Returns false (and leaves `cptr` alone) if `cptr` is not an inner
+    /// node, or if it already has that type.
+    ///
+    /// Nodes are upgraded as they fill up. Deletions only downgrade them once they reach the
+    /// tree's `ShrinkThresholds`.
+    pub unsafe fn shrink_node<T: Element, A: NodeAllocator>(
+        cptr: &mut ChildPtr<T>,
+        alloc: &mut A,
+    ) -> bool {
+        let typ = match cptr.get() {
+            Some(Err(old)) => {
+                let typ = node_kinds!(smallest_kind, old.children as usize);
+                if typ.0 >= old.typ.kind().0 {
+                    return false;
                 }
-            };
-            debug_assert!($slf.find_internal($d).is_none());
-            _res
-        }};
+                typ.like(old.typ)
+            }
+            _ => return false,
+        };
+        move_to_node(cptr, typ, alloc);
+        true
     }
-    impl<T> ::std::fmt::Debug for Node16<T> {
-        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-            write!(f, "Node16({:?}, {:?})", self.keys, &self.ptrs[..])
+
+    /// Move the children of the inner node that `cptr` points to into a new node of the same
+    /// kind, with a generation if `on` is true (see `RawNode::generation`).
+    pub unsafe fn set_has_generation<T: Element, A: NodeAllocator>(
+        cptr: &mut ChildPtr<T>,
+        on: bool,
+        alloc: &mut A,
+    ) {
+        let typ = cptr.get().unwrap().err().unwrap().typ;
+        move_to_node(cptr, typ.with_generation(on), alloc)
+    }
+
+    /// Move the children of the inner node that `cptr` points to into a new node of type `typ`,
+    /// which must have room for them, and free the old node.
+    unsafe fn move_to_node<T: Element, A: NodeAllocator>(
+        cptr: &mut ChildPtr<T>,
+        typ: NodeType,
+        alloc: &mut A,
+    ) {
+        let mut new_ptr = {
+            let old = cptr.get().unwrap().err().unwrap();
+            node_kinds!(empty_of_kind, typ, old, alloc, T)
+        };
+        {
+            let old = cptr.get_mut().unwrap().err().unwrap();
+            let new = new_ptr.get_mut().unwrap().err().unwrap();
+            with_node_mut!(
+                old,
+                old_node,
+                {
+                    let mut ds = SmallVec::<[u8; 64]>::new();
+                    old_node.local_foreach(|d, _| ds.push(d));
+                    for d in ds {
+                        let child = (*old_node.find_raw(d).unwrap()).swap_null();
+                        let _r = with_node_mut!(
+                            &mut *new,
+                            new_node,
+                            new_node.insert(d, child, None, alloc),
+                            T
+                        );
+                        debug_assert!(_r.is_ok());
+                    }
+                },
+                T
+            );
+            if new.typ.stores_values() {
+                with_node_mut!(new, new_node, rehome_values(new_node), T);
+            }
         }
+        // The old node only holds null pointers now.
+        free_node(mem::replace(cptr, new_ptr), alloc);
     }
 
     fn is_sorted(slice: &[u8]) -> bool {
@@ -628,324 +1258,315 @@ mod node_variants {
         };
     }
 
-    macro_rules! n416_foreach {
-        ($slf: expr, $f: expr, $lower: expr,
-         $upper: expr, $lval: expr, $uval: expr,
-         $dir:expr, $ltr:expr) => {{
-            debug_assert!(is_sorted(&$slf.node.keys[..$slf.children as usize]));
-            let low = advance_or(&mut $lower, 0);
-            let high = advance_or(&mut $upper, 255);
-            let children = $slf.children as usize;
-            do_foreach_dir!($ltr, 0..children, i, {
-                let k = $slf.node.keys[i] as usize;
-                if $ltr {
-                    if k < low {
-                        continue;
-                    }
-                    if k > high {
-                        break;
-                    }
-                } else {
-                    if k > high {
-                        continue;
-                    }
-                    if k < low {
-                        break;
-                    }
-                }
-                let low = if k == low { $lower } else { None };
-                let high = if k == high { $upper } else { None };
-                visit_leaf(&$slf.node.ptrs[i], $f, low, high, $lval, $uval, $dir)
-            })
-        }};
+    /// The keys of a `SortedNode`, and how to search them. Bit `i` of each result is set if key
+    /// `i` passes the test; callers mask off the bits past the node's children.
+    pub trait SortedKeys {
+        /// The keys equal to `d`.
+        fn matching(&self, d: u8) -> u32;
+        /// The keys greater than `d`.
+        fn greater(&self, d: u8) -> u32;
     }
 
-    macro_rules! n416_local_foreach {
-        ($slf: expr, $f: expr) => {{
-            debug_assert!(is_sorted(&$slf.node.keys[..$slf.children as usize]));
-            let children = $slf.children as usize;
-            for i in 0..children {
-                let k = $slf.node.keys[i];
-                let ptr = &$slf.node.ptrs[i];
-                debug_assert!(!ptr.is_null());
-                $f(k, unsafe { ptr.to_marked() });
-            }
-        }};
+    impl SortedKeys for [u8; 4] {
+        fn matching(&self, d: u8) -> u32 {
+            self.iter().enumerate().fold(0, |bits, (i, &k)| bits | ((k == d) as u32) << i)
+        }
+
+        fn greater(&self, d: u8) -> u32 {
+            self.iter().enumerate().fold(0, |bits, (i, &k)| bits | ((k > d) as u32) << i)
+        }
     }
-    impl<T> RawNode<Node4<T>> {
-        fn find_internal(&self, d: u8) -> Option<(usize, *mut ChildPtr<T>)> {
-            debug_assert!(self.children <= 4);
-            for i in 0..4 {
-                if i == self.children as usize {
-                    break;
-                }
-                if self.node.keys[i] == d {
-                    unsafe {
-                        debug_assert!(
-                            !self.node.ptrs[i].is_null(),
-                            "Returning something null (i={}, d={}) (already-deleted node?)! keys={:?} ptrs={:?}",
-                            i, d, &self.node.keys[..], &self.node.ptrs[..]);
-                        return Some((i, self.node.ptrs.get_unchecked(i) as *const _ as *mut _));
-                    };
-                }
-            }
-            None
+
+    impl SortedKeys for [u8; 16] {
+        fn matching(&self, d: u8) -> u32 {
+            match_byte(self, d)
+        }
+
+        fn greater(&self, d: u8) -> u32 {
+            match_greater(self, d)
         }
     }
 
-    impl<T: Element> Node<T> for RawNode<Node4<T>> {
-        fn find_raw(&self, d: u8) -> Option<*mut ChildPtr<T>> {
-            self.find_internal(d).map(|(_, ptr)| ptr)
+    impl SortedKeys for [u8; 32] {
+        fn matching(&self, d: u8) -> u32 {
+            match_byte32(self, d)
         }
 
-        unsafe fn delete(&mut self, d: u8) -> DeleteResult<T> {
-            n416_delete!(self, d)
+        fn greater(&self, d: u8) -> u32 {
+            match_greater32(self, d)
         }
+    }
 
-        fn local_foreach<F: FnMut(u8, MarkedPtr<T>)>(&self, mut f: F) {
-            n416_local_foreach!(self, f)
+    /// How a full `SortedNode` makes room for another child.
+    pub trait Grow<T: Element>: NodeBody<Elt = T> + Sized {
+        /// Insert `ptr` at `d` into the full node `node` by moving its children into a node of
+        /// the next larger kind, which replaces `node` in `*pp`. Returns the old node, as
+        /// `Node::insert` does.
+        unsafe fn grow_insert<A: NodeAllocator>(
+            node: &mut RawNode<Self>,
+            d: u8,
+            ptr: ChildPtr<T>,
+            pp: *mut ChildPtr<T>,
+            alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>>;
+    }
+
+    /// Replace the full node `old`, which `*pp` points to, with a node of kind `kind` once
+    /// `fill` has moved the children of `old` into its body. Returns the new node, and the
+    /// pointer to `old` that it replaced.
+    unsafe fn grow<'a, T: Element, O, N: NodeBody<Elt = T>, A: NodeAllocator>(
+        old: &mut RawNode<O>,
+        kind: NodeType,
+        fill: impl FnOnce(&mut O, &mut N),
+        pp: *mut ChildPtr<T>,
+        alloc: &mut A,
+    ) -> (&'a mut RawNode<N>, ChildPtr<T>)
+    where
+        RawNode<N>: Node<T>,
+    {
+        let new_node = &mut *alloc_node(
+            RawNode {
+                typ: kind.like(old.typ),
+                children: old.children,
+                count: old.count,
+                consumed: old.consumed,
+                node: N::empty(),
+            },
+            alloc,
+        );
+        fill(&mut old.node, &mut new_node.node);
+        new_node.copy_header(old);
+        if new_node.typ.stores_values() {
+            rehome_values(new_node);
         }
+        #[cfg(debug_assertions)]
+        {
+            old.children = !0;
+        }
+        let old = mem::replace(&mut *pp, ChildPtr::from_node(new_node));
+        (new_node, old)
+    }
 
-        fn get_min(&self) -> Option<&T> {
-            debug_assert!(self.children <= 4);
-            if self.children == 0 {
-                return None;
-            }
-            // we keep the child list sorted, so we recur at '0'
-            match unsafe { self.node.ptrs[0 as usize].get().unwrap() } {
-                Ok(t) => Some(t),
-                Err(inner_node) => with_node!(inner_node, node, { node.get_min() }),
+    /// Move the keys and children of `old` to the front of `new`, which is empty.
+    fn move_sorted<T, const N: usize, const M: usize>(
+        old: &mut SortedNode<T, N>,
+        new: &mut SortedNode<T, M>,
+    ) {
+        new.keys[..N].copy_from_slice(&old.keys);
+        for (o, n) in old.ptrs.iter_mut().zip(new.ptrs.iter_mut()) {
+            mem::swap(o, n);
+        }
+    }
+
+    /// A node with room for `N` children, which it keeps in the order of their keys. The keys are
+    /// searched all at once (see `SortedKeys`).
+    pub struct SortedNode<T, const N: usize> {
+        keys: [u8; N],
+        ptrs: [ChildPtr<T>; N],
+    }
+
+    pub type Node4<T> = SortedNode<T, 4>;
+    pub type Node16<T> = SortedNode<T, 16>;
+    /// Its keys are searched with AVX2 where that is available (see `match_byte32`).
+    pub type Node32<T> = SortedNode<T, 32>;
+
+    impl<T, const N: usize> NodeBody for SortedNode<T, N> {
+        type Elt = T;
+        const CAPACITY: usize = N;
+
+        fn empty() -> Self {
+            SortedNode {
+                keys: [0; N],
+                ptrs: std::array::from_fn(|_| ChildPtr::null()),
             }
         }
+    }
 
-        fn is_full(&self) -> bool {
-            self.children == 4
+    impl<T, const N: usize> ::std::fmt::Debug for SortedNode<T, N> {
+        fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
+            write!(f, "Node{}({:?}, {:?})", N, self.keys, &self.ptrs[..])
         }
+    }
 
-        unsafe fn insert(
-            &mut self,
+    impl<T: Element> Grow<T> for Node4<T> {
+        unsafe fn grow_insert<A: NodeAllocator>(
+            node: &mut RawNode<Self>,
             d: u8,
             ptr: ChildPtr<T>,
-            pptr: Option<*mut ChildPtr<T>>,
-        ) -> Result<(), ChildPtr<T>> {
-            debug_assert!(self.find_raw(d).is_none());
-            debug_assert!(Some(d) != T::Key::STOP_CHARACTER || ptr.get().unwrap().is_ok());
-            if self.children == 4 {
-                if let Some(pp) = pptr {
-                    let new_node = &mut *Box::into_raw(Box::new(RawNode {
-                        typ: NODE_16,
-                        children: self.children,
-                        consumed: self.consumed,
-                        count: self.count,
-                        prefix: self.prefix,
-                        node: Node16 {
-                            keys: [0; 16],
-                            ptrs: mem::transmute::<[usize; 16], [ChildPtr<T>; 16]>(
-                                [0 as usize; 16],
-                            ),
-                        },
-                    }));
-                    ptr::swap_nonoverlapping(&mut self.node.keys[0], &mut new_node.node.keys[0], 4);
-                    ptr::swap_nonoverlapping(&mut self.node.ptrs[0], &mut new_node.node.ptrs[0], 4);
-                    #[cfg(debug_assertions)]
-                    {
-                        self.children = !0;
-                    }
-                    let new_cptr = ChildPtr::from_node(new_node);
-                    *pp = new_cptr;
-                    let res = new_node.insert(d, ptr, None);
-                    debug_assert!(res.is_ok());
-                    return res;
-                } else {
-                    return Err(ptr);
-                }
-            }
-            for i in 0..4 {
-                if i == (self.children as usize) {
-                    // found an empty slot!
-                    debug_assert!(self.node.ptrs[i].is_null());
-                    self.node.keys[i] = d;
-                    self.node.ptrs[i] = ptr;
-                    self.children += 1;
-                    debug_assert!(is_sorted(&self.node.keys[..self.children as usize]));
-                    return Ok(());
-                }
-                let cur_digit = self.node.keys[i];
-                debug_assert!(
-                    cur_digit != d,
-                    "Found matching current digit! cur_digit={:?}, prefix={:?}, count={:?}, children={:?} keys={:?}",
-                    cur_digit,
-                    &self.prefix[..],
-                    self.count,
-                    self.children,
-                    &self.node.keys[..],
-                );
-                if cur_digit > d {
-                    // we keep the list sorted, so we need to move all other entries ahead by 1
-                    // slot. This is not safe if it's already full.
-                    place_in_hole_at(&mut self.node.keys[..], i, d, 4);
-                    place_in_hole_at(&mut self.node.ptrs[..], i, ptr, 4);
-                    self.children += 1;
-                    debug_assert!(is_sorted(&self.node.keys[..self.children as usize]));
-                    return Ok(());
-                }
-            }
-            unreachable!()
+            pp: *mut ChildPtr<T>,
+            alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>> {
+            let (new, old) = grow(node, NODE_16, move_sorted::<T, 4, 16>, pp, alloc);
+            new.insert(d, ptr, None, alloc).map(|_| Some(old))
         }
+    }
 
-        fn for_each<F: FnMut(&T), D: Direction>(
-            &self,
-            f: &mut F,
-            mut lower: Option<&[u8]>,
-            mut upper: Option<&[u8]>,
-            lval: Option<&T::Key>,
-            rval: Option<&T::Key>,
-            _dir: D,
-        ) {
-            n416_foreach!(self, f, lower, upper, lval, rval, _dir, D::LEFT_TO_RIGHT)
+    impl<T: Element> Grow<T> for Node16<T> {
+        unsafe fn grow_insert<A: NodeAllocator>(
+            node: &mut RawNode<Self>,
+            d: u8,
+            ptr: ChildPtr<T>,
+            pp: *mut ChildPtr<T>,
+            alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>> {
+            let (new, old) = grow(node, NODE_32, move_sorted::<T, 16, 32>, pp, alloc);
+            new.insert(d, ptr, None, alloc).map(|_| Some(old))
         }
     }
 
-    pub struct Node16<T> {
-        keys: [u8; 16],
-        ptrs: [ChildPtr<T>; 16],
+    impl<T: Element> Grow<T> for Node32<T> {
+        unsafe fn grow_insert<A: NodeAllocator>(
+            node: &mut RawNode<Self>,
+            d: u8,
+            ptr: ChildPtr<T>,
+            pp: *mut ChildPtr<T>,
+            alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>> {
+            let fill = |old: &mut Node32<T>, new: &mut Node48<T>| {
+                for i in 0..32 {
+                    mem::swap(&mut old.ptrs[i], &mut new.ptrs[i]);
+                    new.keys[old.keys[i] as usize] = i as u8 + 1;
+                }
+            };
+            let (new, old) = grow(node, NODE_48, fill, pp, alloc);
+            new.insert(d, ptr, None, alloc).map(|_| Some(old))
+        }
     }
 
-    impl<T> RawNode<Node16<T>> {
-        fn find_internal(&self, d: u8) -> Option<(usize, *mut ChildPtr<T>)> {
+    impl<T, const N: usize> RawNode<SortedNode<T, N>>
+    where
+        [u8; N]: SortedKeys,
+    {
+        /// The bits of a search of the keys that belong to children.
+        fn mask(&self) -> u32 {
             debug_assert!(self.children != !0, "This node has been upgraded");
-            let mask = (1 << (self.children as usize)) - 1;
-            #[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), target_feature = "sse2"))]
-            {
-                let ks = simd::u8x16::load(&self.node.keys[..], 0);
-                let d_splat = simd::u8x16::splat(d);
-                let comps = d_splat.eq(ks);
-                let bits = unsafe { _mm_movemask_epi8(mem::transmute(comps)) } & mask;
-                return if bits == 0 {
-                    None
-                } else {
-                    debug_assert_eq!(bits.count_ones(), 1);
-                    let target = bits.trailing_zeros() as usize;
-                    debug_assert!(target < 16);
-                    debug_assert!(
-                        !self.node.ptrs[target].is_null(),
-                        "children={} keys={:?} ptrs={:?}",
-                        self.children,
-                        &self.node.keys[..],
-                        &self.node.ptrs[..]
-                    );
-                    Some((target, unsafe {
-                        self.node.ptrs.get_unchecked(target) as *const _ as *mut _
-                    }))
-                };
-            }
-            #[cfg(not(all(any(target_arch = "x86_64", target_arch = "x86"),
-                          target_feature = "sse2")))]
-            {
-                // copy over Node4 implementation
-                unimplemented!()
+            ((1_u64 << self.children) - 1) as u32
+        }
+
+        fn find_internal(&self, d: u8) -> Option<(usize, *mut ChildPtr<T>)> {
+            let bits = self.node.keys.matching(d) & self.mask();
+            if bits == 0 {
+                None
+            } else {
+                debug_assert_eq!(bits.count_ones(), 1);
+                let target = bits.trailing_zeros() as usize;
+                debug_assert!(target < N);
+                debug_assert!(
+                    !self.node.ptrs[target].is_null(),
+                    "children={} keys={:?} ptrs={:?}",
+                    self.children,
+                    &self.node.keys[..],
+                    &self.node.ptrs[..]
+                );
+                Some((target, unsafe {
+                    self.node.ptrs.as_ptr().add(target) as *mut _
+                }))
             }
         }
     }
 
-    impl<T: Element> Node<T> for RawNode<Node16<T>> {
+    impl<T: Element, const N: usize> Node<T> for RawNode<SortedNode<T, N>>
+    where
+        [u8; N]: SortedKeys,
+        SortedNode<T, N>: Grow<T>,
+    {
         fn is_full(&self) -> bool {
-            self.children == 16
+            self.children as usize == N
         }
+
         fn find_raw(&self, d: u8) -> Option<*mut ChildPtr<T>> {
             self.find_internal(d).map(|(_, ptr)| ptr)
         }
 
         fn local_foreach<F: FnMut(u8, MarkedPtr<T>)>(&self, mut f: F) {
-            n416_local_foreach!(self, f)
+            debug_assert!(is_sorted(&self.node.keys[..self.children as usize]));
+            for i in 0..self.children as usize {
+                let ptr = &self.node.ptrs[i];
+                debug_assert!(!ptr.is_null());
+                f(self.node.keys[i], unsafe { ptr.to_marked() });
+            }
         }
 
         unsafe fn delete(&mut self, d: u8) -> DeleteResult<T> {
-            n416_delete!(self, d)
+            let res = match self.find_internal(d) {
+                None => DeleteResult::Failure,
+                Some((ix, ptr)) => {
+                    let deleted = (*ptr).swap_null();
+                    let children = self.children as usize;
+                    debug_assert!(children > 0);
+                    let after = children - ix - 1;
+                    self.node.keys.copy_within(ix + 1..children, ix);
+                    let ptrs = self.node.ptrs.as_mut_ptr();
+                    ptr::copy(ptrs.add(ix + 1), ptrs.add(ix), after);
+                    self.children -= 1;
+                    ptr::write(ptrs.add(children - 1), ChildPtr::null());
+                    if self.children == 1 {
+                        let mut c_ptr = ChildPtr::null();
+                        debug_assert!(
+                            !self.node.ptrs[0].is_null(),
+                            "{:?} Uh oh! {:?}",
+                            self as *const _,
+                            self
+                        );
+                        mem::swap(&mut self.node.ptrs[0], &mut c_ptr);
+                        debug_assert!(
+                            Some(self.node.keys[0]) != T::Key::STOP_CHARACTER
+                                || c_ptr.get().unwrap().is_ok(),
+                            "Singleton is stop {:#?}",
+                            self
+                        );
+                        DeleteResult::Singleton {
+                            deleted,
+                            last: c_ptr,
+                            last_d: self.node.keys[0],
+                        }
+                    } else {
+                        DeleteResult::Success(deleted)
+                    }
+                }
+            };
+            debug_assert!(self.find_internal(d).is_none());
+            res
         }
 
         fn get_min(&self) -> Option<&T> {
-            debug_assert!(self.children <= 16);
+            debug_assert!(self.children as usize <= N);
             if self.children == 0 {
                 return None;
             }
-            let min_key_ix = 0; // we keep the child list sorted
-            match unsafe { self.node.ptrs[min_key_ix as usize].get().unwrap() } {
+            // we keep the child list sorted, so we recur at '0'
+            match unsafe { self.node.ptrs[0].get().unwrap() } {
                 Ok(t) => Some(t),
-                Err(inner_node) => with_node!(inner_node, node, { node.get_min() }),
+                Err(inner_node) => with_node!(inner_node, node, node.get_min()),
             }
         }
 
-        unsafe fn insert(
+        unsafe fn insert<A: NodeAllocator>(
             &mut self,
             d: u8,
             ptr: ChildPtr<T>,
             pptr: Option<*mut ChildPtr<T>>,
-        ) -> Result<(), ChildPtr<T>> {
+            alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>> {
             debug_assert!(Some(d) != T::Key::STOP_CHARACTER || ptr.get().unwrap().is_ok());
             debug_assert!(self.find_raw(d).is_none());
-            let mask = (1 << (self.children as usize)) - 1;
-            if self.children == 16 {
-                if let Some(pp) = pptr {
-                    // upgrade
-                    let new_node = &mut *Box::into_raw(Box::new(RawNode {
-                        typ: NODE_48,
-                        children: 16,
-                        count: self.count,
-                        consumed: self.consumed,
-                        prefix: self.prefix,
-                        node: Node48 {
-                            keys: [0; 256],
-                            ptrs: mem::transmute::<[usize; 48], [ChildPtr<T>; 48]>(
-                                [0 as usize; 48],
-                            ),
-                        },
-                    }));
-                    for i in 0..16 {
-                        let ix = self.node.keys[i] as usize;
-                        mem::swap(
-                            self.node.ptrs.get_unchecked_mut(i),
-                            new_node.node.ptrs.get_unchecked_mut(i),
-                        );
-                        new_node.node.keys[ix] = i as u8 + 1;
-                    }
-                    #[cfg(debug_assertions)]
-                    {
-                        self.children = !0;
-                    }
-                    let new_cptr = ChildPtr::from_node(new_node);
-                    *pp = new_cptr;
-                    let res = new_node.insert(d, ptr, None);
-                    debug_assert!(res.is_ok());
-                    return Ok(());
-                } else {
-                    return Err(ptr);
-                }
-            }
-            #[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), target_feature = "sse2"))]
-            {
-                let ks = simd::u8x16::load(&self.node.keys[..], 0);
-                let d_splat = simd::u8x16::splat(d);
-                let comps = d_splat.lt(ks);
-                let bits: i32 = _mm_movemask_epi8(mem::transmute(comps)) & mask;
-                let zeros = bits.trailing_zeros();
-                let target = if zeros == 32 {
-                    self.children as usize
-                } else {
-                    zeros as usize
+            if self.is_full() {
+                return match pptr {
+                    Some(pp) => SortedNode::grow_insert(self, d, ptr, pp, alloc),
+                    None => Err(ptr),
                 };
-                place_in_hole_at(&mut self.node.keys[..], target, d, 16);
-                place_in_hole_at(&mut self.node.ptrs[..], target, ptr, 16);
-            }
-            #[cfg(not(all(any(target_arch = "x86_64", target_arch = "x86"),
-                          target_feature = "sse2")))]
-            {
-                // copy over Node16 implementation
-                unimplemented!()
             }
+            let bits = self.node.keys.greater(d) & self.mask();
+            let target = if bits == 0 {
+                self.children as usize
+            } else {
+                bits.trailing_zeros() as usize
+            };
+            place_in_hole_at(&mut self.node.keys[..], target, d, N);
+            place_in_hole_at(&mut self.node.ptrs[..], target, ptr, N);
             self.children += 1;
             debug_assert!(is_sorted(&self.node.keys[..self.children as usize]));
-            return Ok(());
+            Ok(None)
         }
 
         fn for_each<F: FnMut(&T), D: Direction>(
@@ -955,9 +1576,33 @@ mod node_variants {
             mut upper: Option<&[u8]>,
             lval: Option<&T::Key>,
             rval: Option<&T::Key>,
-            _dir: D,
+            dir: D,
         ) {
-            n416_foreach!(self, f, lower, upper, lval, rval, _dir, D::LEFT_TO_RIGHT)
+            debug_assert!(is_sorted(&self.node.keys[..self.children as usize]));
+            let low = advance_or(&mut lower, 0);
+            let high = advance_or(&mut upper, 255);
+            let children = self.children as usize;
+            do_foreach_dir!(D::LEFT_TO_RIGHT, 0..children, i, {
+                let k = self.node.keys[i] as usize;
+                if D::LEFT_TO_RIGHT {
+                    if k < low {
+                        continue;
+                    }
+                    if k > high {
+                        break;
+                    }
+                } else {
+                    if k > high {
+                        continue;
+                    }
+                    if k < low {
+                        break;
+                    }
+                }
+                let low = if k == low { lower } else { None };
+                let high = if k == high { upper } else { None };
+                visit_leaf(&self.node.ptrs[i], f, low, high, lval, rval, dir)
+            })
         }
     }
 
@@ -966,6 +1611,18 @@ mod node_variants {
         ptrs: [ChildPtr<T>; 48],
     }
 
+    impl<T> NodeBody for Node48<T> {
+        type Elt = T;
+        const CAPACITY: usize = 48;
+
+        fn empty() -> Self {
+            Node48 {
+                keys: [0; 256],
+                ptrs: std::array::from_fn(|_| ChildPtr::null()),
+            }
+        }
+    }
+
     impl<T> RawNode<Node48<T>> {
         unsafe fn get_min_inner(&self) -> Option<(usize, *mut ChildPtr<T>)> {
             for d in 0..256 {
@@ -1056,7 +1713,7 @@ mod node_variants {
                 self.get_min_inner()
                     .and_then(|(_, t)| match (*t).get().unwrap() {
                         Ok(t) => Some(t),
-                        Err(inner_node) => with_node!(inner_node, node, { node.get_min() }),
+                        Err(inner_node) => with_node!(inner_node, node, node.get_min()),
                     })
             }
         }
@@ -1084,7 +1741,7 @@ mod node_variants {
                         );
                         self.node.keys[ix] = 0; // not really necessary
                         DeleteResult::Singleton {
-                            deleted: deleted,
+                            deleted,
                             last: (*or_ptr).swap_null(),
                             last_d: ix as u8,
                         }
@@ -1095,48 +1752,31 @@ mod node_variants {
             }
         }
 
-        unsafe fn insert(
+        unsafe fn insert<A: NodeAllocator>(
             &mut self,
             d: u8,
             ptr: ChildPtr<T>,
             pptr: Option<*mut ChildPtr<T>>,
-        ) -> Result<(), ChildPtr<T>> {
+            alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>> {
             debug_assert!(Some(d) != T::Key::STOP_CHARACTER || ptr.get().unwrap().is_ok());
             debug_assert!(self.find_raw(d).is_none());
             self.state_valid();
             debug_assert!(self.children <= 48);
             if self.children == 48 {
-                if let Some(pp) = pptr {
-                    let new_node = &mut *Box::into_raw(Box::new(RawNode {
-                        typ: NODE_256,
-                        children: 48,
-                        count: self.count,
-                        consumed: self.consumed,
-                        prefix: self.prefix,
-                        node: Node256 {
-                            ptrs: mem::transmute::<[usize; 256], [ChildPtr<T>; 256]>(
-                                [0 as usize; 256],
-                            ),
-                        },
-                    }));
-                    for i in 0..256 {
-                        if let Some(node_ptr) = self.find_raw(i as u8) {
-                            debug_assert!(i != d as usize, "{:?} == {:?}", i, d);
-                            mem::swap(&mut *node_ptr, new_node.node.ptrs.get_unchecked_mut(i))
+                let pp = match pptr {
+                    Some(pp) => pp,
+                    None => return Err(ptr),
+                };
+                let fill = |old: &mut Node48<T>, new: &mut Node256<T>| {
+                    for (d, &ix) in old.keys.iter().enumerate() {
+                        if ix != 0 {
+                            mem::swap(&mut old.ptrs[ix as usize - 1], &mut new.ptrs[d]);
                         }
                     }
-                    #[cfg(debug_assertions)]
-                    {
-                        self.children = !0;
-                    }
-                    let new_cptr = ChildPtr::from_node(new_node);
-                    *pp = new_cptr;
-                    let res = new_node.insert(d, ptr, None);
-                    debug_assert!(res.is_ok());
-                    return Ok(());
-                } else {
-                    return Err(ptr);
-                }
+                };
+                let (new, old) = grow(self, NODE_256, fill, pp, alloc);
+                return new.insert(d, ptr, None, alloc).map(|_| Some(old));
             }
             for i in 0..48 {
                 let slot = self.node.ptrs.get_unchecked_mut(i);
@@ -1144,7 +1784,7 @@ mod node_variants {
                     ptr::write(slot, ptr);
                     self.node.keys[d as usize] = i as u8 + 1;
                     self.children += 1;
-                    return Ok(());
+                    return Ok(None);
                 }
             }
             unreachable!()
@@ -1182,6 +1822,17 @@ mod node_variants {
     pub struct Node256<T> {
         ptrs: [ChildPtr<T>; 256],
     }
+
+    impl<T> NodeBody for Node256<T> {
+        type Elt = T;
+        const CAPACITY: usize = 256;
+
+        fn empty() -> Self {
+            Node256 {
+                ptrs: std::array::from_fn(|_| ChildPtr::null()),
+            }
+        }
+    }
     impl<T> ::std::fmt::Debug for Node48<T> {
         fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
             write!(
@@ -1247,7 +1898,7 @@ mod node_variants {
                 }
                 return match unsafe { p.get().unwrap() } {
                     Ok(t) => Some(t),
-                    Err(inner_node) => with_node!(inner_node, node, { node.get_min() }),
+                    Err(inner_node) => with_node!(inner_node, node, node.get_min()),
                 };
             }
             unreachable!()
@@ -1274,7 +1925,7 @@ mod node_variants {
                     }
 
                     return DeleteResult::Singleton {
-                        deleted: deleted,
+                        deleted,
                         last: node.swap_null(),
                         last_d: i as u8,
                     };
@@ -1284,19 +1935,20 @@ mod node_variants {
             DeleteResult::Success(deleted)
         }
 
-        unsafe fn insert(
+        unsafe fn insert<A: NodeAllocator>(
             &mut self,
             d: u8,
             ptr: ChildPtr<T>,
             _p: Option<*mut ChildPtr<T>>,
-        ) -> Result<(), ChildPtr<T>> {
+            _alloc: &mut A,
+        ) -> Result<Option<ChildPtr<T>>, ChildPtr<T>> {
             debug_assert!(Some(d) != T::Key::STOP_CHARACTER || ptr.get().unwrap().is_ok());
             debug_assert!(self.find_raw(d).is_none(), "d={:?} IN {:?}", d, self);
             debug_assert!(self.children <= 256);
             debug_assert!(self.node.ptrs[d as usize].is_null());
             self.children += 1;
             ptr::write(self.node.ptrs.get_unchecked_mut(d as usize), ptr);
-            Ok(())
+            Ok(None)
         }
 
         fn for_each<F: FnMut(&T), D: Direction>(
@@ -0,0 +1,275 @@
+//! Compare a byte against each of a group of 16, as needed to search `Node16` keys and the
+//! control bytes of `SwissPrefixCache`, or of a group of 32 for `Node32` keys.
+//!
+//! This uses SSE2 on x86 and NEON on AArch64. Elsewhere, or with the `portable` feature, it
+//! falls back to SWAR ("SIMD within a register") arithmetic on a pair of `u64`s. Groups of 32
+//! are compared with AVX2 on x86 machines that have it, detected at runtime unless the target
+//! enables it, and as two groups of 16 otherwise.
+#[cfg(all(any(target_arch = "x86_64", target_arch = "x86"), not(feature = "portable")))]
+mod imp {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    // SSE2 is part of the x86-64 baseline, but 32-bit x86 targets may need to check for it at
+    // runtime.
+    macro_rules! dispatch {
+        ($sse2: ident, $swar: ident, $group: expr, $b: expr) => {{
+            #[cfg(target_feature = "sse2")]
+            {
+                unsafe { $sse2($group, $b) }
+            }
+            #[cfg(not(target_feature = "sse2"))]
+            {
+                if is_x86_feature_detected!("sse2") {
+                    unsafe { $sse2($group, $b) }
+                } else {
+                    super::swar::$swar($group, $b)
+                }
+            }
+        }};
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn match_byte_sse2(group: &[u8; 16], b: u8) -> u32 {
+        let ks = _mm_loadu_si128(group.as_ptr() as *const __m128i);
+        _mm_movemask_epi8(_mm_cmpeq_epi8(_mm_set1_epi8(b as i8), ks)) as u32
+    }
+
+    // SSE2 only has signed comparisons; flipping the top bits of both sides turns them into
+    // unsigned ones.
+    #[target_feature(enable = "sse2")]
+    unsafe fn match_greater_sse2(group: &[u8; 16], b: u8) -> u32 {
+        let flip = _mm_set1_epi8(-128i8);
+        let ks = _mm_xor_si128(_mm_loadu_si128(group.as_ptr() as *const __m128i), flip);
+        let bs = _mm_xor_si128(_mm_set1_epi8(b as i8), flip);
+        _mm_movemask_epi8(_mm_cmpgt_epi8(ks, bs)) as u32
+    }
+
+    pub fn match_byte(group: &[u8; 16], b: u8) -> u32 {
+        dispatch!(match_byte_sse2, match_byte, group, b)
+    }
+
+    pub fn match_greater(group: &[u8; 16], b: u8) -> u32 {
+        dispatch!(match_greater_sse2, match_greater, group, b)
+    }
+
+    macro_rules! dispatch_avx2 {
+        ($avx2: ident, $half: ident, $group: expr, $b: expr) => {{
+            #[cfg(target_feature = "avx2")]
+            {
+                unsafe { $avx2($group, $b) }
+            }
+            #[cfg(not(target_feature = "avx2"))]
+            {
+                if is_x86_feature_detected!("avx2") {
+                    unsafe { $avx2($group, $b) }
+                } else {
+                    super::split_match($half, $group, $b)
+                }
+            }
+        }};
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn match_byte_avx2(group: &[u8; 32], b: u8) -> u32 {
+        let ks = _mm256_loadu_si256(group.as_ptr() as *const __m256i);
+        _mm256_movemask_epi8(_mm256_cmpeq_epi8(_mm256_set1_epi8(b as i8), ks)) as u32
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn match_greater_avx2(group: &[u8; 32], b: u8) -> u32 {
+        let flip = _mm256_set1_epi8(-128i8);
+        let ks = _mm256_xor_si256(_mm256_loadu_si256(group.as_ptr() as *const __m256i), flip);
+        let bs = _mm256_xor_si256(_mm256_set1_epi8(b as i8), flip);
+        _mm256_movemask_epi8(_mm256_cmpgt_epi8(ks, bs)) as u32
+    }
+
+    pub fn match_byte32(group: &[u8; 32], b: u8) -> u32 {
+        dispatch_avx2!(match_byte_avx2, match_byte, group, b)
+    }
+
+    pub fn match_greater32(group: &[u8; 32], b: u8) -> u32 {
+        dispatch_avx2!(match_greater_avx2, match_greater, group, b)
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon", not(feature = "portable")))]
+mod imp {
+    use std::arch::aarch64::*;
+
+    // NEON has no equivalent of `_mm_movemask_epi8`: keep bit `i % 8` of each byte of the
+    // comparison, then add up each half.
+    unsafe fn movemask(comps: uint8x16_t) -> u32 {
+        const BITS: [u8; 16] = [1, 2, 4, 8, 16, 32, 64, 128, 1, 2, 4, 8, 16, 32, 64, 128];
+        let bits = vandq_u8(comps, vld1q_u8(BITS.as_ptr()));
+        let lo = vaddv_u8(vget_low_u8(bits)) as u32;
+        let hi = vaddv_u8(vget_high_u8(bits)) as u32;
+        lo | (hi << 8)
+    }
+
+    pub fn match_byte(group: &[u8; 16], b: u8) -> u32 {
+        unsafe { movemask(vceqq_u8(vld1q_u8(group.as_ptr()), vdupq_n_u8(b))) }
+    }
+
+    pub fn match_greater(group: &[u8; 16], b: u8) -> u32 {
+        unsafe { movemask(vcgtq_u8(vld1q_u8(group.as_ptr()), vdupq_n_u8(b))) }
+    }
+
+    pub fn match_byte32(group: &[u8; 32], b: u8) -> u32 {
+        super::split_match(match_byte, group, b)
+    }
+
+    pub fn match_greater32(group: &[u8; 32], b: u8) -> u32 {
+        super::split_match(match_greater, group, b)
+    }
+}
+
+#[cfg(not(any(all(any(target_arch = "x86_64", target_arch = "x86"),
+                  not(feature = "portable")),
+              all(target_arch = "aarch64", target_feature = "neon",
+                  not(feature = "portable")))))]
+use self::swar as imp;
+
+#[cfg(not(any(all(any(target_arch = "x86_64", target_arch = "x86"),
+                  target_feature = "sse2", not(feature = "portable")),
+              all(target_arch = "aarch64", target_feature = "neon",
+                  not(feature = "portable")))))]
+mod swar {
+    use super::super::byteorder::{ByteOrder, LittleEndian};
+
+    const LO: u64 = 0x0101_0101_0101_0101;
+    const HI: u64 = 0x8080_8080_8080_8080;
+
+    // Gather the high bit of each byte of `x` into the low 8 bits of the result. The shifted
+    // bits are 8 apart and the multiplier's are 7 apart, so the products do not overlap.
+    fn movemask(x: u64) -> u32 {
+        (((x & HI) >> 7).wrapping_mul(0x0102_0408_1020_4080) >> 56) as u32
+    }
+
+    // Set the high bit of each byte of `x` that is zero. The addition cannot carry between
+    // bytes, so unlike the usual `(x - LO) & !x & HI` this has no false positives.
+    fn zero_bytes(x: u64) -> u64 {
+        !(((x & !HI) + !HI) | x) & HI
+    }
+
+    // Set the high bit of each byte of `x` that is greater than the same byte of `y`. Compare
+    // the low 7 bits with a subtraction that cannot borrow between bytes, then use the high bits
+    // themselves where they differ.
+    fn greater_bytes(x: u64, y: u64) -> u64 {
+        let gt7 = (x | HI) - ((y & !HI) + LO);
+        let diff = x ^ y;
+        ((diff & x) | (!diff & gt7)) & HI
+    }
+
+    fn halves(group: &[u8; 16]) -> (u64, u64) {
+        (
+            LittleEndian::read_u64(&group[0..8]),
+            LittleEndian::read_u64(&group[8..16]),
+        )
+    }
+
+    pub fn match_byte(group: &[u8; 16], b: u8) -> u32 {
+        let (lo, hi) = halves(group);
+        let bs = LO * b as u64;
+        movemask(zero_bytes(lo ^ bs)) | (movemask(zero_bytes(hi ^ bs)) << 8)
+    }
+
+    pub fn match_greater(group: &[u8; 16], b: u8) -> u32 {
+        let (lo, hi) = halves(group);
+        let bs = LO * b as u64;
+        movemask(greater_bytes(lo, bs)) | (movemask(greater_bytes(hi, bs)) << 8)
+    }
+
+    pub fn match_byte32(group: &[u8; 32], b: u8) -> u32 {
+        super::split_match(match_byte, group, b)
+    }
+
+    pub fn match_greater32(group: &[u8; 32], b: u8) -> u32 {
+        super::split_match(match_greater, group, b)
+    }
+}
+
+/// Compare `b` against each half of `group` with `f`, and combine the results.
+#[allow(dead_code)]
+fn split_match<F: Fn(&[u8; 16], u8) -> u32>(f: F, group: &[u8; 32], b: u8) -> u32 {
+    let (lo, hi) = group.split_at(16);
+    let half = |h: &[u8]| unsafe { &*(h.as_ptr() as *const [u8; 16]) };
+    f(half(lo), b) | (f(half(hi), b) << 16)
+}
+
+/// Return a bitmask with bit `i` set if `group[i] == b`.
+#[inline]
+pub fn match_byte(group: &[u8; 16], b: u8) -> u32 {
+    imp::match_byte(group, b)
+}
+
+/// Return a bitmask with bit `i` set if `group[i] > b`.
+#[inline]
+pub fn match_greater(group: &[u8; 16], b: u8) -> u32 {
+    imp::match_greater(group, b)
+}
+
+/// Like `match_byte`, for a group of 32.
+#[inline]
+pub fn match_byte32(group: &[u8; 32], b: u8) -> u32 {
+    imp::match_byte32(group, b)
+}
+
+/// Like `match_greater`, for a group of 32.
+#[inline]
+pub fn match_greater32(group: &[u8; 32], b: u8) -> u32 {
+    imp::match_greater32(group, b)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+    use super::*;
+    use self::rand::{Rng, SeedableRng, StdRng};
+
+    fn mask_where<F: Fn(u8) -> bool>(group: &[u8], f: F) -> u32 {
+        group
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, c)| if f(*c) { acc | (1 << i) } else { acc })
+    }
+
+    #[test]
+    fn match_boundaries() {
+        let group = [0, 1, 0x7f, 0x80, 0x81, 0xfe, 0xff, 0, 0x80, 0x7f, 1, 0xff, 2, 3, 0x40, 0xc0];
+        for b in 0..=255u8 {
+            assert_eq!(match_byte(&group, b), mask_where(&group, |c| c == b));
+            assert_eq!(match_greater(&group, b), mask_where(&group, |c| c > b));
+        }
+    }
+
+    #[test]
+    fn match_random_groups() {
+        let mut rng = StdRng::from_seed(&[1][..]);
+        for _ in 0..10_000 {
+            let mut group = [0; 16];
+            rng.fill_bytes(&mut group[..]);
+            // Pick `b` from the group most of the time, so that there are matches.
+            let b = if rng.gen_weighted_bool(4) { rng.gen() } else { group[rng.gen_range(0, 16)] };
+            assert_eq!(match_byte(&group, b), mask_where(&group, |c| c == b));
+            assert_eq!(match_greater(&group, b), mask_where(&group, |c| c > b));
+        }
+    }
+
+    #[test]
+    fn match_random_groups32() {
+        let mut rng = StdRng::from_seed(&[2][..]);
+        for _ in 0..10_000 {
+            let mut group = [0; 32];
+            rng.fill_bytes(&mut group[..]);
+            let b = if rng.gen_weighted_bool(4) { rng.gen() } else { group[rng.gen_range(0, 32)] };
+            assert_eq!(match_byte32(&group, b), mask_where(&group, |c| c == b));
+            assert_eq!(match_greater32(&group, b), mask_where(&group, |c| c > b));
+        }
+        let group = [0x80; 32];
+        assert_eq!(match_greater32(&group, 0x7f), !0);
+        assert_eq!(match_byte32(&group, 0x80), !0);
+    }
+}
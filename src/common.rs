@@ -16,6 +16,8 @@ pub trait Digital<'a> {
     // TODO: consider providing a more efficient interface here (e.g. passing a slice directly)
     type I: Iterator<Item = u8> + 'a;
     const STOP_CHARACTER: Option<u8> = None;
+    /// Whether `ArtElement`s holding this type may be stored inline (see `Element::INLINE`).
+    const INLINE: bool = false;
     fn digits(&'a self) -> Self::I;
 }
 
@@ -43,6 +45,7 @@ impl Iterator for U32BytesIterator {
 
 impl<'a> Digital<'a> for u32 {
     type I = U32BytesIterator;
+    const INLINE: bool = true;
     fn digits(&self) -> U32BytesIterator {
         let mut res = U32BytesIterator {
             cursor: 0,
@@ -55,6 +58,7 @@ impl<'a> Digital<'a> for u32 {
 
 impl<'a> Digital<'a> for i32 {
     type I = U32BytesIterator;
+    const INLINE: bool = true;
     fn digits(&self) -> U32BytesIterator {
         let mut res = U32BytesIterator {
             cursor: 0,
@@ -150,7 +154,7 @@ pub struct NullTerminate<I> {
 
 impl<I> NullTerminate<I> {
     fn new(i: I) -> Self {
-        NullTerminate { done: false, i: i }
+        NullTerminate { done: false, i }
     }
 }
 
@@ -180,9 +184,14 @@ impl<I: Iterator<Item = u8>> Iterator for NullTerminate<I> {
             _max,
             "must use iterator with exact length for NullTerminate"
         );
-        if n + 1 == remaining {
+        if n >= remaining {
+            // skip past the underlying bytes, possibly landing on the terminator.
             self.done = true;
-            Some(0)
+            if n == remaining {
+                Some(0)
+            } else {
+                None
+            }
         } else {
             self.i.nth(n).map(|x| x + 1)
         }
@@ -208,6 +217,7 @@ impl<'a> Digital<'a> for String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cmp;
 
     fn test_digits_obey_order<D: for<'a> Digital<'a> + PartialOrd>(x: D, y: D) -> bool {
         let vx: Vec<_> = x.digits().collect();
@@ -249,5 +259,13 @@ mod tests {
         fn digits_usize(x: usize, y: usize) -> bool {
             test_digits_obey_order(x.wrapping_shl(20), y.wrapping_shl(20))
         }
+
+        fn digits_strings_skip(x: String, n: usize) -> bool {
+            // `skip` is implemented in terms of `nth`, which NullTerminate overrides.
+            let n = n % (x.len() + 2);
+            let all: Vec<u8> = x.digits().collect();
+            let skipped: Vec<u8> = x.digits().skip(n).collect();
+            all[cmp::min(n, all.len())..] == skipped[..]
+        }
     }
 }
@@ -0,0 +1,333 @@
+//! Membership filters that sit in front of a `RawART` and answer lookups for absent keys without
+//! descending the tree. See `RawART::enable_bloom_filter` and `RawART::enable_xor_filter`.
+extern crate fnv;
+
+use std::cmp;
+use std::hash::Hasher;
+
+use self::fnv::FnvHasher;
+
+/// Statistics about a tree's membership filter, as returned by `RawART::filter_stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    /// The number of lookups that consulted the filter.
+    pub queries: usize,
+    /// The number of lookups that the filter answered on its own, because it ruled the key out.
+    pub negatives: usize,
+    /// The number of lookups for absent keys that the filter did not rule out.
+    pub false_positives: usize,
+    /// The heap memory used by the filter, in bytes.
+    pub memory: usize,
+}
+
+impl FilterStats {
+    /// The fraction of lookups for absent keys that the filter failed to rule out.
+    pub fn false_positive_rate(&self) -> f64 {
+        let absent = self.negatives + self.false_positives;
+        if absent == 0 {
+            0.0
+        } else {
+            self.false_positives as f64 / absent as f64
+        }
+    }
+}
+
+/// Hash the digits of a key for use with a `KeyFilter`.
+pub fn hash_digits(digits: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(digits);
+    mix(hasher.finish())
+}
+
+// The finalizer from MurmurHash3. The filters take several indices from different bits of one
+// hash, and FNV's high bits are poorly mixed for short keys.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+// Map a 32-bit hash to `[0, n)` without a division.
+fn reduce(h: u32, n: usize) -> usize {
+    ((h as u64 * n as u64) >> 32) as usize
+}
+
+pub enum KeyFilter {
+    Bloom(CountingBloomFilter),
+    Xor(XorFilter),
+}
+
+impl KeyFilter {
+    pub fn may_contain(&self, hash: u64) -> bool {
+        match *self {
+            KeyFilter::Bloom(ref f) => f.may_contain(hash),
+            KeyFilter::Xor(ref f) => f.may_contain(hash),
+        }
+    }
+
+    pub fn memory(&self) -> usize {
+        match *self {
+            KeyFilter::Bloom(ref f) => f.counters.capacity(),
+            KeyFilter::Xor(ref f) => f.fingerprints.capacity(),
+        }
+    }
+}
+
+/// A Bloom filter with a 4-bit counter in place of each bit, so that keys can be removed as well
+/// as added.
+///
+/// A counter that reaches 15 stays there: we no longer know how many keys it counts, so removing
+/// any of them must not take it to zero.
+pub struct CountingBloomFilter {
+    // Two counters per byte, the even-numbered one in the low nibble.
+    counters: Vec<u8>,
+    hashes: u32,
+}
+
+const BLOOM_COUNTERS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+const COUNTER_MAX: u8 = 15;
+
+impl CountingBloomFilter {
+    /// Create a filter sized for `expected_len` keys. With 10 counters per key and 7 hash
+    /// functions the false positive rate at that size is just under 1%; it degrades gracefully
+    /// beyond it.
+    pub fn with_capacity(expected_len: usize) -> Self {
+        let slots = cmp::max(64, expected_len * BLOOM_COUNTERS_PER_KEY);
+        CountingBloomFilter {
+            counters: vec![0; slots.div_ceil(2)],
+            hashes: BLOOM_HASHES,
+        }
+    }
+
+    fn slots(&self) -> usize {
+        self.counters.len() * 2
+    }
+
+    // The `i`th index for `hash`, using double hashing.
+    fn index(&self, hash: u64, i: u32) -> usize {
+        let h1 = hash as u32;
+        let h2 = (hash >> 32) as u32 | 1;
+        reduce(h1.wrapping_add(i.wrapping_mul(h2)), self.slots())
+    }
+
+    fn get(&self, i: usize) -> u8 {
+        (self.counters[i / 2] >> (4 * (i & 1))) & 0xf
+    }
+
+    fn set(&mut self, i: usize, c: u8) {
+        let shift = 4 * (i & 1);
+        let b = &mut self.counters[i / 2];
+        *b = (*b & !(0xf << shift)) | (c << shift);
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        for i in 0..self.hashes {
+            let ix = self.index(hash, i);
+            let c = self.get(ix);
+            if c < COUNTER_MAX {
+                self.set(ix, c + 1);
+            }
+        }
+    }
+
+    /// Remove a key that was previously inserted.
+    pub fn remove(&mut self, hash: u64) {
+        for i in 0..self.hashes {
+            let ix = self.index(hash, i);
+            let c = self.get(ix);
+            debug_assert!(c > 0, "removing a key that is not in the filter");
+            if c > 0 && c < COUNTER_MAX {
+                self.set(ix, c - 1);
+            }
+        }
+    }
+
+    pub fn may_contain(&self, hash: u64) -> bool {
+        (0..self.hashes).all(|i| self.get(self.index(hash, i)) != 0)
+    }
+}
+
+/// An xor filter with 8-bit fingerprints (Graf and Lemire, "Xor Filters: Faster and Smaller
+/// Than Bloom and Cuckoo Filters", 2020).
+///
+/// These use about 9.8 bits per key for a false positive rate of 1/256, but they are built from
+/// the full set of keys at once and cannot be modified afterwards.
+pub struct XorFilter {
+    seed: u64,
+    block_len: usize,
+    fingerprints: Vec<u8>,
+}
+
+impl XorFilter {
+    /// Build a filter containing the keys with hashes `hashes`. Duplicate hashes are removed.
+    pub fn build(hashes: &mut Vec<u64>) -> Self {
+        hashes.sort();
+        hashes.dedup();
+        let n = hashes.len();
+        let block_len = (32 + (1.23 * n as f64).ceil() as usize) / 3;
+        let size = block_len * 3;
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut counts = vec![0u32; size];
+        let mut xors = vec![0u64; size];
+        let mut queue = Vec::new();
+        let mut stack = Vec::with_capacity(n);
+        loop {
+            let mut res = XorFilter {
+                seed,
+                block_len,
+                fingerprints: Vec::new(),
+            };
+            for c in counts.iter_mut() {
+                *c = 0;
+            }
+            for x in xors.iter_mut() {
+                *x = 0;
+            }
+            for &h in hashes.iter() {
+                let k = res.key(h);
+                for &ix in res.indices(k).iter() {
+                    counts[ix] += 1;
+                    xors[ix] ^= k;
+                }
+            }
+            // Repeatedly remove keys that are alone in one of their slots; that slot is where
+            // their fingerprint goes.
+            queue.clear();
+            queue.extend((0..size).filter(|&i| counts[i] == 1));
+            stack.clear();
+            while let Some(i) = queue.pop() {
+                if counts[i] != 1 {
+                    continue;
+                }
+                let k = xors[i];
+                stack.push((k, i));
+                for &ix in res.indices(k).iter() {
+                    counts[ix] -= 1;
+                    xors[ix] ^= k;
+                    if counts[ix] == 1 {
+                        queue.push(ix);
+                    }
+                }
+            }
+            if stack.len() == n {
+                res.fingerprints = vec![0; size];
+                for &(k, i) in stack.iter().rev() {
+                    let [a, b, c] = res.indices(k);
+                    // `fingerprints[i]` is still zero, so it does not matter that it is one of
+                    // `a`, `b` and `c`.
+                    res.fingerprints[i] = fingerprint(k) ^ res.fingerprints[a]
+                        ^ res.fingerprints[b]
+                        ^ res.fingerprints[c];
+                }
+                return res;
+            }
+            // The keys formed a cycle; try again with a different mapping to slots.
+            seed = mix(seed.wrapping_add(0x9e37_79b9_7f4a_7c15));
+        }
+    }
+
+    fn key(&self, hash: u64) -> u64 {
+        mix(hash ^ self.seed)
+    }
+
+    fn indices(&self, k: u64) -> [usize; 3] {
+        let bl = self.block_len;
+        [
+            reduce(k as u32, bl),
+            bl + reduce(k.rotate_left(21) as u32, bl),
+            2 * bl + reduce(k.rotate_left(42) as u32, bl),
+        ]
+    }
+
+    pub fn may_contain(&self, hash: u64) -> bool {
+        let k = self.key(hash);
+        let [a, b, c] = self.indices(k);
+        let fps = &self.fingerprints;
+        fingerprint(k) == fps[a] ^ fps[b] ^ fps[c]
+    }
+
+    #[cfg(test)]
+    fn bits_per_key(&self, n: usize) -> f64 {
+        (self.fingerprints.len() * 8) as f64 / n as f64
+    }
+}
+
+fn fingerprint(k: u64) -> u8 {
+    (k ^ (k >> 32)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+    use super::*;
+    use self::rand::{Rng, SeedableRng, StdRng};
+
+    fn random_hashes(n: usize, seed: usize) -> Vec<u64> {
+        let mut rng = StdRng::from_seed(&[seed][..]);
+        (0..n).map(|_| rng.gen::<u64>()).collect()
+    }
+
+    fn false_positive_rate<F: Fn(u64) -> bool>(f: F) -> f64 {
+        let probes = random_hashes(1 << 16, 2);
+        probes.iter().filter(|&&h| f(h)).count() as f64 / probes.len() as f64
+    }
+
+    #[test]
+    fn bloom_filter_insert_remove() {
+        const N: usize = 10_000;
+        let hashes = random_hashes(N, 1);
+        let mut f = CountingBloomFilter::with_capacity(N);
+        for &h in hashes.iter() {
+            f.insert(h);
+        }
+        for &h in hashes.iter() {
+            assert!(f.may_contain(h));
+        }
+        let rate = false_positive_rate(|h| f.may_contain(h));
+        assert!(rate < 0.02, "false positive rate {}", rate);
+        for &h in hashes[0..N / 2].iter() {
+            f.remove(h);
+        }
+        for &h in hashes[N / 2..].iter() {
+            assert!(f.may_contain(h));
+        }
+        let rate = false_positive_rate(|h| f.may_contain(h));
+        assert!(rate < 0.005, "false positive rate {}", rate);
+        for &h in hashes[N / 2..].iter() {
+            f.remove(h);
+        }
+        assert!(hashes.iter().all(|&h| !f.may_contain(h)));
+    }
+
+    #[test]
+    fn bloom_filter_saturates() {
+        let mut f = CountingBloomFilter::with_capacity(1);
+        for _ in 0..20 {
+            f.insert(7);
+        }
+        f.remove(7);
+        assert!(f.may_contain(7));
+    }
+
+    #[test]
+    fn xor_filter_build() {
+        for &n in [0, 1, 2, 100, 100_000].iter() {
+            let mut hashes = random_hashes(n, 3);
+            // Duplicates must not make construction fail.
+            let extra = hashes.iter().take(n / 2).cloned().collect::<Vec<_>>();
+            hashes.extend(extra);
+            let f = XorFilter::build(&mut hashes.clone());
+            for &h in hashes.iter() {
+                assert!(f.may_contain(h));
+            }
+            if n >= 100_000 {
+                assert!(f.bits_per_key(n) < 10.0);
+                let rate = false_positive_rate(|h| f.may_contain(h));
+                assert!(rate < 0.006, "false positive rate {}", rate);
+            }
+        }
+    }
+}
@@ -1,11 +1,13 @@
-#![feature(swap_nonoverlapping)]
-#![feature(cfg_target_feature)]
-#![feature(stdsimd)]
 #[macro_use]
 mod macros;
 mod common;
+mod aggregate;
 mod art_impl;
 mod art_internal;
+mod byte_match;
+mod filter;
+mod multi_map;
+mod node_alloc;
 mod prefix_cache;
 
 extern crate byteorder;
@@ -13,6 +15,8 @@ extern crate smallvec;
 
 pub use common::Digital;
 pub use art_impl::*;
+pub use aggregate::{AggregatingARTMap, Max, Min, Monoid, Sum};
+pub use multi_map::{ARTMultiMap, MultiEntry};
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
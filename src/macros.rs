@@ -1,35 +1,104 @@
-macro_rules! with_node_inner {
-    ($base_node: expr, $nod: ident, $body: expr, $r: tt) => {
-        with_node_inner!($base_node, $nod, $body, $r, _)
+/// Invoke `$m!` with `$args`, followed by the kinds of inner nodes in increasing order of
+/// capacity, along with their body types and the names of their fields in `MemoryReport` and
+/// `ShrinkThresholds`. `with_node!` and `with_node_mut!` dispatch on this list, and the macros
+/// below derive the rest of the per-kind code from it; see `NodeType` for what else a new kind
+/// of node needs.
+macro_rules! node_kinds {
+    ($m: ident, $( $args: tt )*) => {
+        $m!(
+            $( $args )*;
+            NODE_4 => Node4 (node4),
+            NODE_16 => Node16 (node16),
+            NODE_32 => Node32 (node32),
+            NODE_48 => Node48 (node48),
+            NODE_256 => Node256 (node256)
+        )
     };
-    ($base_node: expr, $nod: ident, $body: expr, $r: tt, $ty: tt) => {{
+}
+
+macro_rules! match_node_kind {
+    ($base_node: expr, $nod: ident, $body: expr, $r: tt, $ty: tt;
+     $( $kind: ident => $node: ident ($field: ident) ),+) => {{
         let _b: $r<()> = $base_node;
-        match _b.typ {
-            NODE_4 => {
-                #[allow(unused_unsafe)]
-                let $nod = unsafe { mem::transmute::<$r<_>, $r<Node4<$ty>>>(_b) };
-                $body
-            }
-            NODE_16 => {
-                #[allow(unused_unsafe)]
-                let $nod = unsafe { mem::transmute::<$r<_>, $r<Node16<$ty>>>(_b) };
-                $body
-            }
-            NODE_48 => {
-                #[allow(unused_unsafe)]
-                let $nod = unsafe { mem::transmute::<$r<_>, $r<Node48<$ty>>>(_b) };
-                $body
-            }
-            NODE_256 => {
-                #[allow(unused_unsafe)]
-                let $nod = unsafe { mem::transmute::<$r<_>, $r<Node256<$ty>>>(_b) };
-                $body
-            }
+        match _b.typ.kind() {
+            $(
+                $kind => {
+                    #[allow(unused_unsafe)]
+                    let $nod: $r<$node<$ty>> = unsafe { cast_node!($r, _b) };
+                    $body
+                }
+            )+
             _ => panic!("Found unrecognized node type {:?}", _b.typ),
         }
     }};
 }
 
+/// Turn the reference `$b` to a node whose kind has been erased into a reference of type `$r` to
+/// the node of its kind. `$b` only covers the node's header, so the result is made from its
+/// address with the provenance of the node's allocation (see `RawNode::in_allocation`). This is
+/// a macro rather than a function so that `$b` is not a protected argument when the new
+/// reference replaces it.
+macro_rules! cast_node {
+    (RawRef, $b: expr) => {
+        &*::std::ptr::with_exposed_provenance(($b as *const RawNode<()>).addr())
+    };
+    (RawMutRef, $b: expr) => {
+        &mut *::std::ptr::with_exposed_provenance_mut(($b as *mut RawNode<()>).addr())
+    };
+}
+
+/// The smallest kind of node with room for `$children` children.
+macro_rules! smallest_kind {
+    ($children: expr; $( $kind: ident => $node: ident ($field: ident) ),+) => {{
+        let _c: usize = $children;
+        $( if _c <= <$node<()> as NodeBody>::CAPACITY { $kind } else )+ {
+            panic!("No node has room for {} children", _c)
+        }
+    }};
+}
+
+/// An empty node of type `$typ`, with the header of `$old` (see `empty_like`).
+macro_rules! empty_of_kind {
+    ($typ: expr, $old: expr, $alloc: expr, $t: ty;
+     $( $kind: ident => $node: ident ($field: ident) ),+) => {
+        match $typ.kind() {
+            $( $kind => empty_like($old, $typ, $node::<$t>::empty(), $alloc), )+
+            _ => panic!("Found unrecognized node type {:?}", $typ),
+        }
+    };
+}
+
+/// The field of `$s` for the kind of the node type `$typ`.
+macro_rules! kind_field {
+    ($s: expr, $typ: expr; $( $kind: ident => $node: ident ($field: ident) ),+) => {
+        match $typ.kind() {
+            $( $kind => &mut $s.$field, )+
+            _ => panic!("Found unrecognized node type {:?}", $typ),
+        }
+    };
+}
+
+/// Like `kind_field!`, for the fields of a `ShrinkThresholds`. The smallest kind has nothing to
+/// shrink to, and so no field: its threshold is 0.
+macro_rules! shrink_threshold {
+    ($s: expr, $typ: expr; $first: ident => $first_node: ident ($first_field: ident),
+     $( $kind: ident => $node: ident ($field: ident) ),+) => {
+        match $typ.kind() {
+            $( $kind => $s.$field, )+
+            _ => 0,
+        }
+    };
+}
+
+macro_rules! with_node_inner {
+    ($base_node: expr, $nod: ident, $body: expr, $r: tt) => {
+        with_node_inner!($base_node, $nod, $body, $r, _)
+    };
+    ($base_node: expr, $nod: ident, $body: expr, $r: tt, $ty: tt) => {
+        node_kinds!(match_node_kind, $base_node, $nod, $body, $r, $ty)
+    };
+}
+
 macro_rules! with_node_mut {
     ($base_node: expr, $nod: ident, $body: expr) => {
         with_node_mut!($base_node, $nod, $body, _)
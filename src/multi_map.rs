@@ -0,0 +1,230 @@
+//! An ordered multimap built on top of `RawART`.
+use std::borrow::Borrow;
+use std::mem;
+use std::slice;
+
+use super::Digital;
+use super::art_impl::RawART;
+use super::art_internal::{Decreasing, Direction, Element, Increasing};
+use super::prefix_cache::NullBuckets;
+use super::smallvec::SmallVec;
+
+/// The leaf type of an `ARTMultiMap`: a key along with all of the values inserted for it, in
+/// insertion order.
+pub struct MultiEntry<K: for<'a> Digital<'a> + PartialOrd, V> {
+    key: K,
+    // Most keys only see a single value, so we store one inline to avoid an extra allocation.
+    vals: SmallVec<[V; 1]>,
+}
+
+impl<K: for<'a> Digital<'a> + PartialOrd, V> Element for MultiEntry<K, V> {
+    type Key = K;
+    type Value = SmallVec<[V; 1]>;
+    fn key(&self) -> &K {
+        &self.key
+    }
+
+    fn value_mut(&mut self) -> &mut SmallVec<[V; 1]> {
+        &mut self.vals
+    }
+
+    fn matches(&self, k: &Self::Key) -> bool {
+        *k == self.key
+    }
+
+    fn replace_matching(&mut self, other: &mut MultiEntry<K, V>) {
+        debug_assert!(self.matches(other.key()));
+        mem::swap(self, other);
+    }
+}
+
+/// An ordered map that can hold multiple values for the same key.
+///
+/// Values for a given key are kept in insertion order: `get_all` and the range traversals yield
+/// them oldest-first, and `remove_one` removes the newest value.
+pub struct ARTMultiMap<K: for<'a> Digital<'a> + PartialOrd, V> {
+    entries: RawART<MultiEntry<K, V>, NullBuckets<MultiEntry<K, V>>>,
+    len: usize,
+}
+
+impl<K: for<'a> Digital<'a> + PartialOrd, V> Default for ARTMultiMap<K, V> {
+    fn default() -> Self {
+        ARTMultiMap::new()
+    }
+}
+
+impl<K: for<'a> Digital<'a> + PartialOrd, V> ARTMultiMap<K, V> {
+    pub fn new() -> Self {
+        ARTMultiMap {
+            entries: RawART::new(),
+            len: 0,
+        }
+    }
+
+    /// The total number of values in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of distinct keys in the map.
+    pub fn num_keys(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        self.entries.contains(key)
+    }
+
+    /// Add `v` to the values stored for `k`, after any values already present.
+    pub fn insert(&mut self, k: K, v: V) {
+        self.len += 1;
+        if let Some(vals) = self.entries.get_mut(&k) {
+            vals.push(v);
+            return;
+        }
+        let mut vals = SmallVec::new();
+        vals.push(v);
+        let _prev = self.entries.insert(MultiEntry { key: k, vals });
+        debug_assert!(_prev.is_none());
+    }
+
+    /// Iterate over all values stored for `key`, in insertion order.
+    pub fn get_all<Q>(&self, key: &Q) -> slice::Iter<'_, V>
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        match self.entries.get(key) {
+            Some(entry) => entry.vals.iter(),
+            None => [].iter(),
+        }
+    }
+
+    /// Remove the newest value stored for `key`, if there is one.
+    pub fn remove_one<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        let (res, now_empty) = match self.entries.get_mut(key) {
+            None => return None,
+            Some(vals) => {
+                debug_assert!(!vals.is_empty());
+                (vals.pop().unwrap(), vals.is_empty())
+            }
+        };
+        if now_empty {
+            let _removed = self.entries.remove(key);
+            debug_assert!(_removed);
+        }
+        self.len -= 1;
+        Some(res)
+    }
+
+    /// Remove all values stored for `key`, returning them in insertion order.
+    pub fn remove_all<Q>(&mut self, key: &Q) -> Vec<V>
+    where
+        Q: Borrow<K> + ?Sized,
+    {
+        match self.entries.remove_element(key) {
+            Some(entry) => {
+                self.len -= entry.vals.len();
+                entry.vals.into_vec()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Call `f` on every key-value pair with a key in `[lower_bound, upper_bound)`, in increasing
+    /// order of keys. Values with the same key are visited in insertion order.
+    pub fn for_each_range<F: FnMut(&K, &V)>(
+        &self,
+        f: F,
+        lower_bound: Option<&K>,
+        upper_bound: Option<&K>,
+    ) {
+        self.for_each_range_dir(f, lower_bound, upper_bound, Increasing);
+    }
+
+    /// Like `for_each_range`, but visits keys in decreasing order. Values with the same key are
+    /// still visited in insertion order.
+    pub fn for_each_range_rev<F: FnMut(&K, &V)>(
+        &self,
+        f: F,
+        lower_bound: Option<&K>,
+        upper_bound: Option<&K>,
+    ) {
+        self.for_each_range_dir(f, lower_bound, upper_bound, Decreasing);
+    }
+
+    fn for_each_range_dir<F: FnMut(&K, &V), D: Direction>(
+        &self,
+        mut f: F,
+        lower_bound: Option<&K>,
+        upper_bound: Option<&K>,
+        _dir: D,
+    ) {
+        self.entries.visit_range(
+            |e: &MultiEntry<K, V>| {
+                for v in e.vals.iter() {
+                    f(&e.key, v)
+                }
+            },
+            lower_bound,
+            upper_bound,
+            _dir,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multimap_behavior() {
+        let mut m = ARTMultiMap::<u64, usize>::new();
+        // timestamps with plenty of collisions
+        for i in 0..1024 {
+            m.insert((i % 100) as u64 * 1000, i);
+        }
+        assert_eq!(m.len(), 1024);
+        assert_eq!(m.num_keys(), 100);
+        let vs: Vec<usize> = m.get_all(&5000).cloned().collect();
+        assert_eq!(vs, (0..1024).filter(|i| i % 100 == 5).collect::<Vec<_>>());
+        assert_eq!(m.get_all(&5001).count(), 0);
+
+        let mut pairs = Vec::new();
+        m.for_each_range(|k, v| pairs.push((*k, *v)), Some(&2000), Some(&4000));
+        let mut expected = Vec::new();
+        for k in 2..4 {
+            for i in (0..1024).filter(|i| i % 100 == k) {
+                expected.push((k as u64 * 1000, i));
+            }
+        }
+        assert_eq!(pairs, expected);
+
+        assert_eq!(m.remove_one(&5000), Some(1005));
+        assert_eq!(m.remove_one(&5000), Some(905));
+        assert_eq!(m.len(), 1022);
+        let rest = m.remove_all(&5000);
+        assert_eq!(rest.len(), 9);
+        assert_eq!(rest[0], 5);
+        assert_eq!(rest[8], 805);
+        assert!(!m.contains_key(&5000));
+        assert_eq!(m.remove_one(&5000), None);
+        assert_eq!(m.len(), 1013);
+        assert_eq!(m.num_keys(), 99);
+
+        // removing the last value for a key removes the key
+        m.insert(7, 1);
+        assert!(m.contains_key(&7));
+        assert_eq!(m.remove_one(&7), Some(1));
+        assert!(!m.contains_key(&7));
+    }
+}
@@ -0,0 +1,354 @@
+//! Allocators for the inner nodes and leaves of a `RawART`.
+//!
+//! Every inner node and every leaf of a tree is a separate allocation, and trees that see a lot
+//! of insertions and deletions allocate and free them constantly. By default trees get their
+//! memory from a `SlabAllocator`, which keeps a free list for each size of allocation and hands
+//! out memory from large chunks; `Global` uses the global allocator directly.
+//!
+//! Other allocators (an arena, or a NUMA-local allocator) can be plugged in by implementing
+//! `NodeAllocator`, or wrapped with `FromGlobalAlloc` if they implement `GlobalAlloc`. A slab can
+//! take its chunks from any of them, and a `&mut` reference to an allocator is an allocator too,
+//! so that several trees can share one.
+use std::alloc::{self, GlobalAlloc, Layout};
+use std::cmp;
+use std::mem;
+use std::ptr;
+
+/// Allocates memory for the inner nodes and leaves of a `RawART`.
+///
+/// A tree frees every allocation through the allocator that made it, with the same layout,
+/// before the allocator is dropped. The only exception is for allocators that set
+/// `FREES_ON_DROP`.
+///
+/// # Safety
+///
+/// `alloc` must return memory that is valid for `layout` and does not overlap any other live
+/// allocation. It stays valid until it is passed to `dealloc`, or until the allocator is dropped.
+pub unsafe trait NodeAllocator {
+    /// If true, dropping the allocator frees all of the memory it allocated. Trees whose elements
+    /// do not need to be dropped then skip walking their nodes when they are dropped.
+    const FREES_ON_DROP: bool = false;
+
+    /// If true, memory freed with `dealloc_node` is only handed out again by `alloc_node`, and it
+    /// stays allocated until the allocator is dropped. `TaggedPrefixCache` relies on this to read
+    /// the header of a node that may have been freed, so trees using it require such an
+    /// allocator.
+    const KEEPS_NODES: bool = false;
+
+    /// Allocate memory for `layout`. This never returns null: running out of memory is handled by
+    /// `std::alloc::handle_alloc_error`.
+    ///
+    /// # Safety
+    ///
+    /// `layout` must have a nonzero size.
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+
+    /// Free an allocation.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc` on this allocator with the same `layout`, and not
+    /// freed since.
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// Allocate memory for an inner node. This is the same as `alloc` unless the allocator sets
+    /// `KEEPS_NODES`.
+    ///
+    /// # Safety
+    ///
+    /// As for `alloc`.
+    unsafe fn alloc_node(&mut self, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+
+    /// Free an inner node allocated with `alloc_node`.
+    ///
+    /// # Safety
+    ///
+    /// As for `dealloc`, with `alloc_node` in place of `alloc`.
+    unsafe fn dealloc_node(&mut self, ptr: *mut u8, layout: Layout) {
+        self.dealloc(ptr, layout)
+    }
+}
+
+/// Allocates each node and leaf from the global allocator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Global;
+
+unsafe impl NodeAllocator for Global {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let res = alloc::alloc(layout);
+        if res.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        res
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        alloc::dealloc(ptr, layout)
+    }
+}
+
+/// Allocates each node and leaf from a `GlobalAlloc`, such as `std::alloc::System` or the
+/// allocator of a crate like `jemallocator`, without making it the global allocator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FromGlobalAlloc<G>(pub G);
+
+unsafe impl<G: GlobalAlloc> NodeAllocator for FromGlobalAlloc<G> {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let res = self.0.alloc(layout);
+        if res.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        res
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout)
+    }
+}
+
+// The referenced allocator outlives the tree, so it does not free anything when the reference
+// is dropped.
+unsafe impl<A: NodeAllocator> NodeAllocator for &mut A {
+    const KEEPS_NODES: bool = A::KEEPS_NODES;
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        (**self).alloc(layout)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_node(&mut self, layout: Layout) -> *mut u8 {
+        (**self).alloc_node(layout)
+    }
+
+    unsafe fn dealloc_node(&mut self, ptr: *mut u8, layout: Layout) {
+        (**self).dealloc_node(ptr, layout)
+    }
+}
+
+/// The number of bytes in each chunk allocated by a `SlabAllocator`.
+const CHUNK_BYTES: usize = 64 << 10;
+/// The minimum number of allocations that fit in a chunk, for allocations too large for
+/// `CHUNK_BYTES` to hold many of them.
+const MIN_CHUNK_SLOTS: usize = 16;
+
+/// A slab allocator with a free list for each size class.
+///
+/// Memory is carved out of 64KiB chunks taken from the parent allocator `P` (by default, the
+/// global allocator). Freed allocations go on the free list for their size and are reused for
+/// later allocations of that size; they are never returned to the parent. Chunks are released
+/// all at once when the allocator (and with it the tree that owns it) is dropped, so a tree can
+/// skip freeing its nodes one at a time. Besides avoiding the global allocator, this keeps nodes
+/// of the same type close together in memory.
+///
+/// Because chunks are only released on drop, the memory a tree has reserved never goes down:
+/// `RawART::shrink_to_fit` and shrink thresholds move nodes into smaller kinds and put the old
+/// nodes on the free lists, but `reserved` stays the same. The freed slots are reused by later
+/// allocations of the same size. There is no way to return a partly used chunk either, since
+/// `KEEPS_NODES` promises that the memory of a freed node stays readable for as long as the
+/// allocator lives. To give memory back after a mass deletion, copy the remaining keys into a
+/// new tree.
+///
+/// A tree only allocates a handful of sizes (one per node type, and one for leaves), so size
+/// classes are found with a linear search. Inner nodes get size classes of their own, so the
+/// memory of a freed node only ever holds other nodes (see `NodeAllocator::KEEPS_NODES`).
+#[derive(Default)]
+pub struct SlabAllocator<P: NodeAllocator = Global> {
+    classes: Vec<SizeClass>,
+    parent: P,
+}
+
+struct SizeClass {
+    // The layout that callers ask for, and the (possibly larger) layout of each slot.
+    request: Layout,
+    // Whether the class holds inner nodes.
+    nodes: bool,
+    slot: Layout,
+    free: *mut FreeSlot,
+    // The unused part of the most recent chunk.
+    next: *mut u8,
+    end: *mut u8,
+    chunks: Vec<*mut u8>,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+// The allocator only hands out memory through `&mut self`, so sharing references to it across
+// threads is as safe as sharing the parent.
+unsafe impl<P: NodeAllocator + Send> Send for SlabAllocator<P> {}
+unsafe impl<P: NodeAllocator + Sync> Sync for SlabAllocator<P> {}
+
+impl SlabAllocator {
+    pub fn new() -> Self {
+        SlabAllocator::default()
+    }
+}
+
+impl<P: NodeAllocator> SlabAllocator<P> {
+    /// A slab allocator that takes its chunks from `parent`.
+    pub fn new_in(parent: P) -> Self {
+        SlabAllocator {
+            classes: Vec::new(),
+            parent,
+        }
+    }
+
+    /// The number of bytes the allocator has taken from its parent allocator. This never
+    /// decreases, as chunks are only returned when the allocator is dropped.
+    pub fn reserved(&self) -> usize {
+        self.classes
+            .iter()
+            .map(|c| c.chunks.len() * c.chunk_layout().size())
+            .sum()
+    }
+
+    // The index of the size class for `layout`.
+    fn class(&mut self, layout: Layout, nodes: bool) -> usize {
+        match self.classes.iter().position(|c| c.request == layout && c.nodes == nodes) {
+            Some(i) => i,
+            None => {
+                self.classes.push(SizeClass::new(layout, nodes));
+                self.classes.len() - 1
+            }
+        }
+    }
+}
+
+impl SizeClass {
+    fn new(request: Layout, nodes: bool) -> Self {
+        // Free slots hold a pointer to the next one.
+        let align = cmp::max(request.align(), mem::align_of::<FreeSlot>());
+        let size = cmp::max(request.size(), mem::size_of::<FreeSlot>());
+        let slot = Layout::from_size_align(size, align)
+            .expect("invalid layout")
+            .pad_to_align();
+        SizeClass {
+            request,
+            nodes,
+            slot,
+            free: ptr::null_mut(),
+            next: ptr::null_mut(),
+            end: ptr::null_mut(),
+            chunks: Vec::new(),
+        }
+    }
+
+    fn chunk_layout(&self) -> Layout {
+        let slots = cmp::max(MIN_CHUNK_SLOTS, CHUNK_BYTES / self.slot.size());
+        Layout::from_size_align(self.slot.size() * slots, self.slot.align())
+            .expect("slab chunk too large")
+    }
+
+    unsafe fn alloc<P: NodeAllocator>(&mut self, parent: &mut P) -> *mut u8 {
+        if !self.free.is_null() {
+            let res = self.free;
+            self.free = (*res).next;
+            return res as *mut u8;
+        }
+        if self.next == self.end {
+            let layout = self.chunk_layout();
+            let chunk = parent.alloc(layout);
+            self.chunks.push(chunk);
+            self.next = chunk;
+            self.end = chunk.add(layout.size());
+        }
+        let res = self.next;
+        self.next = res.add(self.slot.size());
+        res
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        let slot = ptr as *mut FreeSlot;
+        (*slot).next = self.free;
+        self.free = slot;
+    }
+}
+
+impl<P: NodeAllocator> Drop for SlabAllocator<P> {
+    fn drop(&mut self) {
+        for class in self.classes.iter() {
+            let layout = class.chunk_layout();
+            for &chunk in class.chunks.iter() {
+                unsafe { self.parent.dealloc(chunk, layout) };
+            }
+        }
+    }
+}
+
+unsafe impl<P: NodeAllocator> NodeAllocator for SlabAllocator<P> {
+    const FREES_ON_DROP: bool = true;
+    const KEEPS_NODES: bool = true;
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let i = self.class(layout, false);
+        self.classes[i].alloc(&mut self.parent)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let i = self.class(layout, false);
+        self.classes[i].dealloc(ptr)
+    }
+
+    unsafe fn alloc_node(&mut self, layout: Layout) -> *mut u8 {
+        let i = self.class(layout, true);
+        self.classes[i].alloc(&mut self.parent)
+    }
+
+    unsafe fn dealloc_node(&mut self, ptr: *mut u8, layout: Layout) {
+        let i = self.class(layout, true);
+        self.classes[i].dealloc(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn slab_reuses_memory() {
+        let mut slab = SlabAllocator::new();
+        let small = Layout::new::<u8>();
+        let large = Layout::new::<[u64; 300]>();
+        unsafe {
+            let mut ptrs = Vec::new();
+            let mut seen = HashSet::new();
+            for i in 0..10_000 {
+                let layout = if i % 3 == 0 { large } else { small };
+                let p = slab.alloc(layout);
+                assert_eq!(p as usize % layout.align(), 0);
+                assert!(seen.insert(p as usize), "handed out {:?} twice", p);
+                ptrs.push((p, layout));
+            }
+            let reserved = slab.reserved();
+            assert!(reserved > 0);
+            for &(p, layout) in ptrs.iter() {
+                slab.dealloc(p, layout);
+            }
+            for &(_, layout) in ptrs.iter() {
+                slab.alloc(layout);
+            }
+            assert_eq!(slab.reserved(), reserved);
+        }
+    }
+
+    #[test]
+    fn slab_keeps_nodes_apart() {
+        // Freed nodes are only reused for other nodes, even if a leaf asks for the same layout.
+        let mut slab = SlabAllocator::new();
+        let layout = Layout::new::<[u64; 4]>();
+        unsafe {
+            let node = slab.alloc_node(layout);
+            slab.dealloc_node(node, layout);
+            let leaf = slab.alloc(layout);
+            assert_ne!(leaf, node);
+            assert_eq!(slab.alloc_node(layout), node);
+        }
+    }
+}
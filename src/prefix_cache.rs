@@ -1,20 +1,71 @@
 extern crate fnv;
-#[cfg(feature = "print_cache_stats")]
 use std::cell::UnsafeCell;
 use std::cmp;
 use std::marker::PhantomData;
+use std::mem;
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use super::art_internal::MarkedPtr;
+use super::art_internal::{MarkedPtr, RawNode};
+use super::byteorder::{BigEndian, ByteOrder};
 
 pub use self::dense_hash_set::HashSetPrefixCache;
+pub use self::partial::PartialPrefixCache;
+pub use self::long::LongPrefixCache;
+pub use self::swiss::SwissPrefixCache;
+pub use self::tagged::TaggedPrefixCache;
+
+/// The number of buckets in `CacheStats::probe_lengths`.
+pub const PROBE_HISTOGRAM_LEN: usize = 8;
+
+/// Statistics about a tree's prefix cache, as returned by `RawART::cache_stats`.
+///
+/// Counters cover the operations since `enable_cache_stats` or `reset_cache_stats` was last
+/// called, and are 0 for trees that are not recording them. The remaining fields describe the
+/// cache as it is now.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Operations that found an entry for a prefix of their key.
+    pub hits: usize,
+    /// Operations on keys long enough to be cached that found no entry.
+    pub misses: usize,
+    /// Hits on an entry for a leaf rather than an interior node, which operations cannot start
+    /// from, so that they start from the root instead.
+    pub stale_hits: usize,
+    /// Operations that started from a cache hit but could not complete from there (e.g. because
+    /// the key diverged from the cached node's prefix), and restarted from the root.
+    pub restarts: usize,
+    /// The number of prefixes in the cache.
+    pub entries: usize,
+    /// `probe_lengths[i]` is the number of hash table probes that inspected `i + 1` buckets (or
+    /// groups of buckets, for `SwissPrefixCache`). The last bucket also counts longer probes. Only
+    /// `HashSetPrefixCache` and `SwissPrefixCache` track these.
+    pub probe_lengths: [usize; PROBE_HISTOGRAM_LEN],
+    /// Deleted entries that still take up space in the hash table.
+    pub tombstones: usize,
+    /// The (approximate) heap memory used by the cache, in bytes.
+    pub memory: usize,
+}
 
 /// PrefixCache describes types that can cache pointers interior to an ART.
+///
+/// A tree may cache prefixes of several different lengths in the same cache, so prefixes of
+/// different lengths must be kept distinct even if one is a zero-padded version of the other.
 pub trait PrefixCache<T> {
     /// If true, the cache is used during ART set operations. If false, the cache is ignored.
     const ENABLED: bool;
     /// If true, lookup returning None indicates that no nodes with prefix `bs` are in the set.
     const COMPLETE: bool;
+    /// The longest prefix the cache can be keyed on.
+    const MAX_PREFIX_LEN: usize = 8;
+    /// If true, the tree finds the node for a prefix and passes it to `admit` after a lookup
+    /// misses.
+    const ADMITS: bool = false;
+    /// If true, the cache stores generations in the nodes themselves (see `RawNode::generation`)
+    /// and reads them from nodes that may have been freed, so the tree's allocator must keep the
+    /// memory of freed nodes (see `NodeAllocator::KEEPS_NODES`). Only the nodes of trees with
+    /// such a cache have room for a generation.
+    const TAGS_NODES: bool = false;
     fn new() -> Self;
     fn lookup(&self, bs: &[u8]) -> Option<MarkedPtr<T>>;
     fn replace(&mut self, bs: &[u8], ptr: MarkedPtr<T>) -> Option<MarkedPtr<T>> {
@@ -24,14 +75,36 @@ pub trait PrefixCache<T> {
     fn insert(&mut self, bs: &[u8], ptr: MarkedPtr<T>) {
         let _ = self.replace(bs, ptr);
     }
+    /// Offer `ptr`, the node for prefix `bs`, to the cache after a lookup for a key starting with
+    /// `bs` missed in the cache and then found the key from the root; `ptr` is the node that
+    /// traversal passed through. Only caches that are not `COMPLETE` have any use for this.
+    fn admit(&self, _bs: &[u8], _ptr: MarkedPtr<T>) {}
+    /// Remove every entry for a prefix of length `len`. This is used when a tree stops caching
+    /// prefixes of that length.
+    ///
+    /// The default does nothing, which is only correct for caches that never hold any entries.
+    fn clear_prefix_len(&mut self, _len: usize) {}
+    /// Fill in the fields of `stats` that describe the cache itself: its entries, probe lengths,
+    /// tombstones and memory footprint.
+    fn stats(&self, _stats: &mut CacheStats) {}
+    /// Reset the probe length histogram reported by `stats`.
+    fn reset_stats(&mut self) {}
+    /// Start or stop recording the probe length histogram reported by `stats`. Caches start out
+    /// not recording it, as every lookup would have to update a shared counter.
+    fn record_stats(&mut self, _enabled: bool) {}
+    /// Release any memory the cache does not need for its current entries.
+    fn shrink_to_fit(&mut self) {}
     #[inline(always)]
     fn debug_assert_unreachable(&self, _ptr: MarkedPtr<T>) {}
 }
+/// A prefix cache that caches nothing. This is the cache of `ARTSet` and `ARTMap`.
 pub struct NullBuckets<T>(PhantomData<T>);
 
 impl<T> PrefixCache<T> for NullBuckets<T> {
     const ENABLED: bool = false;
     const COMPLETE: bool = false;
+    // nothing is cached, so any prefix length will do.
+    const MAX_PREFIX_LEN: usize = usize::MAX;
     fn new() -> Self {
         NullBuckets(PhantomData)
     }
@@ -41,41 +114,528 @@ impl<T> PrefixCache<T> for NullBuckets<T> {
     fn insert(&mut self, _: &[u8], _ptr: MarkedPtr<T>) {}
 }
 
+/// The probe length histogram of a hash table: empty unless the cache is recording statistics.
+fn probe_histogram(record: bool) -> Vec<AtomicUsize> {
+    if record {
+        (0..PROBE_HISTOGRAM_LEN).map(|_| AtomicUsize::new(0)).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Count a probe that inspected `len` buckets in `histogram`, if it is being recorded. Lookups
+/// take `&self`, so the counts are atomics.
+fn record_probe(histogram: &[AtomicUsize], len: usize) {
+    if let Some(count) = histogram.get(cmp::min(len, PROBE_HISTOGRAM_LEN) - 1) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn read_u64(bs: &[u8]) -> u64 {
+    debug_assert!(bs.len() <= 8);
+    let mut arr = [0_u8; 8];
+    unsafe { ptr::copy_nonoverlapping(bs.as_ptr(), arr.as_mut_ptr(), cmp::min(bs.len(), 8)) };
+    BigEndian::read_u64(&arr[..])
+}
+
+mod long {
+    use super::*;
+    use super::fnv::FnvHasher;
+    use super::super::smallvec::SmallVec;
+
+    use std::collections::HashMap;
+    use std::hash::BuildHasherDefault;
+
+    /// A complete prefix cache keyed by byte strings of any length.
+    ///
+    /// `HashSetPrefixCache` packs its prefixes into a `u64`, which limits it to prefixes of at
+    /// most 8 bytes. That is not enough to skip much of a tree holding long keys with long
+    /// common prefixes, like URLs or paths. Prefixes of up to 32 bytes are stored inline.
+    pub struct LongPrefixCache<T>(HashMap<SmallVec<[u8; 32]>, MarkedPtr<T>, BuildHasherDefault<FnvHasher>>);
+
+    impl<T> LongPrefixCache<T> {
+        fn key(bs: &[u8]) -> SmallVec<[u8; 32]> {
+            let mut res = SmallVec::new();
+            res.extend(bs.iter().cloned());
+            res
+        }
+    }
+
+    impl<T> PrefixCache<T> for LongPrefixCache<T> {
+        const ENABLED: bool = true;
+        const COMPLETE: bool = true;
+        const MAX_PREFIX_LEN: usize = usize::MAX;
+        fn new() -> Self {
+            LongPrefixCache(HashMap::default())
+        }
+
+        fn lookup(&self, bs: &[u8]) -> Option<MarkedPtr<T>> {
+            // N.B. SmallVec<[u8; N]> borrows as [u8], so we can probe without building a key.
+            self.0.get(bs).cloned()
+        }
+
+        fn replace(&mut self, bs: &[u8], ptr: MarkedPtr<T>) -> Option<MarkedPtr<T>> {
+            if ptr.is_null() {
+                self.0.remove(bs)
+            } else {
+                self.0.insert(Self::key(bs), ptr)
+            }
+        }
+
+        fn clear_prefix_len(&mut self, len: usize) {
+            self.0.retain(|k, _| k.len() != len);
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.0.shrink_to_fit();
+        }
+
+        fn stats(&self, stats: &mut CacheStats) {
+            stats.entries = self.0.len();
+            // Keys that spill out of their inline storage are not counted.
+            stats.memory = self.0.capacity()
+                * (mem::size_of::<SmallVec<[u8; 32]>>() + mem::size_of::<MarkedPtr<T>>());
+        }
+    }
+}
+
+mod partial {
+    use super::*;
+    use super::fnv::FnvHasher;
+
+    use std::collections::HashMap;
+    use std::hash::BuildHasherDefault;
+
+    /// The number of prefixes a `PartialPrefixCache` holds if it is constructed with
+    /// `PrefixCache::new`.
+    pub const DEFAULT_CAPACITY: usize = 1 << 12;
+
+    // Prefixes are packed into a `u64` along with their length, which tells apart prefixes from
+    // different levels of the tree.
+    type SlotKey = (u64, u8);
+
+    fn slot_key(bs: &[u8]) -> SlotKey {
+        (read_u64(bs), bs.len() as u8)
+    }
+
+    struct Slot<T> {
+        prefix: SlotKey,
+        ptr: MarkedPtr<T>,
+        referenced: bool,
+    }
+
+    struct ClockState<T> {
+        index: HashMap<SlotKey, usize, BuildHasherDefault<FnvHasher>>,
+        slots: Vec<Slot<T>>,
+        hand: usize,
+    }
+
+    /// A prefix cache that holds at most a fixed number of prefixes.
+    ///
+    /// Entries are evicted using the CLOCK algorithm: each entry has a "referenced" bit that is
+    /// set when a lookup hits it, and a new entry replaces the first entry without the bit set
+    /// that the clock hand reaches, clearing bits as it goes. New nodes enter the cache through
+    /// the tree's usual maintenance, and nodes evicted earlier come back when lookups for them
+    /// miss (see `admit`), so for skewed workloads the cache converges on the hot prefixes.
+    ///
+    /// Because the cache is not `COMPLETE`, a miss only means the traversal has to start from
+    /// the root.
+    pub struct PartialPrefixCache<T> {
+        capacity: usize,
+        // lookups take `&self` but update referenced bits, and admissions happen during lookups.
+        state: UnsafeCell<ClockState<T>>,
+    }
+
+    impl<T> PartialPrefixCache<T> {
+        pub fn with_capacity(capacity: usize) -> Self {
+            PartialPrefixCache {
+                capacity,
+                state: UnsafeCell::new(ClockState {
+                    index: HashMap::default(),
+                    slots: Vec::with_capacity(capacity),
+                    hand: 0,
+                }),
+            }
+        }
+
+        pub fn capacity(&self) -> usize {
+            self.capacity
+        }
+
+        /// The number of prefixes currently cached.
+        pub fn len(&self) -> usize {
+            unsafe { (*self.state.get()).slots.len() }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        // Lookups update the CLOCK state through `&self`; callers must not hold on to the result
+        // across another call.
+        #[allow(clippy::mut_from_ref)]
+        unsafe fn state(&self) -> &mut ClockState<T> {
+            &mut *self.state.get()
+        }
+
+        unsafe fn remove(&self, prefix: SlotKey) {
+            let st = self.state();
+            if let Some(i) = st.index.remove(&prefix) {
+                st.slots.swap_remove(i);
+                if i < st.slots.len() {
+                    st.index.insert(st.slots[i].prefix, i);
+                }
+                if st.hand >= st.slots.len() {
+                    st.hand = 0;
+                }
+            }
+        }
+
+        unsafe fn add(&self, prefix: SlotKey, ptr: MarkedPtr<T>, referenced: bool) {
+            let st = self.state();
+            if let Some(i) = st.index.get(&prefix) {
+                let slot = &mut st.slots[*i];
+                slot.ptr = ptr;
+                slot.referenced |= referenced;
+                return;
+            }
+            if self.capacity == 0 {
+                return;
+            }
+            let new_slot = Slot {
+                prefix,
+                ptr,
+                referenced,
+            };
+            if st.slots.len() < self.capacity {
+                st.index.insert(prefix, st.slots.len());
+                st.slots.push(new_slot);
+                return;
+            }
+            let len = st.slots.len();
+            while st.slots[st.hand].referenced {
+                st.slots[st.hand].referenced = false;
+                st.hand = (st.hand + 1) % len;
+            }
+            let victim = st.hand;
+            st.index.remove(&st.slots[victim].prefix);
+            st.index.insert(prefix, victim);
+            st.slots[victim] = new_slot;
+            st.hand = (victim + 1) % len;
+        }
+    }
+
+    impl<T> PrefixCache<T> for PartialPrefixCache<T> {
+        const ENABLED: bool = true;
+        const COMPLETE: bool = false;
+        const ADMITS: bool = true;
+        fn new() -> Self {
+            PartialPrefixCache::with_capacity(DEFAULT_CAPACITY)
+        }
+
+        fn lookup(&self, bs: &[u8]) -> Option<MarkedPtr<T>> {
+            let st = unsafe { self.state() };
+            match st.index.get(&slot_key(bs)) {
+                Some(i) => {
+                    let slot = &mut st.slots[*i];
+                    slot.referenced = true;
+                    Some(slot.ptr.clone())
+                }
+                None => None,
+            }
+        }
+
+        fn insert(&mut self, bs: &[u8], ptr: MarkedPtr<T>) {
+            let prefix = slot_key(bs);
+            unsafe {
+                if ptr.is_null() {
+                    self.remove(prefix);
+                } else {
+                    self.add(prefix, ptr, false);
+                }
+            }
+            debug_assert!(self.len() <= self.capacity);
+        }
+
+        fn admit(&self, bs: &[u8], ptr: MarkedPtr<T>) {
+            debug_assert!(!ptr.is_null());
+            unsafe { self.add(slot_key(bs), ptr, false) };
+        }
+
+        fn stats(&self, stats: &mut CacheStats) {
+            let st = unsafe { self.state() };
+            stats.entries = st.slots.len();
+            stats.memory = st.slots.capacity() * mem::size_of::<Slot<T>>()
+                + st.index.capacity() * mem::size_of::<(SlotKey, usize)>();
+        }
+
+        fn shrink_to_fit(&mut self) {
+            // The slots are kept at `capacity`, which bounds the cache's size anyway.
+            unsafe { self.state().index.shrink_to_fit() };
+        }
+
+        fn clear_prefix_len(&mut self, len: usize) {
+            let mut i = 0;
+            while i < self.len() {
+                let prefix = unsafe { self.state().slots[i].prefix };
+                if prefix.1 as usize == len {
+                    // `remove` moves the last slot into slot `i`.
+                    unsafe { self.remove(prefix) };
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn clock_eviction() {
+            let mut c = PartialPrefixCache::<u64>::with_capacity(4);
+            let ptr = |i: usize| MarkedPtr::<u64>::from_leaf((i << 3) as *mut u64);
+            for i in 0..4u8 {
+                c.insert(&[i], ptr(i as usize + 1));
+            }
+            assert_eq!(c.len(), 4);
+            // touch everything but 2, which should be the first to go.
+            for i in [0u8, 1, 3].iter() {
+                assert!(c.lookup(&[*i]) == Some(ptr(*i as usize + 1)));
+            }
+            c.insert(&[4], ptr(5));
+            assert_eq!(c.len(), 4);
+            assert!(c.lookup(&[2]).is_none());
+            assert!(c.lookup(&[4]) == Some(ptr(5)));
+            // updates keep their slot, and removals free it up.
+            c.insert(&[0], ptr(6));
+            assert!(c.lookup(&[0]) == Some(ptr(6)));
+            c.insert(&[1], MarkedPtr::null());
+            assert!(c.lookup(&[1]).is_none());
+            assert_eq!(c.len(), 3);
+            c.admit(&[2], ptr(3));
+            assert!(c.lookup(&[2]) == Some(ptr(3)));
+            assert_eq!(c.len(), 4);
+            // prefixes of different lengths are distinct, even if they pad to the same bytes.
+            c.insert(&[2, 0], ptr(7));
+            assert!(c.lookup(&[2]) == Some(ptr(3)));
+            assert!(c.lookup(&[2, 0]) == Some(ptr(7)));
+            c.clear_prefix_len(1);
+            assert_eq!(c.len(), 1);
+            assert!(c.lookup(&[2]).is_none());
+            assert!(c.lookup(&[2, 0]) == Some(ptr(7)));
+        }
+    }
+}
+
+mod tagged {
+    use super::*;
+    use super::fnv::FnvHasher;
+
+    use std::collections::HashMap;
+    use std::hash::BuildHasherDefault;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    type Key = (u64, u8);
+
+    fn key(bs: &[u8]) -> Key {
+        (read_u64(bs), bs.len() as u8)
+    }
+
+    /// The generation given to the next node that a `TaggedPrefixCache` caches. It is shared by
+    /// every tree, as trees sharing an allocator may reuse each other's nodes.
+    static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+    struct Entry<T> {
+        ptr: MarkedPtr<T>,
+        generation: u64,
+    }
+
+    impl<T> Entry<T> {
+        fn is_stale(&self) -> bool {
+            let inner = unsafe { self.ptr.get_raw().unwrap().err().unwrap() };
+            unsafe { *RawNode::generation(inner) != self.generation }
+        }
+    }
+
+    /// A prefix cache that checks its entries before handing them out, rather than relying on
+    /// the tree to keep them up to date.
+    ///
+    /// The other caches store raw node pointers, so a structural change that the tree fails to
+    /// reflect in the cache leaves behind a pointer to freed memory. Here, each entry also holds
+    /// the generation of its node (see `RawNode::generation`), which is unique to the node and
+    /// cleared when it is freed. A lookup ignores an entry whose node has a different generation,
+    /// or no longer covers the prefix's length because a new node was split off above it. Either
+    /// way the operation just starts from the root, so the cache is not `COMPLETE`.
+    ///
+    /// Reading the generation of a node that may have been freed requires an allocator that
+    /// keeps the memory of freed nodes, and only reuses it for other nodes
+    /// (`NodeAllocator::KEEPS_NODES`, as `SlabAllocator` does); trees using this cache check this
+    /// when they are constructed. Leaves have no generation, so prefixes that lead straight to a
+    /// leaf are not cached.
+    pub struct TaggedPrefixCache<T>(HashMap<Key, Entry<T>, BuildHasherDefault<FnvHasher>>);
+
+    impl<T> TaggedPrefixCache<T> {
+        /// The number of entries whose node has been freed.
+        pub fn stale_entries(&self) -> usize {
+            self.0.values().filter(|e| e.is_stale()).count()
+        }
+    }
+
+    impl<T> PrefixCache<T> for TaggedPrefixCache<T> {
+        const ENABLED: bool = true;
+        const COMPLETE: bool = false;
+        const TAGS_NODES: bool = true;
+        fn new() -> Self {
+            TaggedPrefixCache(HashMap::default())
+        }
+
+        fn lookup(&self, bs: &[u8]) -> Option<MarkedPtr<T>> {
+            let entry = self.0.get(&key(bs))?;
+            // Only look past the generation once we know that the node is still there.
+            if entry.is_stale() {
+                return None;
+            }
+            let inner = unsafe { entry.ptr.get().unwrap().err().unwrap() };
+            let (consumed, count) = (inner.consumed as usize, inner.count as usize);
+            if consumed <= bs.len() && bs.len() <= consumed + count {
+                Some(entry.ptr.clone())
+            } else {
+                None
+            }
+        }
+
+        fn insert(&mut self, bs: &[u8], ptr: MarkedPtr<T>) {
+            match unsafe { ptr.get_raw() } {
+                Some(Err(inner)) => {
+                    let generation = unsafe {
+                        debug_assert!((*inner).typ.has_generation());
+                        let generation = RawNode::generation(inner);
+                        if *generation == 0 {
+                            *generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+                        }
+                        *generation
+                    };
+                    self.0.insert(key(bs), Entry { ptr, generation });
+                }
+                _ => {
+                    self.0.remove(&key(bs));
+                }
+            }
+        }
+
+        fn clear_prefix_len(&mut self, len: usize) {
+            self.0.retain(|k, _| k.1 as usize != len);
+        }
+
+        fn stats(&self, stats: &mut CacheStats) {
+            stats.entries = self.0.len();
+            stats.memory = self.0.capacity() * mem::size_of::<(Key, Entry<T>)>();
+        }
+
+        fn shrink_to_fit(&mut self) {
+            self.0.retain(|_, e| !e.is_stale());
+            self.0.shrink_to_fit();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::super::art_internal::{
+            free_node, make_node_with_prefix, ChildPtr, NODE_4,
+        };
+        use super::super::super::node_alloc::SlabAllocator;
+
+        #[test]
+        fn stale_entries_are_ignored() {
+            let mut c = TaggedPrefixCache::<u64>::new();
+            // The slab keeps the memory of freed nodes, which the cache reads.
+            let mut slab = SlabAllocator::new();
+            let typ = NODE_4.with_generation(true);
+            // A node for keys starting with [1, 2].
+            let node = ChildPtr::from_node(unsafe {
+                make_node_with_prefix::<u64, _>(&[1, 2], 0, typ, &mut slab)
+            });
+            let ptr = unsafe { node.to_marked() };
+            c.insert(&[1], ptr.clone());
+            c.insert(&[1, 2], ptr.clone());
+            // the node only covers prefixes of length at most 2.
+            c.insert(&[1, 2, 3], ptr.clone());
+            assert!(c.lookup(&[1]) == Some(ptr.clone()));
+            assert!(c.lookup(&[1, 2]) == Some(ptr.clone()));
+            assert!(c.lookup(&[1, 2, 3]).is_none());
+            // leaves are not cached.
+            c.insert(&[1], MarkedPtr::from_leaf(std::ptr::dangling_mut::<u64>()));
+            assert!(c.lookup(&[1]).is_none());
+            assert_eq!(c.stale_entries(), 0);
+            unsafe { free_node(node, &mut slab) };
+            assert!(c.lookup(&[1, 2]).is_none());
+            assert_eq!(c.stale_entries(), 2);
+            // A new node in the same memory does not revive the old entries.
+            let node = ChildPtr::from_node(unsafe {
+                make_node_with_prefix::<u64, _>(&[1, 2], 0, typ, &mut slab)
+            });
+            assert!(unsafe { node.to_marked() } == ptr);
+            assert!(c.lookup(&[1, 2]).is_none());
+            c.insert(&[1], unsafe { node.to_marked() });
+            assert!(c.lookup(&[1]) == Some(ptr.clone()));
+            c.shrink_to_fit();
+            assert_eq!(c.0.len(), 1);
+            unsafe { free_node(node, &mut slab) };
+        }
+    }
+}
+
 mod dense_hash_set {
     use super::*;
     use super::fnv::FnvHasher;
     use super::super::Digital;
-    use super::super::byteorder::{BigEndian, ByteOrder};
 
     use std::hash::{Hash, Hasher};
-    use std::mem;
 
-    fn read_u64(bs: &[u8]) -> u64 {
-        debug_assert!(bs.len() <= 8);
-        let mut arr = [0 as u8; 8];
-        unsafe { ptr::copy_nonoverlapping(&bs[0], &mut arr[0], cmp::min(bs.len(), 8)) };
-        BigEndian::read_u64(&arr[..])
+    /// A complete prefix cache for prefixes of up to 8 bytes, which are packed into a `u64`.
+    ///
+    /// Prefixes of each length get their own table, so that a tree caching several levels does
+    /// not confuse a prefix with a zero-padded shorter one. The flag says whether the tables
+    /// record their probe lengths (see `PrefixCache::record_stats`).
+    pub struct HashSetPrefixCache<T>(Vec<DenseHashTable<MarkedElt<T>>>, bool);
+
+    impl<T> HashSetPrefixCache<T> {
+        fn table(&self, bs: &[u8]) -> Option<&DenseHashTable<MarkedElt<T>>> {
+            self.0.get(bs.len())
+        }
+
+        fn table_mut(&mut self, bs: &[u8]) -> &mut DenseHashTable<MarkedElt<T>> {
+            while self.0.len() <= bs.len() {
+                self.0.push(DenseHashTable::new(self.1));
+            }
+            &mut self.0[bs.len()]
+        }
     }
 
-    pub struct HashSetPrefixCache<T>(DenseHashTable<MarkedElt<T>>);
     impl<T> PrefixCache<T> for HashSetPrefixCache<T> {
         const ENABLED: bool = true;
         const COMPLETE: bool = true;
         fn new() -> Self {
-            HashSetPrefixCache(DenseHashTable::new())
+            HashSetPrefixCache(Vec::new(), false)
         }
 
         #[cfg(debug_assertions)]
         fn debug_assert_unreachable(&self, ptr: MarkedPtr<T>) {
-            for elt in self.0.buckets.iter() {
+            for (table, elt) in self.0
+                .iter()
+                .flat_map(|table| table.buckets.iter().map(move |elt| (table, elt)))
+            {
                 if elt.ptr == ptr {
                     assert!(
-                        self.0.lookup(&elt.prefix).is_some(),
+                        table.lookup(&elt.prefix).is_some(),
                         "attempted to look up {:?}:{:?} but failed",
                         elt.prefix,
                         elt.ptr
                     );
-                    let l = self.0.lookup(&elt.prefix).unwrap();
+                    let l = table.lookup(&elt.prefix).unwrap();
                     assert!(l.ptr == elt.ptr, "got {:?} != elt {:?}", l, elt);
                     assert!(
                         elt.ptr != ptr,
@@ -90,17 +650,20 @@ mod dense_hash_set {
 
         fn lookup(&self, bs: &[u8]) -> Option<MarkedPtr<T>> {
             let prefix = read_u64(bs);
-            let res = self.0.lookup(&prefix).map(|elt| elt.ptr.clone());
+            let res = self.table(bs)
+                .and_then(|table| table.lookup(&prefix))
+                .map(|elt| elt.ptr.clone());
             #[cfg(debug_assertions)]
             unsafe {
+                // Entries for leaves stored in value slots may be stale, so we only check nodes.
                 if let Some(Err(inner)) = res.as_ref()
-                    .map(|x| x.get().expect("stored pointer should be non-null"))
+                    .map(|x| x.get_raw().expect("stored pointer should be non-null"))
                 {
                     assert!(
-                        inner.children != !0,
+                        (*inner).children != !0,
                         "Returning an expired node {:?} (ty={:?})",
                         res,
-                        inner.typ
+                        (*inner).typ
                     );
                 }
             }
@@ -110,33 +673,66 @@ mod dense_hash_set {
         fn insert(&mut self, bs: &[u8], ptr: MarkedPtr<T>) {
             let prefix = read_u64(bs);
             if ptr.is_null() {
-                self.0.delete(&prefix);
+                self.table_mut(bs).delete(&prefix);
                 debug_assert!(self.lookup(bs).is_none());
             } else {
-                let _ = self.0.insert(MarkedElt {
-                    prefix: prefix,
-                    ptr: ptr,
+                let _ = self.table_mut(bs).insert(MarkedElt {
+                    prefix,
+                    ptr,
                 });
             }
         }
 
+        fn clear_prefix_len(&mut self, len: usize) {
+            if len < self.0.len() {
+                self.0[len] = DenseHashTable::new(self.1);
+            }
+        }
+
+        fn stats(&self, stats: &mut CacheStats) {
+            stats.memory = self.0.capacity() * mem::size_of::<DenseHashTable<MarkedElt<T>>>();
+            for table in self.0.iter() {
+                stats.entries += table.len;
+                stats.tombstones += table.set - table.len;
+                stats.memory += table.buckets.capacity() * mem::size_of::<MarkedElt<T>>();
+                for (total, count) in stats.probe_lengths.iter_mut().zip(table.probes.iter()) {
+                    *total += count.load(Ordering::Relaxed);
+                }
+            }
+        }
+
+        fn reset_stats(&mut self) {
+            for table in self.0.iter_mut() {
+                for count in table.probes.iter() {
+                    count.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+
+        fn record_stats(&mut self, enabled: bool) {
+            self.1 = enabled;
+            for table in self.0.iter_mut() {
+                table.probes = probe_histogram(enabled);
+            }
+        }
+
+        fn shrink_to_fit(&mut self) {
+            for table in self.0.iter_mut() {
+                table.shrink_to_fit();
+            }
+        }
+
         fn replace(&mut self, bs: &[u8], ptr: MarkedPtr<T>) -> Option<MarkedPtr<T>> {
             let prefix = read_u64(bs);
             if ptr.is_null() {
-                self.0.delete(&prefix)
+                self.table_mut(bs).delete(&prefix)
             } else {
-                match self.0.insert(MarkedElt {
-                    prefix: prefix,
-                    ptr: ptr,
-                }) {
-                    Ok(()) => None,
-                    Err(t) => Some(t),
-                }
+                self.table_mut(bs).insert(MarkedElt { prefix, ptr }).err()
             }.map(|t| t.ptr)
         }
     }
 
-    trait DHTE {
+    trait DenseHashTableElt {
         type Key;
         fn null() -> Self;
         fn tombstone() -> Self;
@@ -145,7 +741,9 @@ mod dense_hash_set {
         fn key(&self) -> &Self::Key;
     }
 
-    const MARKED_TOMBSTONE: usize = !0;
+    // An address that no leaf has, tagged as an ordinary leaf: the words of leaves stored inline
+    // are not compared.
+    const MARKED_TOMBSTONE: usize = !0b10;
     struct MarkedElt<T> {
         prefix: u64,
         ptr: MarkedPtr<T>,
@@ -161,7 +759,7 @@ mod dense_hash_set {
         }
     }
 
-    impl<T> DHTE for MarkedElt<T> {
+    impl<T> DenseHashTableElt for MarkedElt<T> {
         type Key = u64;
         fn null() -> Self {
             MarkedElt {
@@ -190,14 +788,20 @@ mod dense_hash_set {
     /// A bare-bones implementation of Google's dense_hash_set. Not a full-featured map, but
     /// contains sufficient functionality to be used as a PrefixCache
     ///
+    /// Deletions leave tombstones behind. The table is rehashed to clear them out once they take
+    /// up a quarter of the buckets, and it shrinks once fewer than 1/8 of its buckets are in use.
+    ///
     /// TODO: explore optimizing this more (for time or for space).
     struct DenseHashTable<T> {
         buckets: Vec<T>,
         len: usize,
         set: usize,
+        // A histogram of the number of buckets inspected by each call to `seek`, if the cache is
+        // recording one (see `record_probe`).
+        probes: Vec<AtomicUsize>,
     }
 
-    impl<T: DHTE> DenseHashTable<T>
+    impl<T: DenseHashTableElt> DenseHashTable<T>
     where
         T::Key: Eq + Hash,
     {
@@ -206,21 +810,18 @@ mod dense_hash_set {
             hash + (i + i * i) / 2
         }
 
-        fn new() -> Self {
+        fn new(record: bool) -> Self {
             DenseHashTable {
                 buckets: Vec::new(),
                 len: 0,
                 set: 0,
+                probes: probe_histogram(record),
             }
         }
 
-        fn seek(
-            &self,
-            k: &T::Key,
-        ) -> (
-            Option<*mut T>, /* first tombstone */
-            Option<*mut T>, /* matching or null */
-        ) {
+        /// The indices of the first tombstone and of the matching or null bucket on the probe
+        /// chain of `k`.
+        fn seek(&self, k: &T::Key) -> (Option<usize>, Option<usize>) {
             let mut tombstone = None;
             let l = self.buckets.len();
             debug_assert!(l.is_power_of_two());
@@ -236,84 +837,86 @@ mod dense_hash_set {
                 debug_assert!(ix < self.buckets.len());
                 times += 1;
                 let bucket = unsafe { self.buckets.get_unchecked(ix) };
-                let bucket_raw = bucket as *const T as *mut T;
-                if tombstone.is_none() && bucket.is_tombstone() {
-                    tombstone = Some(bucket_raw);
+                if bucket.is_tombstone() {
+                    tombstone = tombstone.or(Some(ix));
                 } else if bucket.is_null() || bucket.key() == k {
-                    return (tombstone, Some(bucket_raw));
+                    record_probe(&self.probes, times);
+                    return (tombstone, Some(ix));
                 }
                 ix = Self::next_probe(hash, times);
             }
+            record_probe(&self.probes, times);
             (tombstone, None)
         }
 
         fn grow(&mut self) {
             debug_assert!(self.set >= self.len);
-            let old_len = if self.buckets.len() == 0 {
-                self.buckets.push(T::null());
-                return;
-            } else if self.buckets.len() < 32
-                || (self.set as i64) - (self.len as i64) < (self.buckets.len() as i64 / 4)
-            {
+            let l = self.buckets.len();
+            if l == 0 {
+                self.rehash(1);
+            } else if l < 32 || self.set - self.len < l / 4 {
                 // actually grow. If this condition is not met, then we just re-hash
-                let l = self.buckets.len();
-                self.buckets.extend((0..l).map(|_| T::null()));
-                l
+                self.rehash(l * 2);
             } else {
-                self.buckets.len()
-            };
-            debug_assert!(self.buckets.len().is_power_of_two());
-            debug_assert!(old_len.is_power_of_two());
-            let mut v = Vec::with_capacity(self.len);
-            for i in &mut self.buckets[0..old_len] {
-                if i.is_null() {
-                    continue;
-                }
-                if i.is_tombstone() {
-                    *i = T::null();
-                    continue;
-                }
-                let mut t = T::null();
-                mem::swap(i, &mut t);
-                v.push(t);
+                self.rehash(l);
+            }
+        }
+
+        /// Shrink the table to the smallest size that holds its elements.
+        fn shrink_to_fit(&mut self) {
+            if self.len == 0 {
+                self.buckets = Vec::new();
+                self.set = 0;
+            } else {
+                self.rehash((self.len * 2 + 1).next_power_of_two());
             }
+        }
+
+        /// Move the elements into a fresh table with `n` buckets, dropping any tombstones.
+        fn rehash(&mut self, n: usize) {
+            debug_assert!(n.is_power_of_two());
+            debug_assert!(self.len <= n / 2);
+            let old = mem::replace(&mut self.buckets, (0..n).map(|_| T::null()).collect());
             self.set = 0;
             self.len = 0;
-            for elt in v.into_iter() {
+            for elt in old.into_iter() {
+                if elt.is_null() || elt.is_tombstone() {
+                    continue;
+                }
                 let _res = self.insert(elt);
                 debug_assert!(_res.is_ok());
             }
         }
 
         fn lookup(&self, k: &T::Key) -> Option<&T> {
-            if self.buckets.len() == 0 {
+            if self.buckets.is_empty() {
                 return None;
             }
             let (_, b_opt) = self.seek(k);
-            b_opt.and_then(|b| unsafe {
-                if (*b).is_null() {
-                    None
-                } else {
-                    Some(&*b)
-                }
-            })
+            b_opt.map(|b| &self.buckets[b]).filter(|b| !b.is_null())
         }
 
         fn delete(&mut self, k: &T::Key) -> Option<T> {
-            if self.buckets.len() == 0 {
+            if self.buckets.is_empty() {
                 return None;
             }
             let (_, b_opt) = self.seek(k);
-            b_opt.and_then(|b| unsafe {
-                if (*b).is_null() {
-                    None
-                } else {
-                    let mut tomb = T::tombstone();
-                    mem::swap(&mut *b, &mut tomb);
+            let res = match b_opt {
+                Some(b) if !self.buckets[b].is_null() => {
                     self.len -= 1;
-                    Some(tomb)
+                    Some(mem::replace(&mut self.buckets[b], T::tombstone()))
+                }
+                _ => None,
+            };
+            let l = self.buckets.len();
+            if res.is_some() && l > 32 {
+                if self.len < l / 8 {
+                    self.rehash(l / 2);
+                } else if self.set - self.len > l / 4 {
+                    self.rehash(l);
                 }
-            })
+            }
+            res
         }
 
         fn insert(&mut self, mut t: T) -> Result<(), T> {
@@ -323,26 +926,24 @@ mod dense_hash_set {
             debug_assert!(!t.is_null());
             debug_assert!(!t.is_tombstone());
             let (tmb, b_opt) = self.seek(t.key());
-            unsafe {
-                let bucket = b_opt.unwrap();
-                if (*bucket).is_null() {
-                    // t is not already in the table. We insert it somewhere
-                    if let Some(tombstone_bucket) = tmb {
-                        // there was a tombstone earlier in the probe chain. We overwrite its
-                        // value.
-                        *tombstone_bucket = t;
-                    } else {
-                        // we insert it into the new slot
-                        *bucket = t;
-                        self.set += 1;
-                    }
-                    self.len += 1;
-                    Ok(())
+            let bucket = b_opt.unwrap();
+            if self.buckets[bucket].is_null() {
+                // t is not already in the table. We insert it somewhere
+                if let Some(tombstone_bucket) = tmb {
+                    // there was a tombstone earlier in the probe chain. We overwrite its
+                    // value.
+                    self.buckets[tombstone_bucket] = t;
                 } else {
-                    // t is already in the table, we simply swap in the new value
-                    mem::swap(&mut *bucket, &mut t);
-                    Err(t)
+                    // we insert it into the new slot
+                    self.buckets[bucket] = t;
+                    self.set += 1;
                 }
+                self.len += 1;
+                Ok(())
+            } else {
+                // t is already in the table, we simply swap in the new value
+                mem::swap(&mut self.buckets[bucket], &mut t);
+                Err(t)
             }
         }
     }
@@ -361,7 +962,7 @@ mod dense_hash_set {
 
         #[derive(Debug)]
         struct UsizeElt(usize, usize);
-        impl DHTE for UsizeElt {
+        impl DenseHashTableElt for UsizeElt {
             type Key = usize;
             fn null() -> Self {
                 UsizeElt(0, 0)
@@ -388,7 +989,7 @@ mod dense_hash_set {
 
         #[test]
         fn dense_hash_set_smoke_test() {
-            let mut s = DenseHashTable::<UsizeElt>::new();
+            let mut s = DenseHashTable::<UsizeElt>::new(false);
             let mut v1 = random_vec(!0, 1 << 18);
             for item in v1.iter() {
                 let _ = s.insert(UsizeElt::new(*item));
@@ -448,5 +1049,422 @@ mod dense_hash_set {
                 );
             }
         }
+
+        #[test]
+        fn dense_hash_set_shrinks() {
+            let mut s = DenseHashTable::<UsizeElt>::new(false);
+            for _ in 0..3 {
+                let mut v = random_vec(!0, 1 << 14);
+                v.sort();
+                v.dedup();
+                for item in v.iter() {
+                    let _ = s.insert(UsizeElt::new(*item));
+                }
+                let peak = s.buckets.len();
+                let keep = v.split_off(v.len() - 16);
+                for item in v.iter() {
+                    assert!(s.delete(item).is_some());
+                }
+                assert!(s.buckets.len() <= peak / 64, "{} buckets", s.buckets.len());
+                assert!(s.set - s.len <= s.buckets.len() / 4);
+                for item in v.iter() {
+                    assert!(s.lookup(item).is_none());
+                }
+                for item in keep.iter() {
+                    assert!(s.lookup(item).is_some());
+                }
+                s.shrink_to_fit();
+                assert_eq!(s.buckets.len(), 64);
+                assert_eq!(s.set, s.len);
+                for item in keep.iter() {
+                    assert!(s.delete(item).is_some());
+                }
+                assert_eq!(s.len, 0);
+            }
+        }
+
+        #[test]
+        fn dense_hash_set_tombstones_do_not_match() {
+            // Tombstones have the key 0, but they must not be mistaken for an element with that
+            // key, however many of them a probe passes.
+            for round in 0..64 {
+                let mut s = DenseHashTable::<UsizeElt>::new(false);
+                let keys: Vec<usize> = (1..16).map(|i| round * 16 + i).collect();
+                for k in keys.iter() {
+                    assert!(s.insert(UsizeElt::new(*k)).is_ok());
+                }
+                for k in keys.iter() {
+                    assert!(s.delete(k).is_some());
+                }
+                assert!(s.lookup(&0).is_none());
+                assert!(s.delete(&0).is_none());
+                assert!(s.insert(UsizeElt::new(0)).is_ok());
+                assert!(s.insert(UsizeElt::new(0)).is_err());
+                assert_eq!(s.len, 1);
+            }
+        }
+    }
+}
+
+mod swiss {
+    use super::*;
+    use super::super::byte_match::match_byte;
+
+    /// The number of slots whose control bytes are matched at once.
+    const GROUP_SIZE: usize = 16;
+    /// The control byte of an empty slot. Full slots hold the top 7 bits of their key's hash, so
+    /// they never have the high bit set.
+    const EMPTY: u8 = 0x80;
+
+    /// A complete prefix cache for prefixes of up to 8 bytes, laid out like Abseil's SwissTable.
+    ///
+    /// Slots are split into groups of 16, and each slot has a control byte holding 7 bits of the
+    /// hash of its prefix. A probe compares all 16 control bytes of a group at once (using SSE2
+    /// where it is available) and only looks at the prefixes whose bytes match, so most probes
+    /// touch one control word and one prefix. The hash is a single multiplication, which is
+    /// enough for prefixes packed into a `u64`.
+    ///
+    /// Deletions leave no tombstones behind. Instead, each group counts the entries that were
+    /// placed further along their probe sequence because the group was full; a lookup stops at
+    /// the first group where it misses that has no such entries, and removing an entry decrements
+    /// the counts along its probe sequence. This means workloads that churn through prefixes never
+    /// need to rehash to clear out dead entries.
+    ///
+    /// Like `HashSetPrefixCache`, prefixes of each length get their own table.
+    pub struct SwissPrefixCache<T>(Vec<SwissTable<T>>, bool);
+
+    impl<T> SwissPrefixCache<T> {
+        fn table(&self, bs: &[u8]) -> Option<&SwissTable<T>> {
+            self.0.get(bs.len())
+        }
+
+        fn table_mut(&mut self, bs: &[u8]) -> &mut SwissTable<T> {
+            while self.0.len() <= bs.len() {
+                self.0.push(SwissTable::new(self.1));
+            }
+            &mut self.0[bs.len()]
+        }
+    }
+
+    /// Pack `bs` into the low bytes of a `u64`. Unlike `read_u64`, this keeps the varying bytes of
+    /// short prefixes in the low bits, which the hash depends on the most.
+    fn pack(bs: &[u8]) -> u64 {
+        debug_assert!(bs.len() <= 8);
+        bs.iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+    }
+
+    impl<T> PrefixCache<T> for SwissPrefixCache<T> {
+        const ENABLED: bool = true;
+        const COMPLETE: bool = true;
+        fn new() -> Self {
+            SwissPrefixCache(Vec::new(), false)
+        }
+
+        #[cfg(debug_assertions)]
+        fn debug_assert_unreachable(&self, ptr: MarkedPtr<T>) {
+            for table in self.0.iter() {
+                for (i, &(prefix, ref p)) in table.slots.iter().enumerate() {
+                    assert!(
+                        table.ctrl[i] == EMPTY || *p != ptr,
+                        "Found ptr {:?} in slot with prefix {:?}",
+                        ptr,
+                        prefix
+                    );
+                }
+            }
+        }
+
+        fn lookup(&self, bs: &[u8]) -> Option<MarkedPtr<T>> {
+            self.table(bs).and_then(|table| table.lookup(pack(bs)))
+        }
+
+        fn replace(&mut self, bs: &[u8], ptr: MarkedPtr<T>) -> Option<MarkedPtr<T>> {
+            let prefix = pack(bs);
+            if ptr.is_null() {
+                self.table_mut(bs).delete(prefix)
+            } else {
+                self.table_mut(bs).insert(prefix, ptr)
+            }
+        }
+
+        fn clear_prefix_len(&mut self, len: usize) {
+            if len < self.0.len() {
+                self.0[len] = SwissTable::new(self.1);
+            }
+        }
+
+        fn stats(&self, stats: &mut CacheStats) {
+            stats.memory = self.0.capacity() * mem::size_of::<SwissTable<T>>();
+            for table in self.0.iter() {
+                stats.entries += table.len;
+                stats.memory += table.ctrl.capacity()
+                    + table.slots.capacity() * mem::size_of::<(u64, MarkedPtr<T>)>()
+                    + table.overflow.capacity() * mem::size_of::<u32>();
+                for (total, count) in stats.probe_lengths.iter_mut().zip(table.probes.iter()) {
+                    *total += count.load(Ordering::Relaxed);
+                }
+            }
+        }
+
+        fn reset_stats(&mut self) {
+            for table in self.0.iter_mut() {
+                for count in table.probes.iter() {
+                    count.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+
+        fn record_stats(&mut self, enabled: bool) {
+            self.1 = enabled;
+            for table in self.0.iter_mut() {
+                table.probes = probe_histogram(enabled);
+            }
+        }
+
+        fn shrink_to_fit(&mut self) {
+            for table in self.0.iter_mut() {
+                let mut groups = 0;
+                if table.len > 0 {
+                    groups = 1;
+                    while table.len * 8 > groups * GROUP_SIZE * 7 {
+                        groups *= 2;
+                    }
+                }
+                if groups < table.overflow.len() {
+                    table.resize(groups);
+                }
+            }
+        }
+    }
+
+    struct SwissTable<T> {
+        // One control byte per slot, `GROUP_SIZE` slots per group.
+        ctrl: Vec<u8>,
+        slots: Vec<(u64, MarkedPtr<T>)>,
+        // `overflow[g]` is the number of entries whose probe sequence passed over group `g`
+        // because it was full when they were inserted.
+        overflow: Vec<u32>,
+        len: usize,
+        // A histogram of the number of groups inspected by each lookup; see
+        // `DenseHashTable::probes`.
+        probes: Vec<AtomicUsize>,
+    }
+
+    /// An iterator over the groups probed for a hash: triangular probing visits every group once
+    /// when the number of groups is a power of two.
+    struct ProbeSeq {
+        group: usize,
+        stride: usize,
+        mask: usize,
+    }
+
+    impl Iterator for ProbeSeq {
+        type Item = usize;
+        fn next(&mut self) -> Option<usize> {
+            let res = self.group;
+            self.stride += 1;
+            self.group = (self.group + self.stride) & self.mask;
+            Some(res)
+        }
+    }
+
+    impl<T> SwissTable<T> {
+        fn new(record: bool) -> Self {
+            SwissTable {
+                ctrl: Vec::new(),
+                slots: Vec::new(),
+                overflow: Vec::new(),
+                len: 0,
+                probes: probe_histogram(record),
+            }
+        }
+
+        fn hash(k: u64) -> u64 {
+            // Multiplying by an odd constant mixes each bit of the key into the bits above it;
+            // folding the high half back down lets the group index depend on all of them.
+            let h = k.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            h ^ (h >> 32)
+        }
+
+        fn tag(hash: u64) -> u8 {
+            (hash >> 57) as u8
+        }
+
+        fn probe_seq(&self, hash: u64) -> ProbeSeq {
+            let mask = self.overflow.len() - 1;
+            ProbeSeq {
+                group: hash as usize & mask,
+                stride: 0,
+                mask,
+            }
+        }
+
+        fn group(&self, g: usize) -> &[u8; GROUP_SIZE] {
+            let group = &self.ctrl[g * GROUP_SIZE..(g + 1) * GROUP_SIZE];
+            unsafe { &*(group.as_ptr() as *const [u8; GROUP_SIZE]) }
+        }
+
+        /// Find the slot holding `k`.
+        fn find(&self, k: u64) -> Option<usize> {
+            if self.len == 0 {
+                return None;
+            }
+            let hash = Self::hash(k);
+            let tag = Self::tag(hash);
+            let mut probes = 0;
+            let mut res = None;
+            // Every group is visited once in the first `overflow.len()` steps, which bounds the
+            // search even if every group has overflowed.
+            for g in self.probe_seq(hash).take(self.overflow.len()) {
+                probes += 1;
+                let mut bits = match_byte(self.group(g), tag);
+                while bits != 0 {
+                    let slot = g * GROUP_SIZE + bits.trailing_zeros() as usize;
+                    if self.slots[slot].0 == k {
+                        res = Some(slot);
+                        break;
+                    }
+                    bits &= bits - 1;
+                }
+                if res.is_some() || self.overflow[g] == 0 {
+                    break;
+                }
+            }
+            record_probe(&self.probes, probes);
+            res
+        }
+
+        fn lookup(&self, k: u64) -> Option<MarkedPtr<T>> {
+            self.find(k).map(|slot| self.slots[slot].1.clone())
+        }
+
+        fn insert(&mut self, k: u64, ptr: MarkedPtr<T>) -> Option<MarkedPtr<T>> {
+            debug_assert!(!ptr.is_null());
+            if let Some(slot) = self.find(k) {
+                return Some(mem::replace(&mut self.slots[slot].1, ptr));
+            }
+            // Keep at least 1/8 of the slots empty so that probe sequences stay short.
+            if (self.len + 1) * 8 > self.ctrl.len() * 7 {
+                self.grow();
+            }
+            self.insert_new(k, ptr);
+            None
+        }
+
+        /// Insert `k`, which is not in the table, into the first group along its probe sequence
+        /// with an empty slot.
+        fn insert_new(&mut self, k: u64, ptr: MarkedPtr<T>) {
+            let hash = Self::hash(k);
+            for g in self.probe_seq(hash) {
+                let empty = match_byte(self.group(g), EMPTY);
+                if empty != 0 {
+                    let slot = g * GROUP_SIZE + empty.trailing_zeros() as usize;
+                    self.ctrl[slot] = Self::tag(hash);
+                    self.slots[slot] = (k, ptr);
+                    self.len += 1;
+                    return;
+                }
+                self.overflow[g] += 1;
+            }
+        }
+
+        fn delete(&mut self, k: u64) -> Option<MarkedPtr<T>> {
+            let slot = self.find(k)?;
+            let target = slot / GROUP_SIZE;
+            for g in self.probe_seq(Self::hash(k)) {
+                if g == target {
+                    break;
+                }
+                debug_assert!(self.overflow[g] > 0);
+                self.overflow[g] -= 1;
+            }
+            self.ctrl[slot] = EMPTY;
+            self.len -= 1;
+            let res = mem::replace(&mut self.slots[slot].1, MarkedPtr::null());
+            // Shrink once fewer than 1/8 of the slots are in use, leaving the table a quarter full
+            // at most.
+            let groups = self.overflow.len();
+            if groups > 1 && self.len * 8 < groups * GROUP_SIZE {
+                self.resize(groups / 2);
+            }
+            Some(res)
+        }
+
+        fn grow(&mut self) {
+            let groups = cmp::max(1, self.overflow.len() * 2);
+            self.resize(groups);
+        }
+
+        /// Move the entries into a fresh table with `groups` groups.
+        fn resize(&mut self, groups: usize) {
+            debug_assert!(groups == 0 || groups.is_power_of_two());
+            debug_assert!(self.len * 8 <= groups * GROUP_SIZE * 7);
+            let ctrl = mem::replace(&mut self.ctrl, vec![EMPTY; groups * GROUP_SIZE]);
+            let slots = mem::replace(
+                &mut self.slots,
+                (0..groups * GROUP_SIZE).map(|_| (0, MarkedPtr::null())).collect(),
+            );
+            self.overflow = vec![0; groups];
+            self.len = 0;
+            for (c, (k, ptr)) in ctrl.into_iter().zip(slots) {
+                if c != EMPTY {
+                    self.insert_new(k, ptr);
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::super::rand;
+        use super::super::super::rand::Rng;
+        use std::collections::HashMap;
+
+        #[test]
+        fn swiss_table_smoke_test() {
+            let mut rng = rand::thread_rng();
+            let ptr = |i: usize| MarkedPtr::<u64>::from_leaf((i << 3) as *mut u64);
+            let mut t = SwissTable::<u64>::new(false);
+            let mut m = HashMap::new();
+            // A small key space makes for plenty of replacements and deletions of present keys,
+            // and dense keys fill up groups.
+            for i in 0..(1 << 18) {
+                let k = rng.gen_range::<u64>(0, 1 << 14);
+                if rng.gen::<bool>() {
+                    assert_eq!(t.insert(k, ptr(i + 1)), m.insert(k, ptr(i + 1)));
+                } else {
+                    assert_eq!(t.delete(k), m.remove(&k));
+                }
+                assert_eq!(t.len, m.len());
+            }
+            for k in 0..(1 << 14) {
+                assert_eq!(t.lookup(k), m.get(&k).cloned());
+            }
+            for k in m.keys() {
+                assert!(t.delete(*k).is_some());
+            }
+            assert_eq!(t.len, 0);
+            // Without tombstones, an empty table is back in its original state.
+            assert!(t.overflow.iter().all(|o| *o == 0));
+            assert!(t.ctrl.iter().all(|c| *c == EMPTY));
+        }
+
+        #[test]
+        fn prefix_lengths_are_distinct() {
+            let ptr = |i: usize| MarkedPtr::<u64>::from_leaf((i << 3) as *mut u64);
+            let mut c = SwissPrefixCache::<u64>::new();
+            c.insert(&[2], ptr(1));
+            c.insert(&[0, 2], ptr(2));
+            c.insert(&[2, 0], ptr(3));
+            assert!(c.lookup(&[2]) == Some(ptr(1)));
+            assert!(c.lookup(&[0, 2]) == Some(ptr(2)));
+            assert!(c.lookup(&[2, 0]) == Some(ptr(3)));
+            c.clear_prefix_len(2);
+            assert!(c.lookup(&[2]) == Some(ptr(1)));
+            assert!(c.lookup(&[0, 2]).is_none());
+            assert!(c.replace(&[2], MarkedPtr::null()) == Some(ptr(1)));
+            assert!(c.lookup(&[2]).is_none());
+        }
     }
 }